@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::fmt;
 
+use crate::assembler::AssemblerSection;
+
 #[derive(Debug, Clone)]
 pub enum AssemblerError {
     // Declaration not found
@@ -13,8 +15,38 @@ pub enum AssemblerError {
     UnknownDirectiveFound { directive: String },
     // Opcode not found
     NonOpcodeInOpcodeField,
-    // Sections not enough
-    InsufficientSections,
+    // A required section is absent
+    MissingSection { section: AssemblerSection },
+    // Opcode outside of the code section
+    InstructionOutsideCodeSection {
+        section: AssemblerSection,
+        instruction: u32,
+    },
+    // Register alias used without a `.reg` declaration
+    UnknownRegisterAlias { alias: String },
+    // Malformed `.reg` declaration
+    InvalidRegisterAlias { instruction: u32 },
+    // Data directive in a section that can't hold it, e.g. `.asciiz` in `.bss`
+    DirectiveNotAllowedInSection {
+        directive: String,
+        section: AssemblerSection,
+        instruction: u32,
+    },
+    // Label used or exported but never declared
+    UndefinedSymbol {
+        name: String,
+    },
+    // `.extern` label used in a program that isn't linked
+    UnresolvedExternal {
+        name: String,
+    },
+    // Pc-relative label too far away to be encoded in an operand
+    OffsetOutOfRange {
+        name: String,
+        offset: i64,
+    },
+    // Data declared in code added to a program that was already assembled
+    DataAfterLoading,
     // Parse error
     ParseError { error: String },
 }
@@ -35,7 +67,42 @@ impl fmt::Display for AssemblerError {
                 f.write_str(&format!("Invalid or unknown directive found. Directive name was: {}", directive))
             }
             AssemblerError::NonOpcodeInOpcodeField => f.write_str("An non-opcode was found in an opcode field"),
-            AssemblerError::InsufficientSections => f.write_str("Less than two sections/segments were found in the code"),
+            AssemblerError::MissingSection { ref section } => {
+                f.write_str(&format!("No {} section was found, but one is required", section))
+            }
+            AssemblerError::InstructionOutsideCodeSection { ref section, instruction } => f.write_str(&format!(
+                "Found an instruction in the {} section, instructions belong in .code. Instruction # was {}",
+                section, instruction
+            )),
+            AssemblerError::UnknownRegisterAlias { ref alias } => {
+                f.write_str(&format!("Register alias ${} was used but never declared with .reg", alias))
+            }
+            AssemblerError::InvalidRegisterAlias { instruction } => f.write_str(&format!(
+                "A .reg declaration must be of the form `.reg name $register`. Instruction # was {}",
+                instruction
+            )),
+            AssemblerError::DirectiveNotAllowedInSection {
+                ref directive,
+                ref section,
+                instruction,
+            } => f.write_str(&format!(
+                "The .{} directive is not allowed in the {} section. Instruction # was {}",
+                directive, section, instruction
+            )),
+            AssemblerError::UndefinedSymbol { ref name } => {
+                f.write_str(&format!("Label @{} was used but never declared", name))
+            }
+            AssemblerError::UnresolvedExternal { ref name } => f.write_str(&format!(
+                "Label @{} is declared .extern, assemble this file as an object and link it to use it",
+                name
+            )),
+            AssemblerError::OffsetOutOfRange { ref name, offset } => f.write_str(&format!(
+                "Label @{} is {} bytes away from where it is used, which does not fit in an operand",
+                name, offset
+            )),
+            AssemblerError::DataAfterLoading => f.write_str(
+                "Only code can be added to a program that was already assembled, its data sections are fixed",
+            ),
             AssemblerError::ParseError { ref error } => f.write_str(&format!("There was an error parsing the code: {}", error)),
         }
     }
@@ -49,7 +116,15 @@ impl Error for AssemblerError {
             AssemblerError::SymbolAlreadyDeclared => "This symbol was previously declared.",
             AssemblerError::UnknownDirectiveFound { .. } => "Invalid or unknown directive found.",
             AssemblerError::NonOpcodeInOpcodeField => "A non-opcode was found in an opcode field",
-            AssemblerError::InsufficientSections => "Less than two sections/segments were found in the code",
+            AssemblerError::MissingSection { .. } => "A required section was not found in the code",
+            AssemblerError::InstructionOutsideCodeSection { .. } => "Found an instruction outside of the code section",
+            AssemblerError::UnknownRegisterAlias { .. } => "Register alias was used but never declared.",
+            AssemblerError::InvalidRegisterAlias { .. } => "Malformed register alias declaration.",
+            AssemblerError::DirectiveNotAllowedInSection { .. } => "Directive is not allowed in this section.",
+            AssemblerError::UndefinedSymbol { .. } => "Label was used but never declared.",
+            AssemblerError::UnresolvedExternal { .. } => "External label used in a program that isn't linked.",
+            AssemblerError::OffsetOutOfRange { .. } => "Label is too far away to be referred to.",
+            AssemblerError::DataAfterLoading => "Data was declared in code added to an assembled program.",
             AssemblerError::ParseError { .. } => "There was an error parsing the code",
         }
     }
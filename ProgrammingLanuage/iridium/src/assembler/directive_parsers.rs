@@ -1,9 +1,11 @@
-use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::{alpha1, alphanumeric, multispace};
 
 use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::integer_parsers::integer;
 use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::operand_parsers::operand;
+use crate::assembler::register_parsers::register;
 use crate::assembler::Token;
 
 named!(directive_declaration<CompleteStr, Token>,
@@ -38,10 +40,76 @@ named!(pub directive_combined<CompleteStr, AssemblerInstruction>,
     )
 );
 
+// Looks for a register alias declaration, such as `.reg counter $5`
+named!(pub register_alias_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".reg") >>
+        multispace >>
+        name: alphanumeric >>
+        reg: register >>
+        (
+            AssemblerInstruction{
+                opcode: None,
+                directive: Some(Token::Directive{name: "reg".to_string()}),
+                label: None,
+                operand1: Some(Token::RegisterAlias{name: name.to_string()}),
+                operand2: Some(reg),
+                operand3: None,
+            }
+        )
+    )
+);
+
+// Looks for a symbol linkage declaration, such as `.global main` or `.extern print`
+named!(pub symbol_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".") >>
+        kind: alt!(tag!("global") | tag!("extern")) >>
+        multispace >>
+        name: alphanumeric >>
+        (
+            AssemblerInstruction{
+                opcode: None,
+                directive: Some(Token::Directive{name: kind.to_string()}),
+                label: None,
+                operand1: Some(Token::LabelUsage{name: name.to_string()}),
+                operand2: None,
+                operand3: None,
+            }
+        )
+    )
+);
+
+// Looks for a constant declaration, such as `.equ size #64`
+named!(pub constant_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        opt!(multispace) >>
+        tag!(".equ") >>
+        multispace >>
+        name: alphanumeric >>
+        value: integer >>
+        (
+            AssemblerInstruction{
+                opcode: None,
+                directive: Some(Token::Directive{name: "equ".to_string()}),
+                label: None,
+                operand1: Some(Token::LabelUsage{name: name.to_string()}),
+                operand2: Some(value),
+                operand3: None,
+            }
+        )
+    )
+);
+
 /// Will try to parse out any of the Directive forms
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            register_alias_directive |
+            symbol_directive |
+            constant_directive |
             directive_combined
         ) >>
         (
@@ -138,6 +206,74 @@ mod tests {
     }
 
     #[test]
+    fn test_parse_register_alias_directive() {
+        let result = directive(CompleteStr(".reg counter $5\n"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: None,
+                    operand1: Some(Token::RegisterAlias {
+                        name: "counter".to_string()
+                    }),
+                    operand2: Some(Token::Register { reg_num: 5 }),
+                    operand3: None,
+                    directive: Some(Token::Directive {
+                        name: "reg".to_string()
+                    }),
+                    label: None,
+                }
+            ))
+        );
+
+        let result = register_alias_directive(CompleteStr(".reg counter $32"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_symbol_directive() {
+        let result = directive(CompleteStr(".global main"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                AssemblerInstruction {
+                    opcode: None,
+                    operand1: Some(Token::LabelUsage {
+                        name: "main".to_string()
+                    }),
+                    operand2: None,
+                    operand3: None,
+                    directive: Some(Token::Directive {
+                        name: "global".to_string()
+                    }),
+                    label: None,
+                }
+            ))
+        );
+
+        let (_, result) = directive(CompleteStr(".extern print")).unwrap();
+        assert_eq!(result.directive_name(), Some("extern".to_string()));
+        assert!(symbol_directive(CompleteStr(".global")).is_err());
+    }
+
+    #[test]
+    fn test_parse_constant_directive() {
+        let (rest, result) = directive(CompleteStr(".equ size #64\n")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(result.directive_name(), Some("equ".to_string()));
+        assert_eq!(
+            result.operand1,
+            Some(Token::LabelUsage {
+                name: "size".to_string()
+            })
+        );
+        assert_eq!(result.operand2, Some(Token::IntegerOperand { value: 64 }));
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_string_directive() {
         let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
         assert_eq!(result.is_ok(), true);
@@ -1,10 +1,12 @@
+use nom::multispace;
 use nom::types::CompleteStr;
 
-use crate::assembler::directive_parsers::directive_combined;
+use crate::assembler::directive_parsers::directive;
 use crate::assembler::label_parsers::label_declaration;
 use crate::assembler::opcode_parsers::*;
 use crate::assembler::operand_parsers::operand;
 use crate::assembler::{SymbolTable, Token};
+use crate::instruction::Opcode;
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
@@ -17,28 +19,95 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Assembles the instruction, which will be placed at `address` in the program
+    pub fn to_bytes(&self, symbols: &SymbolTable, address: u32) -> Vec<u8> {
         let mut results = vec![];
-        if let Some(Token::Op { code }) = self.opcode {
+        let code = self.assembled_opcode(symbols);
+        if let Some(code) = code {
             results.push(code as u8);
         } else {
             println!("Non-opcode found in opcode field");
         }
+        // Labels used by pc-relative instructions are encoded as the distance to them
+        let relative_to = match code {
+            Some(code) if code.is_pc_relative() => Some(address),
+            _ => None,
+        };
 
-        for operand in vec![&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, symbols)
-            }
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(token, &mut results, symbols, relative_to)
+        }
+
+        // Pad out operands the VM reads but the source left off, e.g. the third byte of `eq $0 $1`
+        while results.len() < self.encoded_len() as usize {
+            results.push(0);
         }
 
         results
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    /// The opcode this instruction is assembled into. Code labels are always referred to
+    /// relative to the instruction using them, so the code works wherever it is loaded:
+    /// `jmp @label` becomes `br`, `jeq @label` becomes `beq` and `load $0 @label` becomes `lea`.
+    pub fn assembled_opcode(&self, symbols: &SymbolTable) -> Option<Opcode> {
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return None,
+        };
+        Some(match (code, &self.operand1, &self.operand2) {
+            (Opcode::JMP, Some(Token::LabelUsage { .. }), _) => Opcode::BR,
+            (Opcode::JEQ, Some(Token::LabelUsage { .. }), _) => Opcode::BEQ,
+            (Opcode::LOAD, _, Some(Token::LabelUsage { name })) if symbols.is_code_label(name) => {
+                Opcode::LEA
+            }
+            _ => code,
+        })
+    }
+
+    /// The number of bytes this instruction takes up once assembled. This is never less than
+    /// the width the VM decodes for the opcode: comparisons such as `eq $0 $1` only name two
+    /// registers but are read as four bytes, so without padding everything after them would be
+    /// decoded out of step.
+    pub fn encoded_len(&self) -> u32 {
+        let mut len = 1;
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            len += match token {
+                Token::Register { .. } | Token::RegisterAlias { .. } => 1,
+                Token::IntegerOperand { .. } | Token::LabelUsage { .. } => 2,
+                _ => 0,
+            };
+        }
+        match self.opcode {
+            Some(Token::Op { code }) => len.max(code.encoded_len() as u32),
+            _ => len,
+        }
+    }
+
+    fn extract_operand(
+        t: &Token,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+        relative_to: Option<u32>,
+    ) {
         match t {
             Token::Register { reg_num } => {
                 results.push(*reg_num);
             }
+            Token::RegisterAlias { name } => {
+                if let Some(reg_num) = symbols.register_alias(name) {
+                    results.push(reg_num);
+                } else {
+                    println!("No register found for alias {:?}", name);
+                }
+            }
             Token::IntegerOperand { value } => {
                 let converted = *value as u16;
                 let byte1 = converted;
@@ -47,15 +116,16 @@ impl AssemblerInstruction {
                 results.push(byte1 as u8);
             }
             Token::LabelUsage { name } => {
-                if let Some(value) = symbols.symbol_value(name) {
-                    let converted = value as u16;
-                    let byte1 = converted;
-                    let byte2 = converted >> 8;
-                    results.push(byte2 as u8);
-                    results.push(byte1 as u8);
-                } else {
-                    println!("No value found for {:?}", name);
-                }
+                // Labels without a value yet (e.g. `.extern` ones) are left as zero for the linker to fill in
+                let converted = match (symbols.symbol_value(name), relative_to) {
+                    (Some(value), Some(address)) => (value as i64 - address as i64) as u16,
+                    (Some(value), None) => value as u16,
+                    (None, _) => 0,
+                };
+                let byte1 = converted;
+                let byte2 = converted >> 8;
+                results.push(byte2 as u8);
+                results.push(byte1 as u8);
             }
             _ => {
                 println!("Opcode found in operand field");
@@ -63,10 +133,12 @@ impl AssemblerInstruction {
         };
     }
 
+    #[allow(clippy::needless_return)]
     pub fn is_label(&self) -> bool {
         return self.label.is_some();
     }
 
+    #[allow(clippy::needless_return)]
     pub fn label_name(&self) -> Option<String> {
         return match &self.label {
             Some(Token::LabelDeclaration { name }) => Some(name.to_string()),
@@ -74,6 +146,7 @@ impl AssemblerInstruction {
         };
     }
 
+    #[allow(clippy::needless_return)]
     pub fn is_directive(&self) -> bool {
         return self.directive.is_some();
     }
@@ -85,14 +158,90 @@ impl AssemblerInstruction {
         None
     }
 
+    #[allow(clippy::needless_return)]
     pub fn is_opcode(&self) -> bool {
         return self.opcode.is_some();
     }
 
+    #[allow(clippy::needless_return)]
     pub fn has_operands(&self) -> bool {
         return self.operand1.is_some();
     }
 
+    /// Returns the names of all register aliases used as operands of this instruction
+    pub fn register_aliases(&self) -> Vec<String> {
+        let mut aliases = vec![];
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            if let Token::RegisterAlias { name } = token {
+                aliases.push(name.to_string());
+            }
+        }
+        aliases
+    }
+
+    /// Returns the names of all labels used as operands of this instruction
+    pub fn label_usages(&self) -> Vec<String> {
+        let mut labels = vec![];
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            if let Token::LabelUsage { name } = token {
+                labels.push(name.to_string());
+            }
+        }
+        labels
+    }
+
+    /// Returns the labels used as operands of this instruction, along with the offset of
+    /// their value in the assembled instruction
+    pub fn label_usage_offsets(&self) -> Vec<(String, u32)> {
+        let mut usages = vec![];
+        let mut offset = 1;
+        for token in [&self.operand1, &self.operand2, &self.operand3]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            match token {
+                Token::LabelUsage { name } => {
+                    usages.push((name.to_string(), offset));
+                    offset += 2;
+                }
+                Token::IntegerOperand { .. } => offset += 2,
+                Token::Register { .. } | Token::RegisterAlias { .. } => offset += 1,
+                _ => {}
+            }
+        }
+        usages
+    }
+
+    /// Returns the labels this instruction refers to relative to itself, along with their
+    /// distance from `address`. Labels without a value yet are left out.
+    pub fn relative_label_offsets(
+        &self,
+        symbols: &SymbolTable,
+        address: u32,
+    ) -> Vec<(String, i64)> {
+        match self.assembled_opcode(symbols) {
+            Some(code) if code.is_pc_relative() => self
+                .label_usages()
+                .into_iter()
+                .filter_map(|name| {
+                    let value = symbols.symbol_value(&name)?;
+                    Some((name, value as i64 - address as i64))
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    #[allow(clippy::collapsible_match)]
     pub fn get_string_constant(&self) -> Option<String> {
         match &self.operand1 {
             Some(d) => match d {
@@ -106,9 +255,10 @@ impl AssemblerInstruction {
 
 named!(pub instruction<CompleteStr, AssemblerInstruction>,
    do_parse!(
+       opt!(multispace) >>
        ins: alt!(
            instruction_combined |
-           directive_combined
+           directive
        ) >> ( ins )
    )
 );
@@ -335,4 +485,24 @@ mod tests {
             assert_eq!(instruction.label_name(), Some("label".to_string()));
         }
     }
+
+    #[test]
+    fn test_comparison_padded_to_decoded_width() {
+        let (_, eq) = instruction(CompleteStr("eq $0 $1\n")).unwrap();
+        assert_eq!(eq.encoded_len(), Opcode::EQ.encoded_len() as u32);
+        assert_eq!(
+            eq.to_bytes(&SymbolTable::new(), 0),
+            vec![Opcode::EQ as u8, 0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_parse_jeq() {
+        let (_, jeq) = instruction(CompleteStr("jeq $0\n")).unwrap();
+        assert_eq!(jeq.opcode, Some(Token::Op { code: Opcode::JEQ }));
+        assert_eq!(
+            jeq.to_bytes(&SymbolTable::new(), 0),
+            vec![Opcode::JEQ as u8, 0]
+        );
+    }
 }
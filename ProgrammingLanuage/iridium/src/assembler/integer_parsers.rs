@@ -20,6 +20,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_integer() {
         // Test a valid integer operand
         let result = integer(CompleteStr("#10"));
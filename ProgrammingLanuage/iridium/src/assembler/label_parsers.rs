@@ -32,8 +32,11 @@ named!(pub label_usage<CompleteStr, Token>,
 );
 
 #[cfg(test)]
-fn tests() {
+mod tests {
+    use super::*;
+
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_label_declaration() {
         let result = label_declaration(CompleteStr("test:"));
         assert_eq!(result.is_ok(), true);
@@ -49,6 +52,7 @@ fn tests() {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_label_usage() {
         let result = label_usage(CompleteStr("@test"));
         assert_eq!(result.is_ok(), true);
@@ -0,0 +1,227 @@
+use std::fmt::Write;
+
+use crate::assembler::{Assembler, AssemblerSection, SymbolType};
+
+/// How many bytes are shown on each row of a listing
+const BYTES_PER_ROW: usize = 8;
+/// How many bytes are shown on each row of a data dump
+const BYTES_PER_DUMP_ROW: usize = 16;
+
+/// Where the assembled bytes of one source line ended up
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingEntry {
+    /// 1-based source line
+    pub line: u32,
+    pub section: AssemblerSection,
+    /// Offset into the assembled program for code, or into the section for data
+    pub offset: u32,
+    /// Number of bytes assembled (or reserved, for .bss)
+    pub len: u32,
+    /// Labels used as operands on this line
+    pub labels: Vec<String>,
+}
+
+impl Assembler {
+    /// Produces a human readable listing of the last program assembled from `source`: every
+    /// source line next to its address and bytes, followed by the symbol table and a dump of
+    /// the data sections
+    pub fn listing(&self, source: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "Iridium assembler listing").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "{:>5}  {:<7}  {:<6}  {:<width$}  SOURCE",
+            "LINE",
+            "SECTION",
+            "ADDR",
+            "BYTES",
+            width = BYTES_PER_ROW * 3 - 1
+        )
+        .unwrap();
+        for (index, text) in source.lines().enumerate() {
+            let line = index as u32 + 1;
+            match self.listing_entries.iter().find(|e| e.line == line) {
+                Some(entry) => self.write_entry(&mut out, entry, text),
+                None => {
+                    writeln!(out, "{:>5}  {:<7}  {:<6}  {:<23}  {}", line, "", "", "", text).unwrap()
+                }
+            }
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "Symbols").unwrap();
+        writeln!(
+            out,
+            "{:<16}  {:<14}  {:<7}  {:<9}  {:<5}  USED ON LINES",
+            "NAME", "TYPE", "SECTION", "VALUE", "SIZE"
+        )
+        .unwrap();
+        for symbol in self.symbols.iter() {
+            let section = match symbol.section {
+                Some(ref section) => section.to_string(),
+                None => "-".to_string(),
+            };
+            let value = match (&symbol.symbol_type, symbol.offset) {
+                (SymbolType::RegisterAlias, Some(reg_num)) => format!("${}", reg_num),
+                (SymbolType::Constant, Some(value)) => format!("#{}", value),
+                (_, Some(offset)) => format!("{:#06x}", offset),
+                (_, None) => "undefined".to_string(),
+            };
+            let size = match symbol.size {
+                Some(size) => size.to_string(),
+                None => "-".to_string(),
+            };
+            let lines: Vec<String> = symbol
+                .references
+                .iter()
+                .map(|location| location.line.to_string())
+                .collect();
+            writeln!(
+                out,
+                "{:<16}  {:<14}  {:<7}  {:<9}  {:<5}  {}",
+                symbol.name,
+                format!("{:?}", symbol.symbol_type),
+                section,
+                value,
+                size,
+                lines.join(", ")
+            )
+            .unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "Read-only data ({} bytes)", self.ro.len()).unwrap();
+        write_dump(&mut out, &self.ro, 0);
+        writeln!(out).unwrap();
+        writeln!(out, "Data ({} bytes)", self.data.len()).unwrap();
+        write_dump(&mut out, &self.data, self.ro.len());
+        out
+    }
+
+    fn write_entry(&self, out: &mut String, entry: &ListingEntry, text: &str) {
+        // Data offsets are relative to their section, turn them into data addresses
+        let (address, bytes) = match entry.section {
+            AssemblerSection::Code { .. } => {
+                let start = entry.offset as usize;
+                (start, self.bytecode.get(start..start + entry.len as usize))
+            }
+            AssemblerSection::ReadOnlyData { .. } => {
+                let start = entry.offset as usize;
+                (start, self.ro.get(start..start + entry.len as usize))
+            }
+            AssemblerSection::Data { .. } => {
+                let start = entry.offset as usize;
+                (
+                    self.ro.len() + start,
+                    self.data.get(start..start + entry.len as usize),
+                )
+            }
+            _ => (
+                self.ro.len() + self.data.len() + entry.offset as usize,
+                None,
+            ),
+        };
+
+        let mut annotated = text.to_string();
+        for label in &entry.labels {
+            match self.symbols.symbol_value(label) {
+                Some(value) => write!(annotated, "    ; @{} = {:#06x}", label, value).unwrap(),
+                None => write!(annotated, "    ; @{} is undefined", label).unwrap(),
+            }
+        }
+
+        let rows: Vec<String> = match bytes {
+            Some(bytes) if !bytes.is_empty() => bytes.chunks(BYTES_PER_ROW).map(hex).collect(),
+            _ => vec![format!("({} bytes reserved)", entry.len)],
+        };
+        for (row_index, row) in rows.iter().enumerate() {
+            if row_index == 0 {
+                writeln!(
+                    out,
+                    "{:>5}  {:<7}  {:04x}    {:<23}  {}",
+                    entry.line,
+                    entry.section.to_string(),
+                    address,
+                    row,
+                    annotated
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    out,
+                    "{:>5}  {:<7}  {:04x}    {}",
+                    "",
+                    "",
+                    address + row_index * BYTES_PER_ROW,
+                    row
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Writes a classic hex dump of `bytes`, which start at `base` in the data address space
+fn write_dump(out: &mut String, bytes: &[u8], base: usize) {
+    for (row_index, row) in bytes.chunks(BYTES_PER_DUMP_ROW).enumerate() {
+        let printable: String = row
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(
+            out,
+            "{:04x}  {:<width$}  |{}|",
+            base + row_index * BYTES_PER_DUMP_ROW,
+            hex(row),
+            printable,
+            width = BYTES_PER_DUMP_ROW * 3 - 1
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let mut asm = Assembler::new();
+        let source = ".rodata\ngreeting: .asciiz 'Hello there, Iridium'\n.bss\nbuffer: .space #16\n.code\nstart: load $0 @start\nprts @greeting\nhlt";
+        asm.assemble(source).unwrap();
+        let listing = asm.listing(source);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert!(lines[3].ends_with(".rodata"));
+        assert!(lines[4].starts_with("    2  .rodata  0000    48 65 6c 6c 6f 20 74 68  greeting:"));
+        // Long data continues on the following rows
+        assert!(lines[5].starts_with("                0008    65 72 65 2c 20 49 72 69"));
+        assert!(lines[8].contains("(16 bytes reserved)"));
+        // Loading a code label is pc-relative
+        assert!(lines[10].starts_with("    6  .code    0040    16 00 00 00"));
+        assert!(lines[10].ends_with("; @start = 0x0040"));
+        assert!(lines[11].ends_with("; @greeting = 0x0000"));
+        assert!(lines[12].starts_with("    8  .code    0047    00"));
+
+        assert!(listing.contains("greeting          DataLabel       .rodata  0x0000     21     7"));
+        assert!(listing.contains("start             CodeLabel       .code    0x0040     8      6"));
+        assert!(listing.contains("buffer            DataLabel       .bss     0x0015     16     \n"));
+        assert!(listing.contains("Read-only data (21 bytes)"));
+        assert!(listing.contains("0000  48 65 6c 6c 6f 20 74 68 65 72 65 2c 20 49 72 69  |Hello there, Iri|"));
+    }
+}
@@ -1,27 +1,38 @@
 use crate::assembler::assembler_errors::AssemblerError;
 use crate::assembler::instruction_parsers::AssemblerInstruction;
-use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::listing::ListingEntry;
+use crate::assembler::program_parsers::{program, Program, SourceLocation};
+use crate::debug_info::{DebugInfo, DebugLabel, LineEntry};
 use crate::instruction::Opcode;
+use crate::linker::object::{ObjectFile, ObjectSection, ObjectSymbol, Relocation};
+use crate::pie::{PieHeader, PIE_FLAG_POSITION_INDEPENDENT};
+use crate::symbol_map::{SymbolKind, SymbolMap, SymbolMapEntry};
 use nom::types::CompleteStr;
+use std::collections::HashMap;
+use std::fmt;
 
 pub mod assembler_errors;
+// The docs of `named!` parsers are not picked up by rustdoc
+#[allow(unused_doc_comments)]
 pub mod directive_parsers;
 pub mod instruction_parsers;
 pub mod integer_parsers;
 pub mod irstring_parsers;
+#[allow(unused_doc_comments)]
 pub mod label_parsers;
+pub mod listing;
 pub mod opcode_parsers;
 pub mod operand_parsers;
 pub mod program_parsers;
 pub mod register_parsers;
 
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
+pub use crate::pie::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
+    RegisterAlias { name: String },
     IntegerOperand { value: i32 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
@@ -29,16 +40,32 @@ pub enum Token {
     IrString { name: String },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SymbolType {
-    Label,
+    // Label declared in `.code`, its value is the address of an instruction
+    CodeLabel,
+    // Label declared in `.rodata`, `.data` or `.bss`, its value is a data address
+    DataLabel,
+    // Value given with `.equ`
+    Constant,
+    RegisterAlias,
+    // Label declared with `.extern`, defined by another object
+    External,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Symbol {
     name: String,
     offset: Option<u32>,
     symbol_type: SymbolType,
+    /// The section a label was declared in, if any
+    section: Option<AssemblerSection>,
+    /// Number of bytes up to the next label of the section, or the end of the section
+    size: Option<u32>,
+    /// Where the symbol was declared
+    location: Option<SourceLocation>,
+    /// Where the symbol is used as an operand
+    references: Vec<SourceLocation>,
 }
 
 impl Symbol {
@@ -47,51 +74,206 @@ impl Symbol {
             name,
             symbol_type,
             offset: Some(offset),
+            section: None,
+            size: None,
+            location: None,
+            references: vec![],
         }
     }
+
+    pub fn new_in_section(
+        name: String,
+        symbol_type: SymbolType,
+        offset: u32,
+        section: AssemblerSection,
+    ) -> Symbol {
+        Symbol {
+            section: Some(section),
+            ..Symbol::new(name, symbol_type, offset)
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn symbol_type(&self) -> SymbolType {
+        self.symbol_type
+    }
+
+    /// The address of a label, the value of a constant or the number of an aliased register
+    pub fn value(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn section(&self) -> Option<&AssemblerSection> {
+        self.section.as_ref()
+    }
+
+    pub fn size(&self) -> Option<u32> {
+        self.size
+    }
+
+    pub fn location(&self) -> Option<SourceLocation> {
+        self.location
+    }
+
+    pub fn references(&self) -> &[SourceLocation] {
+        &self.references
+    }
+
+    pub fn is_label(&self) -> bool {
+        self.symbol_type == SymbolType::CodeLabel || self.symbol_type == SymbolType::DataLabel
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct SymbolTable {
-    symbols: Vec<Symbol>,
+    symbols: HashMap<String, Symbol>,
+    /// Symbol names in the order they were declared, so iterating is deterministic
+    order: Vec<String>,
 }
 
 impl SymbolTable {
     pub fn new() -> SymbolTable {
-        SymbolTable { symbols: vec![] }
+        SymbolTable::default()
     }
 
+    /// Adds a symbol, replacing any symbol of the same name
     pub fn add_symbol(&mut self, s: Symbol) {
-        self.symbols.push(s);
+        if !self.symbols.contains_key(&s.name) {
+            self.order.push(s.name.clone());
+        }
+        self.symbols.insert(s.name.clone(), s);
+    }
+
+    pub fn symbol(&self, s: &str) -> Option<&Symbol> {
+        self.symbols.get(s)
     }
 
+    /// Every symbol, in the order they were declared
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.order.iter().map(move |name| &self.symbols[name])
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The value a symbol stands for when it is used as an operand
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return symbol.offset;
+        match self.symbols.get(s) {
+            Some(symbol) if symbol.is_label() || symbol.symbol_type == SymbolType::Constant => {
+                symbol.offset
             }
+            _ => None,
         }
-        None
     }
 
-    pub fn has_symbol(&self, s: &str) -> bool {
-        for symbol in &self.symbols {
-            // TODO: Find out if there is a way to not have to specify `return true;` in an if
-            if symbol.name == s {
-                return true;
+    /// Returns the register number a `.reg` alias was declared for
+    pub fn register_alias(&self, s: &str) -> Option<u8> {
+        match self.symbols.get(s) {
+            Some(symbol) if symbol.symbol_type == SymbolType::RegisterAlias => {
+                symbol.offset.map(|reg_num| reg_num as u8)
             }
+            _ => None,
         }
-        false
+    }
+
+    fn has_type(&self, s: &str, symbol_type: SymbolType) -> bool {
+        self.symbols
+            .get(s)
+            .is_some_and(|symbol| symbol.symbol_type == symbol_type)
+    }
+
+    /// Whether `s` is a label declared in a code section
+    pub fn is_code_label(&self, s: &str) -> bool {
+        self.has_type(s, SymbolType::CodeLabel)
+    }
+
+    /// Whether `s` was declared with `.extern`
+    pub fn is_external(&self, s: &str) -> bool {
+        self.has_type(s, SymbolType::External)
+    }
+
+    /// Whether `s` was declared with `.equ`
+    pub fn is_constant(&self, s: &str) -> bool {
+        self.has_type(s, SymbolType::Constant)
+    }
+
+    pub fn has_symbol(&self, s: &str) -> bool {
+        self.symbols.contains_key(s)
     }
 
     pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
-        for symbol in &mut self.symbols {
-            if symbol.name == s {
+        match self.symbols.get_mut(s) {
+            Some(symbol) => {
                 symbol.offset = Some(offset);
-                return true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that `s` is used as an operand at `location`
+    pub fn add_reference(&mut self, s: &str, location: SourceLocation) -> bool {
+        match self.symbols.get_mut(s) {
+            Some(symbol) => {
+                symbol.references.push(location);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Where `s` is used as an operand
+    pub fn references(&self, s: &str) -> &[SourceLocation] {
+        match self.symbols.get(s) {
+            Some(symbol) => &symbol.references,
+            None => &[],
+        }
+    }
+
+    /// Labels that are declared but never used as an operand
+    pub fn unused_labels(&self) -> Vec<&Symbol> {
+        self.iter()
+            .filter(|symbol| symbol.is_label() && symbol.references.is_empty())
+            .collect()
+    }
+
+    /// Moves every symbol declared in `section` by `base`, turning section-relative
+    /// offsets into addresses
+    pub fn relocate_section(&mut self, section: &AssemblerSection, base: u32) {
+        for symbol in self.symbols.values_mut() {
+            if symbol.section.as_ref() == Some(section) {
+                symbol.offset = symbol.offset.map(|offset| offset + base);
+            }
+        }
+    }
+
+    /// Sizes every label of `section`, which ends at `end`: a label extends up to the next
+    /// label of its section
+    fn size_labels(&mut self, section: &AssemblerSection, end: u32) {
+        let mut starts: Vec<u32> = self
+            .symbols
+            .values()
+            .filter(|symbol| symbol.is_label() && symbol.section.as_ref() == Some(section))
+            .filter_map(|symbol| symbol.offset)
+            .collect();
+        starts.push(end);
+        for symbol in self.symbols.values_mut() {
+            if !symbol.is_label() || symbol.section.as_ref() != Some(section) {
+                continue;
+            }
+            if let Some(offset) = symbol.offset {
+                let next = starts.iter().filter(|start| **start > offset).min();
+                symbol.size = Some(next.map_or(0, |next| next - offset));
             }
         }
-        false
     }
 }
 
@@ -101,25 +283,52 @@ pub enum AssemblerPhase {
     Second,
 }
 
+/// Sections an assembly file can be split into. In the VM's data address space, `.rodata`
+/// is mapped first, followed by `.data` and then `.bss`.
 #[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerSection {
+    // Read-only initialized data
+    ReadOnlyData { starting_instruction: Option<u32> },
+    // Writable initialized data
     Data { starting_instruction: Option<u32> },
+    // Writable data which is zeroed when the program is loaded
+    Bss { starting_instruction: Option<u32> },
     Code { starting_instruction: Option<u32> },
     Unknown,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for AssemblerSection {
     fn default() -> Self {
         AssemblerSection::Unknown
     }
 }
 
+impl fmt::Display for AssemblerSection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssemblerSection::ReadOnlyData { .. } => f.write_str(".rodata"),
+            AssemblerSection::Data { .. } => f.write_str(".data"),
+            AssemblerSection::Bss { .. } => f.write_str(".bss"),
+            AssemblerSection::Code { .. } => f.write_str(".code"),
+            AssemblerSection::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+#[allow(clippy::needless_lifetimes)]
 impl<'a> From<&'a str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
+            "rodata" => AssemblerSection::ReadOnlyData {
+                starting_instruction: None,
+            },
             "data" => AssemblerSection::Data {
                 starting_instruction: None,
             },
+            "bss" => AssemblerSection::Bss {
+                starting_instruction: None,
+            },
             "code" => AssemblerSection::Code {
                 starting_instruction: None,
             },
@@ -136,10 +345,21 @@ pub struct Assembler {
     pub symbols: SymbolTable,
     /// The read-only data section constants are put in
     pub ro: Vec<u8>,
+    /// The writable, initialized data section
+    pub data: Vec<u8>,
+    /// The number of zeroed bytes reserved in the .bss section
+    pub bss_size: u32,
     /// The compiled bytecode generated from the assembly instructions
     pub bytecode: Vec<u8>,
     /// Tracks the current offset of the read-only section
     ro_offset: u32,
+    /// Tracks the current offset of the writable data section
+    data_offset: u32,
+    /// Tracks the current offset of the code, not counting the header
+    code_offset: u32,
+    /// The address the code is placed at, right after the header unless code is added with
+    /// `assemble_more`
+    code_base: u32,
     /// A list of all the sections we've seen in the code
     sections: Vec<AssemblerSection>,
     /// The current section the assembler is in
@@ -148,83 +368,326 @@ pub struct Assembler {
     current_instruction: u32,
     /// Any errors we find along the way. At the end, we'll present them to the user.
     errors: Vec<AssemblerError>,
+    /// Where each instruction of the program being assembled starts in the source
+    locations: Vec<SourceLocation>,
+    /// The bytes each source line was assembled into, used to produce listings
+    listing_entries: Vec<ListingEntry>,
+    /// Where each instruction of the code came from, used to produce debug info
+    debug_lines: Vec<LineEntry>,
+    /// Labels declared with `.global`
+    globals: Vec<String>,
+    /// Every label operand in the code, for the linker to patch
+    relocations: Vec<Relocation>,
 }
 
 impl Assembler {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
             ro: vec![],
+            data: vec![],
+            bss_size: 0,
             bytecode: vec![],
             current_instruction: 0,
             current_section: None,
             sections: vec![],
             errors: vec![],
             ro_offset: 0,
+            data_offset: 0,
+            code_offset: 0,
+            code_base: PIE_HEADER_LENGTH as u32,
+            locations: vec![],
+            listing_entries: vec![],
+            debug_lines: vec![],
+            globals: vec![],
+            relocations: vec![],
+        }
+    }
+
+    /// Builds the program image: the header, then `code`, followed by the read-only and
+    /// initialized data sections so the program can be run without its source
+    fn write_pie_image(&self, code: &[u8]) -> Vec<u8> {
+        // Code labels are only used through pc-relative instructions
+        PieHeader::build_image(
+            code,
+            &self.ro,
+            &self.data,
+            self.bss_size,
+            PIE_FLAG_POSITION_INDEPENDENT,
+        )
+    }
+
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let code = self.assemble_code(raw)?;
+        // Programs have to be complete, labels from other files can only be used when linking
+        for relocation in &self.relocations {
+            if self.symbols.symbol_value(&relocation.symbol).is_none() {
+                self.errors.push(AssemblerError::UnresolvedExternal {
+                    name: relocation.symbol.clone(),
+                });
+            }
         }
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        };
+
+        // Put the header and the data sections around the code
+        let assembled_program = self.write_pie_image(&code);
+        self.bytecode = assembled_program.clone();
+        Ok(assembled_program)
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            header.push(byte.clone());
+    /// Assembles `raw` like `assemble` does, and appends debug info mapping the code back to
+    /// `file` so faults can be reported by source line
+    pub fn assemble_with_debug_info(
+        &mut self,
+        raw: &str,
+        file: &str,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut assembled_program = self.assemble(raw)?;
+        let debug_info = self.debug_info(file).to_bytes();
+        // The image was just built from a valid header, so it can't be rejected
+        PieHeader::append_debug_info(&mut assembled_program, &debug_info).unwrap();
+        self.bytecode = assembled_program.clone();
+        Ok(assembled_program)
+    }
+
+    /// The debug info of the last program assembled, which was read from `file`
+    pub fn debug_info(&self, file: &str) -> DebugInfo {
+        let mut debug_info = DebugInfo::new(file);
+        debug_info.lines = self.debug_lines.clone();
+        let code_labels = self
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.symbol_type == SymbolType::CodeLabel);
+        for symbol in code_labels {
+            if let Some(offset) = symbol.offset {
+                debug_info.labels.push(DebugLabel {
+                    name: symbol.name.clone(),
+                    offset,
+                });
+            }
         }
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+        debug_info.labels.sort_by_key(|label| label.offset);
+        debug_info
+    }
+
+    /// The labels of the last program assembled, with the addresses they ended up at
+    pub fn symbol_map(&self) -> SymbolMap {
+        let mut map = SymbolMap::new();
+        for symbol in self.symbols.iter().filter(|symbol| symbol.is_label()) {
+            let offset = match symbol.offset {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let (kind, section) = match symbol.section {
+                Some(AssemblerSection::ReadOnlyData { .. }) => {
+                    (SymbolKind::Data, ObjectSection::ReadOnlyData)
+                }
+                Some(AssemblerSection::Data { .. }) => (SymbolKind::Data, ObjectSection::Data),
+                Some(AssemblerSection::Bss { .. }) => (SymbolKind::Data, ObjectSection::Bss),
+                _ => (SymbolKind::Code, ObjectSection::Code),
+            };
+            map.entries.push(SymbolMapEntry {
+                name: symbol.name.clone(),
+                kind,
+                section,
+                offset,
+                size: symbol.size.unwrap_or(0),
+            });
         }
-        header
+        map.entries
+            .sort_by_key(|entry| (entry.kind == SymbolKind::Data, entry.offset));
+        map
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
-        // Runs the raw program through our `nom` parser
-        match program(CompleteStr(raw)) {
-            // If there were no parsing errors, we now have a `Vec<AssemblyInstructions>` to process.
-            // `remainder` _should_ be "".
-            // TODO: Add a check for `remainder`, make sure it is "".
-            Ok((_remainder, program)) => {
-                // println!("{:?}", program);
-                // Start processing the AssembledInstructions. This is the first pass of our two-pass assembler.
-                // We pass a read-only reference down to another function.
-                self.process_first_phase(&program);
-
-                // If we accumulated any errors in the first pass, return them and don't try to do the second pass
-                if !self.errors.is_empty() {
-                    // TODO: Can we avoid a clone here?
-                    return Err(self.errors.clone());
-                };
+    /// Labels that are neither used as an operand nor exported with `.global`
+    pub fn unused_labels(&self) -> Vec<&Symbol> {
+        self.symbols
+            .unused_labels()
+            .into_iter()
+            .filter(|symbol| !self.globals.contains(&symbol.name))
+            .collect()
+    }
 
-                // Make sure that we have at least one data section and one code section
-                if self.sections.len() != 2 {
-                    // TODO: Detail out which one(s) are missing
-                    println!("Did not find at least two sections.");
-                    self.errors.push(AssemblerError::InsufficientSections);
-                    // TODO: Can we avoid a clone here?
-                    return Err(self.errors.clone());
+    /// Assembles `raw` into an object file, which can use labels declared with `.extern` and
+    /// has to be linked before it can be run
+    pub fn assemble_object(&mut self, raw: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let code = self.assemble_code(raw)?;
+        let mut object = ObjectFile {
+            code,
+            ro: self.ro.clone(),
+            data: self.data.clone(),
+            bss_size: self.bss_size,
+            relocations: self.relocations.clone(),
+            ..ObjectFile::new()
+        };
+
+        // Labels are stored relative to their section, undoing the layout `process_first_phase` picked
+        let data_base = self.ro.len() as u32;
+        let bss_base = data_base + self.data.len() as u32;
+        for symbol in self.symbols.iter() {
+            let offset = match (symbol.symbol_type, symbol.offset) {
+                (SymbolType::CodeLabel, Some(offset)) | (SymbolType::DataLabel, Some(offset)) => {
+                    offset
+                }
+                (SymbolType::External, _) => {
+                    object.imports.push(symbol.name.clone());
+                    continue;
                 }
-                // Run the second pass, which translates opcodes and associated operands into the bytecode
-                let mut body = self.process_second_phase(&program);
+                _ => continue,
+            };
+            let (section, base) = match symbol.section {
+                Some(AssemblerSection::ReadOnlyData { .. }) => (ObjectSection::ReadOnlyData, 0),
+                Some(AssemblerSection::Data { .. }) => (ObjectSection::Data, data_base),
+                Some(AssemblerSection::Bss { .. }) => (ObjectSection::Bss, bss_base),
+                _ => (ObjectSection::Code, PIE_HEADER_LENGTH as u32),
+            };
+            object.symbols.push(ObjectSymbol {
+                name: symbol.name.clone(),
+                section,
+                offset: offset - base,
+                global: self.globals.contains(&symbol.name),
+            });
+        }
 
-                // Get the header
-                let mut assembled_program = self.write_pie_header();
-                // Merge the header with the populated body vector
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
-            }
-            // If there were parsing errors, bad syntax, etc, this arm is run
-            Err(e) => {
-                println!("There was an error parsing the code: {:?}", e);
-                Err(vec![AssemblerError::ParseError {
-                    error: e.to_string(),
-                }])
+        for name in &self.globals {
+            if self.symbols.symbol_value(name).is_none() {
+                self.errors
+                    .push(AssemblerError::UndefinedSymbol { name: name.clone() });
             }
         }
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        };
+        Ok(object)
+    }
+
+    /// Runs both passes over `raw`, returning the code without a header
+    fn assemble_code(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        // Every program is assembled from scratch, nothing is kept from an earlier call
+        self.reset();
+        let program = parse(raw)?;
+        self.locations = program.locations.clone();
+        // Start processing the AssembledInstructions. This is the first pass of our two-pass assembler.
+        // We pass a read-only reference down to another function.
+        self.process_first_phase(&program);
+
+        // If we accumulated any errors in the first pass, return them and don't try to do the second pass
+        if !self.errors.is_empty() {
+            // TODO: Can we avoid a clone here?
+            return Err(self.errors.clone());
+        };
+
+        // Data sections are optional, but there is nothing to run without a code section
+        if !self.has_section(&AssemblerSection::from("code")) {
+            println!("Did not find a code section.");
+            self.errors.push(AssemblerError::MissingSection {
+                section: AssemblerSection::from("code"),
+            });
+            // TODO: Can we avoid a clone here?
+            return Err(self.errors.clone());
+        }
+        // Run the second pass, which translates opcodes and associated operands into the bytecode
+        let code = self.process_second_phase(&program);
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        };
+        Ok(code)
+    }
+
+    /// Forgets everything about the last program assembled, symbols included
+    pub fn reset(&mut self) {
+        *self = Assembler::new();
+    }
+
+    /// Assembles `raw` as more code for the program assembled so far, e.g. a line typed at the
+    /// REPL. Symbols declared earlier can be used and new ones are added to them; the code is
+    /// placed at `address`. If assembling fails, the symbols are left as they were.
+    ///
+    /// Only code can be added: data sections are mapped when a program is loaded into the VM.
+    pub fn assemble_more(
+        &mut self,
+        raw: &str,
+        address: u32,
+    ) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let program = parse(raw)?;
+        let symbols = self.symbols.clone();
+        let data_sizes = (self.ro.len(), self.data.len(), self.bss_size);
+        self.errors.clear();
+        self.relocations.clear();
+        self.locations = program.locations.clone();
+        self.phase = AssemblerPhase::First;
+        self.current_instruction = 0;
+        self.code_base = address;
+        self.code_offset = 0;
+        // Lines typed on their own belong to the code, like the lines of a program without sections
+        self.process_section_header("code");
+
+        self.declare_symbols(&program);
+        if (self.ro.len(), self.data.len(), self.bss_size) != data_sizes {
+            self.errors.push(AssemblerError::DataAfterLoading);
+        }
+        self.phase = AssemblerPhase::Second;
+        let code = if self.errors.is_empty() {
+            self.process_second_phase(&program)
+        } else {
+            vec![]
+        };
+        if !self.errors.is_empty() {
+            self.symbols = symbols;
+            self.ro.truncate(data_sizes.0);
+            self.data.truncate(data_sizes.1);
+            self.bss_size = data_sizes.2;
+            return Err(self.errors.clone());
+        }
+        Ok(code)
     }
 
     // Extract all labels, build symbol table
     fn process_first_phase(&mut self, p: &Program) {
+        self.declare_symbols(p);
+        // Data labels were recorded relative to their section. Now that the size of every
+        // section is known, turn them into addresses in the VM's data address space.
+        self.symbols.relocate_section(
+            &AssemblerSection::Data {
+                starting_instruction: None,
+            },
+            self.ro_offset,
+        );
+        self.symbols.relocate_section(
+            &AssemblerSection::Bss {
+                starting_instruction: None,
+            },
+            self.ro_offset + self.data_offset,
+        );
+        let data_end = self.ro_offset + self.data_offset;
+        self.symbols
+            .size_labels(&AssemblerSection::from("rodata"), self.ro_offset);
+        self.symbols
+            .size_labels(&AssemblerSection::from("data"), data_end);
+        self.symbols
+            .size_labels(&AssemblerSection::from("bss"), data_end + self.bss_size);
+        self.symbols.size_labels(
+            &AssemblerSection::from("code"),
+            self.code_base + self.code_offset,
+        );
+        // Once we're done with this function, set the phase to second
+        self.phase = AssemblerPhase::Second;
+    }
+
+    /// Declares the labels and handles the directives of every instruction of `p`
+    #[allow(clippy::needless_borrow)]
+    fn declare_symbols(&mut self, p: &Program) {
         // Iterate over every instruction, even though in the first phase we care about labels and directives but nothing else
         for i in &p.instructions {
+            if i.is_opcode() && self.current_section.is_none() {
+                // Instructions before any section header are treated as if `.code` had been written
+                self.process_section_header("code");
+            }
+
             if i.is_label() {
                 // TODO: Factor this out into another function? Put it in `process_label_declaration`?
                 if self.current_section.is_some() {
@@ -241,12 +704,26 @@ impl Assembler {
             if i.is_directive() {
                 self.process_directive(i);
             }
+
+            if i.is_opcode() {
+                match self.current_section {
+                    Some(AssemblerSection::Code { .. }) => {
+                        self.code_offset += i.encoded_len();
+                    }
+                    Some(ref section) => {
+                        self.errors
+                            .push(AssemblerError::InstructionOutsideCodeSection {
+                                section: section.clone(),
+                                instruction: self.current_instruction,
+                            });
+                    }
+                    None => {}
+                }
+            }
             // This is used to keep track of which instruction we hit an error on
             // TODO: Do we really need to track this?
             self.current_instruction += 1;
         }
-        // Once we're done with this function, set the phase to second
-        self.phase = AssemblerPhase::Second;
     }
 
     /// Handles the declaration of a label such as:
@@ -272,11 +749,41 @@ impl Assembler {
             return;
         }
 
-        // If we make it here, it isn't a symbol we've seen before, so stick it in the table
-        let symbol = Symbol::new(name, SymbolType::Label, (self.current_instruction * 4) + 60);
+        // If we make it here, it isn't a symbol we've seen before, so stick it in the table.
+        // Labels in data sections point at the next byte of their section.
+        let mut symbol = match self.current_section {
+            Some(AssemblerSection::ReadOnlyData { .. }) => Symbol::new_in_section(
+                name,
+                SymbolType::DataLabel,
+                self.ro_offset,
+                AssemblerSection::from("rodata"),
+            ),
+            Some(AssemblerSection::Data { .. }) => Symbol::new_in_section(
+                name,
+                SymbolType::DataLabel,
+                self.data_offset,
+                AssemblerSection::from("data"),
+            ),
+            Some(AssemblerSection::Bss { .. }) => Symbol::new_in_section(
+                name,
+                SymbolType::DataLabel,
+                self.bss_size,
+                AssemblerSection::from("bss"),
+            ),
+            // Code labels point at the bytecode of their instruction. Code sections are
+            // merged in the order they appear, right after the header.
+            _ => Symbol::new_in_section(
+                name,
+                SymbolType::CodeLabel,
+                self.code_base + self.code_offset,
+                AssemblerSection::from("code"),
+            ),
+        };
+        symbol.location = self.current_location();
         self.symbols.add_symbol(symbol);
     }
 
+    #[allow(clippy::needless_return)]
     fn process_directive(&mut self, i: &AssemblerInstruction) {
         // First let’s make sure we have a parseable nae
         let directive_name = match i.directive_name() {
@@ -295,6 +802,30 @@ impl Assembler {
                 "asciiz" => {
                     self.handle_asciiz(i);
                 }
+                // A big-endian 32 bit integer, e.g. `counter: .word #10`
+                "word" => {
+                    self.handle_word(i);
+                }
+                // A run of zeroed bytes, e.g. `buffer: .space #64`
+                "space" => {
+                    self.handle_space(i);
+                }
+                // Declares a per-file name for a register, e.g. `.reg counter $5`
+                "reg" => {
+                    self.handle_register_alias(i);
+                }
+                // Names a constant value, e.g. `.equ size #64`
+                "equ" => {
+                    self.handle_constant(i);
+                }
+                // Exports a label to other objects, e.g. `.global main`
+                "global" => {
+                    self.handle_global(i);
+                }
+                // Declares a label defined by another object, e.g. `.extern print`
+                "extern" => {
+                    self.handle_extern(i);
+                }
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound {
                         directive: directive_name.clone(),
@@ -320,11 +851,40 @@ impl Assembler {
             );
             return;
         }
-        // TODO: Check if we really need to keep a list of all sections seen
-        self.sections.push(new_section.clone());
+        // Sections can be opened more than once; their contents are merged in the order they appear
+        if !self.has_section(&new_section) {
+            self.sections.push(new_section.clone());
+        }
         self.current_section = Some(new_section);
     }
 
+    /// Where the current instruction starts in the source
+    fn current_location(&self) -> Option<SourceLocation> {
+        self.locations
+            .get(self.current_instruction as usize)
+            .copied()
+    }
+
+    /// Records that the current instruction was assembled into `len` bytes at `offset` of the current section
+    fn add_listing_entry(&mut self, offset: u32, len: u32, labels: Vec<String>) {
+        let line = match self.locations.get(self.current_instruction as usize) {
+            Some(location) => location.line,
+            None => return,
+        };
+        let section = self.current_section.clone().unwrap_or_default();
+        self.listing_entries.push(ListingEntry {
+            line,
+            section,
+            offset,
+            len,
+            labels,
+        });
+    }
+
+    fn has_section(&self, section: &AssemblerSection) -> bool {
+        self.sections.contains(section)
+    }
+
     /// Handles a declaration of a null-terminated string:
     /// hello: .asciiz 'Hello!'
     fn handle_asciiz(&mut self, i: &AssemblerInstruction) {
@@ -333,28 +893,19 @@ impl Assembler {
             return;
         }
 
-        // In this case, operand1 will have the entire string we need to read in to RO memory
+        // In this case, operand1 will have the entire string we need to read in to memory
         match i.get_string_constant() {
             Some(s) => {
-                match i.label_name() {
-                    Some(name) => {
-                        self.symbols.set_symbol_offset(&name, self.ro_offset);
-                    }
-                    None => {
-                        // This would be someone typing:
-                        // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
-                        return;
-                    }
+                if i.label_name().is_none() {
+                    // This would be someone typing:
+                    // .asciiz 'Hello'
+                    println!("Found a string constant with no associated label!");
+                    return;
                 };
-                // We'll read the string into the read-only section byte-by-byte
-                for byte in s.as_bytes() {
-                    self.ro.push(*byte);
-                    self.ro_offset += 1;
-                }
+                let mut bytes = s.into_bytes();
                 // This is the null termination bit we are using to indicate a string has ended
-                self.ro.push(0);
-                self.ro_offset += 1;
+                bytes.push(0);
+                self.write_data("asciiz", &bytes);
             }
             None => {
                 // This just means someone typed `.asciiz` for some reason
@@ -363,13 +914,221 @@ impl Assembler {
         }
     }
 
+    /// Handles a declaration of a 32 bit integer:
+    /// counter: .word #10
+    fn handle_word(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        match i.operand1 {
+            Some(Token::IntegerOperand { value }) => {
+                self.write_data("word", &value.to_be_bytes());
+            }
+            _ => {
+                println!("A .word must be followed by an integer: {:?}", i);
+            }
+        }
+    }
+
+    /// Handles a reservation of zeroed bytes:
+    /// buffer: .space #64
+    fn handle_space(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        let size = match i.operand1 {
+            Some(Token::IntegerOperand { value }) => value as u32,
+            _ => {
+                println!("A .space must be followed by an integer: {:?}", i);
+                return;
+            }
+        };
+        // The .bss section holds no bytes in the assembled program, only its size
+        if let Some(AssemblerSection::Bss { .. }) = self.current_section {
+            self.add_listing_entry(self.bss_size, size, vec![]);
+            self.bss_size += size;
+        } else {
+            self.write_data("space", &vec![0; size as usize]);
+        }
+    }
+
+    /// Appends initialized data to the current section, which has to be `.rodata` or `.data`
+    fn write_data(&mut self, directive: &str, bytes: &[u8]) {
+        match self.current_section {
+            Some(AssemblerSection::ReadOnlyData { .. }) => {
+                self.add_listing_entry(self.ro_offset, bytes.len() as u32, vec![]);
+                self.ro.extend_from_slice(bytes);
+                self.ro_offset += bytes.len() as u32;
+            }
+            Some(AssemblerSection::Data { .. }) => {
+                self.add_listing_entry(self.data_offset, bytes.len() as u32, vec![]);
+                self.data.extend_from_slice(bytes);
+                self.data_offset += bytes.len() as u32;
+            }
+            Some(ref section) => {
+                self.errors.push(AssemblerError::DirectiveNotAllowedInSection {
+                    directive: directive.to_string(),
+                    section: section.clone(),
+                    instruction: self.current_instruction,
+                });
+            }
+            None => {
+                self.errors.push(AssemblerError::NoSegmentDeclarationFound {
+                    instruction: self.current_instruction,
+                });
+            }
+        }
+    }
+
+    /// Handles a declaration of a register alias:
+    /// .reg counter $5
+    fn handle_register_alias(&mut self, i: &AssemblerInstruction) {
+        // Aliases have to be known before any bytecode is generated, so they are only declared in the first pass
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+
+        let name = match &i.operand1 {
+            Some(Token::RegisterAlias { name }) => name.to_string(),
+            _ => {
+                self.errors.push(AssemblerError::InvalidRegisterAlias {
+                    instruction: self.current_instruction,
+                });
+                return;
+            }
+        };
+        // An alias may also be declared in terms of an alias declared before it
+        let reg_num = match &i.operand2 {
+            Some(Token::Register { reg_num }) => *reg_num,
+            Some(Token::RegisterAlias { name }) => match self.symbols.register_alias(name) {
+                Some(reg_num) => reg_num,
+                None => {
+                    self.errors.push(AssemblerError::UnknownRegisterAlias {
+                        alias: name.to_string(),
+                    });
+                    return;
+                }
+            },
+            _ => {
+                self.errors.push(AssemblerError::InvalidRegisterAlias {
+                    instruction: self.current_instruction,
+                });
+                return;
+            }
+        };
+
+        if self.symbols.has_symbol(&name) {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+            return;
+        }
+        let mut symbol = Symbol::new(name, SymbolType::RegisterAlias, reg_num as u32);
+        symbol.location = self.current_location();
+        self.symbols.add_symbol(symbol);
+    }
+
+    /// Handles an export of a label, which may be declared before or after the label itself:
+    /// .global main
+    fn handle_global(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        if let Some(Token::LabelUsage { name }) = &i.operand1 {
+            if !self.globals.contains(name) {
+                self.globals.push(name.to_string());
+            }
+        }
+    }
+
+    /// Handles a declaration of a constant, which can be used wherever a label can:
+    /// .equ size #64
+    fn handle_constant(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        if let (Some(Token::LabelUsage { name }), Some(Token::IntegerOperand { value })) =
+            (&i.operand1, &i.operand2)
+        {
+            if self.symbols.has_symbol(name) {
+                self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                return;
+            }
+            let mut symbol = Symbol::new(name.to_string(), SymbolType::Constant, *value as u32);
+            symbol.location = self.current_location();
+            self.symbols.add_symbol(symbol);
+        }
+    }
+
+    /// Handles a declaration of a label defined in another object:
+    /// .extern print
+    fn handle_extern(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        if let Some(Token::LabelUsage { name }) = &i.operand1 {
+            if self.symbols.has_symbol(name) {
+                self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+                return;
+            }
+            self.symbols.add_symbol(Symbol {
+                offset: None,
+                location: self.current_location(),
+                ..Symbol::new(name.to_string(), SymbolType::External, 0)
+            });
+        }
+    }
+
     // Build program(byte code)
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         self.current_instruction = 0;
+        self.current_section = None;
+        self.debug_lines.clear();
         let mut program = vec![];
         for i in &p.instructions {
             if i.is_opcode() {
-                let mut bytes = i.to_bytes(&self.symbols);
+                for alias in i.register_aliases() {
+                    if self.symbols.register_alias(&alias).is_none() {
+                        self.errors
+                            .push(AssemblerError::UnknownRegisterAlias { alias });
+                    }
+                }
+                for (name, offset) in i.label_usage_offsets() {
+                    if self.symbols.symbol_value(&name).is_none()
+                        && !self.symbols.is_external(&name)
+                    {
+                        self.errors
+                            .push(AssemblerError::UndefinedSymbol { name: name.clone() });
+                    }
+                    if let Some(location) = self.current_location() {
+                        self.symbols.add_reference(&name, location);
+                    }
+                    // Constants are the same wherever the code ends up
+                    if self.symbols.is_constant(&name) {
+                        continue;
+                    }
+                    self.relocations.push(Relocation {
+                        instruction: program.len() as u32,
+                        offset: program.len() as u32 + offset,
+                        symbol: name,
+                    });
+                }
+                let address = self.code_base + program.len() as u32;
+                for (name, offset) in i.relative_label_offsets(&self.symbols, address) {
+                    if offset < i64::from(i16::MIN) || offset > i64::from(i16::MAX) {
+                        self.errors
+                            .push(AssemblerError::OffsetOutOfRange { name, offset });
+                    }
+                }
+                if let Some(location) = self.locations.get(self.current_instruction as usize) {
+                    self.debug_lines.push(LineEntry {
+                        offset: address,
+                        line: location.line,
+                        column: location.column,
+                    });
+                }
+                let mut bytes = i.to_bytes(&self.symbols, address);
+                self.add_listing_entry(address, bytes.len() as u32, i.label_usages());
                 program.append(&mut bytes);
             }
             if i.is_directive() {
@@ -383,18 +1142,44 @@ impl Assembler {
     }
 }
 
+/// Parses a whole program, failing if any of it can't be made sense of
+fn parse(raw: &str) -> Result<Program, Vec<AssemblerError>> {
+    // Runs the raw program through our `nom` parser
+    match program(CompleteStr(raw)) {
+        // `remainder` _should_ be "", anything else is input the parser could not make sense of
+        // (e.g., `$200`, which is not a register).
+        Ok((remainder, _)) if !remainder.trim().is_empty() => {
+            let unparsed = remainder.trim().lines().next().unwrap_or_default();
+            println!("There was an error parsing the code near: {}", unparsed);
+            Err(vec![AssemblerError::ParseError {
+                error: format!("Unable to parse `{}`", unparsed),
+            }])
+        }
+        Ok((_remainder, program)) => Ok(program),
+        // If there were parsing errors, bad syntax, etc, this arm is run
+        Err(e) => {
+            println!("There was an error parsing the code: {:?}", e);
+            Err(vec![AssemblerError::ParseError {
+                error: e.to_string(),
+            }])
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    #[allow(unused_imports)]
     use super::super::*;
     use super::*;
     use crate::vm::VM;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
-        let new_symbol = Symbol::new("test".to_string(), SymbolType::Label, 12);
+        let new_symbol = Symbol::new("test".to_string(), SymbolType::CodeLabel, 12);
         sym.add_symbol(new_symbol);
-        assert_eq!(sym.symbols.len(), 1);
+        assert_eq!(sym.len(), 1);
         let v = sym.symbol_value("test");
         assert_eq!(true, v.is_some());
         let v = v.unwrap();
@@ -417,15 +1202,494 @@ mod tests {
             ";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
-        assert_eq!(program.len(), 17 + PIE_HEADER_LENGTH);
+        // `neq` is padded to the 4 bytes the VM reads
+        assert_eq!(program.len(), 18 + PIE_HEADER_LENGTH);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 17 + PIE_HEADER_LENGTH);
+        assert_eq!(vm.program.len(), 18 + PIE_HEADER_LENGTH);
     }
 
     #[test]
-    fn test_write_pie_header() {
+    fn test_assemble_register_aliases() {
         let mut asm = Assembler::new();
-        let header = asm.write_pie_header();
-        assert_eq!(header.len(), PIE_HEADER_LENGTH);
+        let test_string = r"
+            .data
+            .code
+            .reg counter $5
+            .reg limit $counter
+            load $counter #100
+            load $sp #1
+            add $counter $ra $t0
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        let body = &program[PIE_HEADER_LENGTH..];
+        assert_eq!(body, &[1, 5, 0, 100, 1, 30, 0, 1, 2, 5, 31, 4][..]);
+        assert_eq!(asm.symbols.register_alias("limit"), Some(5));
+    }
+
+    #[test]
+    fn test_assemble_unknown_register_alias() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .data
+            .code
+            load $counter #100
+            ";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            AssemblerError::UnknownRegisterAlias {
+                alias: "counter".to_string()
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_assemble_register_out_of_range() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .data
+            .code
+            load $200 #100
+            ";
+        assert!(asm.assemble(test_string).is_err());
+    }
+
+    #[test]
+    fn test_assemble_data_sections() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .rodata
+            greeting: .asciiz 'Hi'
+            .data
+            counter: .word #258
+            .bss
+            buffer: .space #16
+            .code
+            load $0 @counter
+            lw $1 $0
+            sw $1 $0
+            load $2 @buffer
+            prts @greeting
+            ";
+        asm.assemble(test_string).unwrap();
+        assert_eq!(asm.ro, vec![72, 105, 0]);
+        assert_eq!(asm.data, vec![0, 0, 1, 2]);
+        assert_eq!(asm.bss_size, 16);
+        assert_eq!(asm.symbols.symbol_value("greeting"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("counter"), Some(3));
+        assert_eq!(asm.symbols.symbol_value("buffer"), Some(7));
+    }
+
+    #[test]
+    fn test_assemble_initialized_data_in_bss() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .bss
+            greeting: .asciiz 'Hi'
+            .code
+            hlt
+            ";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains(".bss"));
+    }
+
+    #[test]
+    fn test_run_data_sections() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .data
+            counter: .word #41
+            .code
+            load $0 @counter
+            lw $1 $0
+            inc $1
+            sw $1 $0
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        // Everything the VM needs is in the program itself
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run();
+        assert_eq!(vm.read_word(0), Ok(42));
+    }
+
+    #[test]
+    fn test_run_exit_status() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .code
+            load $0 #7
+            exit $0
+            load $0 #8
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        assert_eq!(vm.run().exit_code(), 7);
+        assert_eq!(vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_assemble_reopened_sections() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .data
+            first: .word #1
+            .code
+            load $0 @second
+            start: lw $1 $0
+            .data
+            second: .word #2
+            .code
+            end: hlt
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(asm.data, vec![0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(asm.symbols.symbol_value("second"), Some(4));
+        assert_eq!(asm.symbols.symbol_value("start"), Some(64 + 4));
+        assert_eq!(asm.symbols.symbol_value("end"), Some(64 + 7));
+        // The merged data section follows the code
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 8 + 8);
+        assert_eq!(program[PIE_HEADER_LENGTH + 7], Opcode::HLT as u8);
+    }
+
+    #[test]
+    fn test_assemble_implicit_code_section() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            load $0 #100
+            loop: dec $0
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 6);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(64 + 4));
+    }
+
+    #[test]
+    fn test_assemble_missing_code_section() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .data
+            hello: .asciiz 'Hello'
+            ";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "No .code section was found, but one is required"
+        );
+    }
+
+    #[test]
+    fn test_assemble_instruction_in_data_section() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .code
+            hlt
+            .data
+            load $0 #100
+            ";
+        assert!(asm.assemble(test_string).is_err());
+    }
+
+    #[test]
+    fn test_assemble_undefined_label() {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".code\nload $0 @nowhere").unwrap_err();
+        assert!(errors[0].to_string().contains("@nowhere"));
+
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble(".extern print\n.code\nload $0 @print")
+            .unwrap_err();
+        assert!(errors[0].to_string().contains(".extern"));
+    }
+
+    #[test]
+    fn test_assemble_object() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .global main
+            .extern print
+            .rodata
+            greeting: .asciiz 'Hi'
+            .data
+            counter: .word #1
+            .code
+            main: load $0 @counter
+            load $1 @print
+            ";
+        let object = asm.assemble_object(test_string).unwrap();
+        assert_eq!(object.code.len(), 8);
+        assert_eq!(object.ro, b"Hi\0");
+        assert_eq!(object.imports, vec!["print".to_string()]);
+        assert_eq!(
+            object.symbol("main"),
+            Some(&ObjectSymbol {
+                name: "main".to_string(),
+                section: ObjectSection::Code,
+                offset: 0,
+                global: true,
+            })
+        );
+        // Data labels are relative to their own section
+        assert_eq!(object.symbol("counter").unwrap().offset, 0);
+        assert!(!object.symbol("counter").unwrap().global);
+        assert_eq!(
+            object.relocations,
+            vec![
+                Relocation {
+                    instruction: 0,
+                    offset: 2,
+                    symbol: "counter".to_string()
+                },
+                Relocation {
+                    instruction: 4,
+                    offset: 6,
+                    symbol: "print".to_string()
+                },
+            ]
+        );
+
+        let mut asm = Assembler::new();
+        let errors = asm
+            .assemble_object(".global nothing\n.code\nhlt")
+            .unwrap_err();
+        assert!(errors[0].to_string().contains("@nothing"));
+    }
+
+    #[test]
+    fn test_assemble_pc_relative_labels() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .code
+            load $0 #3
+            load $1 #0
+            loop: dec $0
+            eq $0 $1
+            jeq @done
+            jmp @loop
+            done: load $2 @loop
+            hlt
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        let header = PieHeader::parse(&program).unwrap();
+        assert!(header.is_position_independent());
+        // Offsets count from the start of the instruction using the label
+        assert_eq!(&program[78..81], &[Opcode::BEQ as u8, 0, 6]);
+        assert_eq!(&program[81..84], &[Opcode::BR as u8, 0xff, 0xf7]);
+        assert_eq!(&program[84..88], &[Opcode::LEA as u8, 2, 0xff, 0xf4]);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run();
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.registers[2], 72);
+    }
+
+    #[test]
+    fn test_assemble_label_out_of_range() {
+        let mut asm = Assembler::new();
+        // Each `load` is 4 bytes, so `far` ends up just over 32K bytes past the jump
+        let test_string = format!(".code\njmp @far\n{}far: hlt\n", "load $0 #0\n".repeat(8192));
+        let errors = asm.assemble(&test_string).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        match errors[0] {
+            AssemblerError::OffsetOutOfRange { ref name, offset } => {
+                assert_eq!(name, "far");
+                assert_eq!(offset, 3 + 4 * 8192);
+            }
+            ref error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_write_pie_image() {
+        let asm = Assembler::new();
+        let program = asm.write_pie_image(&[0, 0]);
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 2);
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.code_length, 2);
+        assert_eq!(header.entry_point, PIE_HEADER_LENGTH as u32);
+    }
+
+    #[test]
+    fn test_assemble_embeds_data_sections() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+            .rodata
+            greeting: .asciiz 'Hi'
+            .data
+            counter: .word #7
+            .bss
+            buffer: .space #4
+            .code
+            prts @greeting
+            hlt
+            ";
+        let program = asm.assemble(test_string).unwrap();
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.ro_data(&program), b"Hi\0");
+        assert_eq!(header.data(&program), &[0, 0, 0, 7]);
+        assert_eq!(header.bss_size, 4);
+        assert_eq!(program.len(), header.code_end() + 3 + 4);
+    }
+
+    #[test]
+    fn test_symbol_kinds_and_references() {
+        let mut asm = Assembler::new();
+        asm.assemble(
+            r"
+            .equ limit #3
+            .rodata
+            hello: .asciiz 'Hi'
+            unused: .asciiz 'Bye'
+            .code
+            load $1 @limit
+            loop: dec $1
+            prts @hello
+            jmp @loop
+            ",
+        )
+        .unwrap();
+        let symbols = &asm.symbols;
+        assert_eq!(
+            symbols.symbol("limit").unwrap().symbol_type(),
+            SymbolType::Constant
+        );
+        assert_eq!(symbols.symbol_value("limit"), Some(3));
+        assert_eq!(
+            symbols.symbol("hello").unwrap().symbol_type(),
+            SymbolType::DataLabel
+        );
+        assert_eq!(symbols.symbol("hello").unwrap().size(), Some(3));
+        let hello = symbols.symbol("hello").unwrap();
+        assert_eq!(
+            hello.location(),
+            Some(SourceLocation {
+                line: 4,
+                column: 13
+            })
+        );
+
+        let looped = symbols.symbol("loop").unwrap();
+        assert_eq!(looped.symbol_type(), SymbolType::CodeLabel);
+        // dec, prts and br
+        assert_eq!(looped.size(), Some(8));
+        assert_eq!(
+            symbols.references("loop"),
+            &[SourceLocation {
+                line: 10,
+                column: 13
+            }]
+        );
+        assert_eq!(symbols.references("limit").len(), 1);
+
+        let unused: Vec<&str> = asm.unused_labels().iter().map(|s| s.name()).collect();
+        assert_eq!(unused, vec!["unused"]);
+        let names: Vec<&str> = symbols.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["limit", "hello", "unused", "loop"]);
+        // The constant was put straight into the code
+        assert_eq!(
+            &asm.bytecode[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 4],
+            &[1, 1, 0, 3]
+        );
+    }
+
+    #[test]
+    fn test_assemble_twice() {
+        let mut asm = Assembler::new();
+        let source = ".data\ncounter: .word #1\n.code\nstart: load $0 @counter\njmp @start\n";
+        let first = asm.assemble(source).unwrap();
+        assert_eq!(asm.assemble(source).unwrap(), first);
+        assert_eq!(asm.symbols.len(), 2);
+        assert_eq!(asm.data, vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_assemble_more() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".code\nstart: load $0 #1\n").unwrap();
+        let address = program.len() as u32;
+
+        // Earlier symbols can be used, and new ones are placed at `address`
+        let code = asm
+            .assemble_more(".equ two #2\nmore: load $1 @two\njmp @start", address)
+            .unwrap();
+        assert_eq!(asm.symbols.symbol_value("more"), Some(address));
+        assert_eq!(&code[..4], &[1, 1, 0, 2]);
+        // br back to `start`, counting from the `br` itself
+        let offset = (64 - (address as i32 + 4)) as i16 as u16;
+        assert_eq!(&code[4..], &[23, (offset >> 8) as u8, offset as u8]);
+
+        // A failed call doesn't declare anything
+        assert!(asm.assemble_more("bad: load $0 @missing", address).is_err());
+        assert!(!asm.symbols.has_symbol("bad"));
+        match asm.assemble_more(".data\nx: .word #1", address) {
+            Err(errors) => assert!(matches!(errors[..], [AssemblerError::DataAfterLoading])),
+            Ok(_) => panic!("Data can't be added to an assembled program"),
+        }
+        assert!(!asm.symbols.has_symbol("x"));
+        assert!(asm.data.is_empty());
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let mut asm = Assembler::new();
+        asm.assemble(
+            r"
+            .rodata
+            hello: .asciiz 'Hi'
+            .data
+            counter: .word #1
+            .code
+            main: load $0 #1
+            loop: dec $0
+            hlt
+            ",
+        )
+        .unwrap();
+        let map = asm.symbol_map();
+        let names: Vec<(&str, u32, u32)> = map
+            .entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.offset, entry.size))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("main", 64, 4),
+                ("loop", 68, 3),
+                ("hello", 0, 3),
+                ("counter", 3, 4)
+            ]
+        );
+        assert_eq!(map.symbol("counter").unwrap().section, ObjectSection::Data);
+    }
+
+    #[test]
+    fn test_assemble_with_debug_info() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble_with_debug_info(".code\nload $0 #1\nloop:   dec $0\nhlt\n", "foo.iasm")
+            .unwrap();
+        let header = PieHeader::parse(&program).unwrap();
+        let debug_info = DebugInfo::from_bytes(header.debug_info(&program).unwrap()).unwrap();
+        assert_eq!(debug_info.file, "foo.iasm");
+        assert_eq!(
+            debug_info.lines[1],
+            LineEntry {
+                offset: 68,
+                line: 3,
+                column: 1
+            }
+        );
+        assert_eq!(
+            debug_info.describe(71),
+            Some("foo.iasm:4:1 in `loop`".to_string())
+        );
     }
 }
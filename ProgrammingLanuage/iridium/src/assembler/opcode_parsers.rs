@@ -18,6 +18,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_opcode_load() {
         let result = opcode_load(CompleteStr("load"));
         assert_eq!(result.is_ok(), true);
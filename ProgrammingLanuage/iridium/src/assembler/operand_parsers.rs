@@ -20,6 +20,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_operand() {
         let result = operand(CompleteStr("#10"));
         assert_eq!(result.is_ok(), true);
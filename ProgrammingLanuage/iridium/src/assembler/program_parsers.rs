@@ -1,39 +1,86 @@
 use nom::types::CompleteStr;
+use nom::{Context, Err, ErrorKind, IResult};
 
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
 use crate::assembler::SymbolTable;
 
+/// Where an instruction starts in the source code. Both values are 1-based.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SourceLocation {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl SourceLocation {
+    /// Finds the line and column of a byte offset into `source`
+    pub fn from_offset(source: &str, offset: usize) -> SourceLocation {
+        let before = &source[..offset];
+        let line = before.matches('\n').count() as u32 + 1;
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        SourceLocation {
+            line,
+            column: before[line_start..].chars().count() as u32 + 1,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    /// The location of each instruction, in the same order as `instructions`
+    pub locations: Vec<SourceLocation>,
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    /// Assembles every instruction, placing the first one at `address`
+    pub fn to_bytes(&self, symbols: &SymbolTable, address: u32) -> Vec<u8> {
         let mut program: Vec<u8> = vec![];
         for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+            let mut bytes = instruction.to_bytes(symbols, address + program.len() as u32);
+            program.append(&mut bytes);
         }
         program
     }
 }
 
-named!(pub program<CompleteStr, Program>,
-    do_parse!(
-        instructions: many1!(instruction) >>
-        (
-            Program {
-                instructions: instructions
+/// Parses one or more instructions, remembering where in the input each of them started
+pub fn program(input: CompleteStr) -> IResult<CompleteStr, Program> {
+    let mut instructions = vec![];
+    let mut locations = vec![];
+    let mut remaining = input;
+    loop {
+        // Instructions may be preceded by whitespace, which isn't part of their location
+        let start = input.len() - remaining.trim_start().len();
+        match instruction(remaining) {
+            // Stop if nothing was consumed, otherwise we would loop forever
+            Ok((rest, _)) if rest.len() == remaining.len() => break,
+            Ok((rest, i)) => {
+                instructions.push(i);
+                locations.push(SourceLocation::from_offset(&input, start));
+                remaining = rest;
             }
-        )
-    )
-);
+            Err(Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if instructions.is_empty() {
+        return Err(Err::Error(Context::Code(input, ErrorKind::Many1)));
+    }
+    Ok((
+        remaining,
+        Program {
+            instructions,
+            locations,
+        },
+    ))
+}
 
 mod tests {
     #[allow(unused_imports)]
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_program() {
         let result = program(CompleteStr("test: inc $0\n neq $0 $2\n jmpe @test\n hlt"));
         assert_eq!(result.is_ok(), true);
@@ -43,19 +90,40 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100\n"));
         assert_eq!(result.is_ok(), true);
         let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes(&SymbolTable::new());
+        let bytecode = program.to_bytes(&SymbolTable::new(), 0);
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_complete_program() {
         let test_program = CompleteStr(".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt");
         let result = program(test_program);
         assert_eq!(result.is_ok(), true);
     }
+
+    #[test]
+    fn test_program_locations() {
+        let result = program(CompleteStr("\n.code\n  load $0 #100\n\n    hlt\n"));
+        let (_, p) = result.unwrap();
+        assert_eq!(
+            p.locations,
+            vec![
+                SourceLocation { line: 2, column: 1 },
+                SourceLocation { line: 3, column: 3 },
+                SourceLocation { line: 5, column: 5 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_program() {
+        assert!(program(CompleteStr("   \n")).is_err());
+    }
 }
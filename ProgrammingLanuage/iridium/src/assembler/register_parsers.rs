@@ -1,18 +1,103 @@
 use crate::assembler::Token;
 
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{alphanumeric, digit};
+
+/// Number of general purpose registers the VM has
+pub const REGISTER_COUNT: usize = 32;
+
+/// ABI names that can be used in place of a register number, e.g. `$sp` instead of `$30`
+const ABI_REGISTER_NAMES: [(&str, u8); 32] = [
+    ("a0", 0),
+    ("a1", 1),
+    ("a2", 2),
+    ("a3", 3),
+    ("t0", 4),
+    ("t1", 5),
+    ("t2", 6),
+    ("t3", 7),
+    ("t4", 8),
+    ("t5", 9),
+    ("t6", 10),
+    ("t7", 11),
+    ("t8", 12),
+    ("t9", 13),
+    ("t10", 14),
+    ("t11", 15),
+    ("s0", 16),
+    ("s1", 17),
+    ("s2", 18),
+    ("s3", 19),
+    ("s4", 20),
+    ("s5", 21),
+    ("s6", 22),
+    ("s7", 23),
+    ("s8", 24),
+    ("s9", 25),
+    ("s10", 26),
+    ("s11", 27),
+    ("gp", 28),
+    ("fp", 29),
+    ("sp", 30),
+    ("ra", 31),
+];
+
+/// Looks up the register number for an ABI register name such as `sp`
+pub fn abi_register(name: &str) -> Option<u8> {
+    let lowercased_name = name.to_lowercase();
+    ABI_REGISTER_NAMES
+        .iter()
+        .find(|(abi_name, _)| *abi_name == lowercased_name)
+        .map(|(_, reg_num)| *reg_num)
+}
+
+/// Looks up the ABI name of a register number, e.g. `30` is `sp`
+pub fn abi_register_name(reg_num: u8) -> Option<&'static str> {
+    ABI_REGISTER_NAMES
+        .iter()
+        .find(|(_, abi_reg_num)| *abi_reg_num == reg_num)
+        .map(|(name, _)| *name)
+}
+
+fn is_register_number(reg_num: u8) -> bool {
+    (reg_num as usize) < REGISTER_COUNT
+}
+
+fn starts_with_letter(name: CompleteStr) -> bool {
+    name.chars().next().is_some_and(char::is_alphabetic)
+}
+
+// Looks for a numbered register, such as `$0`. Only `$0` to `$31` are accepted.
+named!(register_number<CompleteStr, Token>,
+    do_parse!(
+        tag!("$") >>
+        reg_num: verify!(map_res!(digit, |d: CompleteStr| d.parse::<u8>()), is_register_number) >>
+        (
+            Token::Register{ reg_num }
+        )
+    )
+);
+
+// Looks for a named register, such as `$sp`. Anything that isn't an ABI name is an alias
+// which has to be declared with `.reg` in the same file.
+named!(register_name<CompleteStr, Token>,
+    do_parse!(
+        tag!("$") >>
+        name: verify!(alphanumeric, starts_with_letter) >>
+        (
+            match abi_register(&name) {
+                Some(reg_num) => Token::Register{ reg_num },
+                None => Token::RegisterAlias{ name: name.to_string() },
+            }
+        )
+    )
+);
 
 named!(pub register<CompleteStr, Token>,
     ws!(
-        do_parse!(
-            tag!("$") >>
-            reg_num: digit >>
-            (
-                Token::Register{
-                    reg_num: reg_num.parse::<u8>().unwrap()
-                }
-            )
+        alt!(
+            register_number |
+            register_name
         )
     )
 );
@@ -22,12 +107,50 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_register() {
         let result = register(CompleteStr("$0"));
         assert_eq!(result.is_ok(), true);
         let result = register(CompleteStr("0"));
         assert_eq!(result.is_ok(), false);
-        let result = register(CompleteStr("$a"));
-        assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$31"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { reg_num: 31 })));
+    }
+
+    #[test]
+    fn test_parse_register_out_of_range() {
+        let result = register(CompleteStr("$32"));
+        assert!(result.is_err());
+        let result = register(CompleteStr("$200"));
+        assert!(result.is_err());
+        let result = register(CompleteStr("$1000"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_abi_register() {
+        let result = register(CompleteStr("$sp"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { reg_num: 30 })));
+        let result = register(CompleteStr("$RA"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { reg_num: 31 })));
+        let result = register(CompleteStr("$a3"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { reg_num: 3 })));
+        let result = register(CompleteStr("$t0"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Register { reg_num: 4 })));
+        assert_eq!(abi_register_name(30), Some("sp"));
+    }
+
+    #[test]
+    fn test_parse_register_alias() {
+        let result = register(CompleteStr("$counter"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::RegisterAlias {
+                    name: "counter".to_string()
+                }
+            ))
+        );
     }
 }
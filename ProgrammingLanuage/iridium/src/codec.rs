@@ -0,0 +1,160 @@
+use std::marker::PhantomData;
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// The input ends at `offset`, in the middle of a field. Each format that is read with a
+/// `Reader` turns this into its own error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Truncated {
+    pub offset: usize,
+}
+
+pub fn write_u16(out: &mut Vec<u8>, value: u16) {
+    let mut buf = [0; 2];
+    BigEndian::write_u16(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+pub fn write_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0; 4];
+    BigEndian::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+pub fn write_u64(out: &mut Vec<u8>, value: u64) {
+    let mut buf = [0; 8];
+    BigEndian::write_u64(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+/// Writes `bytes` prefixed with their length
+pub fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Reads the big-endian fields of a binary format one after the other, failing with the
+/// format's error `E`
+pub struct Reader<'a, E> {
+    bytes: &'a [u8],
+    position: usize,
+    error: PhantomData<fn() -> E>,
+}
+
+impl<'a, E: From<Truncated>> Reader<'a, E> {
+    /// Reads `bytes` from `position` on, e.g. right after a prefix that was already checked
+    pub fn new(bytes: &'a [u8], position: usize) -> Reader<'a, E> {
+        Reader {
+            bytes,
+            position,
+            error: PhantomData,
+        }
+    }
+
+    /// Offset of the next field
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], E> {
+        if self.bytes.len() - self.position < len {
+            return Err(E::from(Truncated {
+                offset: self.bytes.len(),
+            }));
+        }
+        let taken = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, E> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, E> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, E> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, E> {
+        Ok(BigEndian::read_u64(self.take(8)?))
+    }
+
+    /// Reads bytes written by `write_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], E> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a string written by `write_bytes`, failing with `invalid` called with its offset
+    /// if it isn't UTF-8
+    pub fn read_string(&mut self, invalid: impl FnOnce(usize) -> E) -> Result<String, E> {
+        let offset = self.position;
+        match String::from_utf8(self.read_bytes()?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(invalid(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum TestError {
+        Truncated { offset: usize },
+        InvalidString { offset: usize },
+    }
+
+    impl From<Truncated> for TestError {
+        fn from(error: Truncated) -> TestError {
+            TestError::Truncated {
+                offset: error.offset,
+            }
+        }
+    }
+
+    #[test]
+    fn test_reads_what_was_written() {
+        let mut out = vec![7];
+        write_u16(&mut out, 0x0102);
+        write_u32(&mut out, 0x0304_0506);
+        write_u64(&mut out, u64::MAX - 1);
+        write_bytes(&mut out, b"iridium");
+        assert_eq!(out[1..7], [1, 2, 3, 4, 5, 6]);
+
+        let mut reader: Reader<TestError> = Reader::new(&out, 1);
+        assert_eq!(reader.read_u16(), Ok(0x0102));
+        assert_eq!(reader.read_u32(), Ok(0x0304_0506));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.position(), 15);
+        let invalid = |offset| TestError::InvalidString { offset };
+        assert_eq!(reader.read_string(invalid), Ok("iridium".to_string()));
+        assert_eq!(reader.read_u8(), Err(TestError::Truncated { offset: 26 }));
+    }
+
+    #[test]
+    fn test_truncated_field_reports_where_the_input_ends() {
+        let mut out = vec![];
+        write_bytes(&mut out, b"iridium");
+        let mut reader: Reader<TestError> = Reader::new(&out[..8], 0);
+        assert_eq!(reader.read_bytes(), Err(TestError::Truncated { offset: 8 }));
+        let mut reader: Reader<TestError> = Reader::new(&out[..3], 0);
+        assert_eq!(reader.read_u32(), Err(TestError::Truncated { offset: 3 }));
+    }
+
+    #[test]
+    fn test_string_must_be_utf8() {
+        let mut out = vec![0];
+        write_bytes(&mut out, &[0xff, 0xfe]);
+        let mut reader: Reader<TestError> = Reader::new(&out, 1);
+        assert_eq!(
+            reader.read_string(|offset| TestError::InvalidString { offset }),
+            Err(TestError::InvalidString { offset: 1 })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::codec::{write_bytes, write_u16, write_u32, Reader, Truncated};
+
+/// The version of the debug info layout written by this assembler
+pub const DEBUG_INFO_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugInfoError {
+    // Debug info was written for a different layout
+    UnsupportedVersion { version: u16 },
+    // Debug info ends in the middle of a field
+    Truncated { offset: usize },
+    // File or label name is not valid UTF-8
+    InvalidName { offset: usize },
+}
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DebugInfoError::UnsupportedVersion { version } => write!(
+                f,
+                "Debug info version {} is not supported, expected version {}",
+                version, DEBUG_INFO_VERSION
+            ),
+            DebugInfoError::Truncated { offset } => {
+                write!(f, "Debug info ends unexpectedly at offset {}", offset)
+            }
+            DebugInfoError::InvalidName { offset } => {
+                write!(f, "Debug info has an invalid name at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for DebugInfoError {}
+
+impl From<Truncated> for DebugInfoError {
+    fn from(error: Truncated) -> DebugInfoError {
+        DebugInfoError::Truncated {
+            offset: error.offset,
+        }
+    }
+}
+
+/// Where the instruction at `offset` came from in the source. Line and column are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineEntry {
+    pub offset: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A label declared in the code
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugLabel {
+    pub name: String,
+    pub offset: u32,
+}
+
+/// Maps offsets into a program back to the source it was assembled from. Offsets are counted
+/// from the start of the program, header included, so they can be compared to the VM's `pc`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    /// Name of the source file
+    pub file: String,
+    /// One entry per instruction, ordered by offset
+    pub lines: Vec<LineEntry>,
+    /// Code labels, ordered by offset
+    pub labels: Vec<DebugLabel>,
+}
+
+impl DebugInfo {
+    pub fn new(file: &str) -> DebugInfo {
+        DebugInfo {
+            file: file.to_string(),
+            ..DebugInfo::default()
+        }
+    }
+
+    /// The source location of the instruction containing `offset`
+    pub fn location(&self, offset: usize) -> Option<&LineEntry> {
+        self.lines
+            .iter()
+            .take_while(|entry| entry.offset as usize <= offset)
+            .last()
+    }
+
+    /// The name of the closest label at or before `offset`
+    pub fn label(&self, offset: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .take_while(|label| label.offset as usize <= offset)
+            .last()
+            .map(|label| label.name.as_str())
+    }
+
+    /// The offset of the first instruction assembled from `line`, for setting breakpoints
+    pub fn line_offset(&self, line: u32) -> Option<u32> {
+        self.lines
+            .iter()
+            .find(|entry| entry.line == line)
+            .map(|entry| entry.offset)
+    }
+
+    /// Describes `offset` the way a person would look it up, e.g. "foo.iasm:12:5 in `loop`"
+    pub fn describe(&self, offset: usize) -> Option<String> {
+        let entry = self.location(offset)?;
+        let mut description = format!("{}:{}:{}", self.file, entry.line, entry.column);
+        if let Some(label) = self.label(offset) {
+            description.push_str(&format!(" in `{}`", label));
+        }
+        Some(description)
+    }
+
+    /// Serializes the debug info. All integers are big-endian, and names are prefixed with
+    /// their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u16(&mut out, DEBUG_INFO_VERSION);
+        write_bytes(&mut out, self.file.as_bytes());
+        write_u32(&mut out, self.lines.len() as u32);
+        for entry in &self.lines {
+            write_u32(&mut out, entry.offset);
+            write_u32(&mut out, entry.line);
+            write_u32(&mut out, entry.column);
+        }
+        write_u32(&mut out, self.labels.len() as u32);
+        for label in &self.labels {
+            write_bytes(&mut out, label.name.as_bytes());
+            write_u32(&mut out, label.offset);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DebugInfo, DebugInfoError> {
+        let mut reader = Reader::new(bytes, 0);
+        let version = reader.read_u16()?;
+        if version != DEBUG_INFO_VERSION {
+            return Err(DebugInfoError::UnsupportedVersion { version });
+        }
+        let mut debug_info = DebugInfo::new(&reader.read_string(invalid_name)?);
+        for _ in 0..reader.read_u32()? {
+            debug_info.lines.push(LineEntry {
+                offset: reader.read_u32()?,
+                line: reader.read_u32()?,
+                column: reader.read_u32()?,
+            });
+        }
+        for _ in 0..reader.read_u32()? {
+            let name = reader.read_string(invalid_name)?;
+            let offset = reader.read_u32()?;
+            debug_info.labels.push(DebugLabel { name, offset });
+        }
+        Ok(debug_info)
+    }
+}
+
+fn invalid_name(offset: usize) -> DebugInfoError {
+    DebugInfoError::InvalidName { offset }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_info() -> DebugInfo {
+        DebugInfo {
+            file: "foo.iasm".to_string(),
+            lines: vec![
+                LineEntry {
+                    offset: 64,
+                    line: 2,
+                    column: 1,
+                },
+                LineEntry {
+                    offset: 68,
+                    line: 3,
+                    column: 5,
+                },
+            ],
+            labels: vec![DebugLabel {
+                name: "loop".to_string(),
+                offset: 68,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_debug_info_keeps_lines_and_labels() {
+        let debug_info = debug_info();
+        let bytes = debug_info.to_bytes();
+        assert_eq!(DebugInfo::from_bytes(&bytes), Ok(debug_info));
+    }
+
+    #[test]
+    fn test_truncated_debug_info_reports_where_it_ends() {
+        let bytes = debug_info().to_bytes();
+        assert_eq!(
+            DebugInfo::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DebugInfoError::Truncated {
+                offset: bytes.len() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let debug_info = debug_info();
+        assert_eq!(debug_info.describe(64), Some("foo.iasm:2:1".to_string()));
+        // Faults are reported with `pc` somewhere inside the instruction
+        assert_eq!(
+            debug_info.describe(70),
+            Some("foo.iasm:3:5 in `loop`".to_string())
+        );
+        assert_eq!(debug_info.describe(10), None);
+        assert_eq!(debug_info.line_offset(3), Some(68));
+    }
+}
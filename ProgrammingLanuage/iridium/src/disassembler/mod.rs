@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+use crate::instruction::{Opcode, OperandKind};
+use crate::pie::{HeaderError, PieHeader, PIE_HEADER_LENGTH};
+use crate::symbol_map::{SymbolKind, SymbolMap};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisassemblerError {
+    // Program header is missing or wrong
+    InvalidHeader { error: HeaderError },
+    // Program ends in the middle of an instruction
+    TruncatedInstruction { offset: usize },
+}
+
+impl fmt::Display for DisassemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisassemblerError::InvalidHeader { ref error } => {
+                f.write_str(&format!("Program header is invalid: {}", error))
+            }
+            DisassemblerError::TruncatedInstruction { offset } => f.write_str(&format!(
+                "Program ends in the middle of the instruction at offset {}",
+                offset
+            )),
+        }
+    }
+}
+
+impl Error for DisassemblerError {}
+
+/// An operand as it was read from bytecode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    Integer(u16),
+    Offset(i16),
+    Padding(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    /// Offset of the opcode from the start of the program, header included
+    pub offset: usize,
+    pub opcode: Opcode,
+    /// The opcode byte as it appears in the program, which is useful for illegal opcodes
+    pub raw_opcode: u8,
+    pub operands: Vec<Operand>,
+}
+
+impl DisassembledInstruction {
+    /// The number of bytes the instruction takes up
+    pub fn byte_len(&self) -> usize {
+        self.opcode.encoded_len()
+    }
+
+    fn register(&self, index: usize) -> Option<u8> {
+        match self.operands.get(index) {
+            Some(Operand::Register(reg_num)) => Some(*reg_num),
+            _ => None,
+        }
+    }
+
+    fn integer(&self, index: usize) -> Option<u16> {
+        match self.operands.get(index) {
+            Some(Operand::Integer(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The address a pc-relative operand refers to
+    fn relative_target(&self, index: usize) -> Option<usize> {
+        match self.operands.get(index) {
+            Some(Operand::Offset(offset)) => {
+                let target = self.offset as isize + *offset as isize;
+                if target >= 0 {
+                    Some(target as usize)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A jump whose destination could be worked out statically
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JumpTarget {
+    /// Offset of the jump instruction
+    pub instruction: usize,
+    /// Where it jumps to
+    pub target: usize,
+    /// Offset of the instruction whose operand holds the target, if the target is an address
+    /// rather than a distance
+    pub source: Option<usize>,
+}
+
+/// A decoded program along with what could be worked out about it statically
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Disassembly {
+    pub instructions: Vec<DisassembledInstruction>,
+    /// Names for every code address that is jumped to
+    pub labels: BTreeMap<usize, String>,
+    /// Instructions (by offset) whose integer or pc-relative operand refers to a label
+    pub label_loads: HashMap<usize, usize>,
+    /// Strings printed by `prts` instructions, by instruction offset
+    pub strings: HashMap<usize, String>,
+}
+
+/// Decodes the instruction at `offset` of `program`. Fails rather than reading past the end
+/// of `program`, including when `offset` itself is out of range.
+pub fn decode_instruction(
+    program: &[u8],
+    offset: usize,
+) -> Result<DisassembledInstruction, DisassemblerError> {
+    let truncated = DisassemblerError::TruncatedInstruction { offset };
+    let raw_opcode = match program.get(offset) {
+        Some(raw_opcode) => *raw_opcode,
+        None => return Err(truncated),
+    };
+    let opcode = Opcode::from(raw_opcode);
+    let bytes = match program.get(offset..offset + opcode.encoded_len()) {
+        Some(bytes) => bytes,
+        None => return Err(truncated),
+    };
+
+    let mut operands = vec![];
+    let mut position = 1;
+    for kind in opcode.operands() {
+        let word = || ((bytes[position] as u16) << 8) | bytes[position + 1] as u16;
+        operands.push(match kind {
+            OperandKind::Register => Operand::Register(bytes[position]),
+            OperandKind::Integer => Operand::Integer(word()),
+            OperandKind::Offset => Operand::Offset(word() as i16),
+            OperandKind::Padding => Operand::Padding(bytes[position]),
+        });
+        position += kind.byte_len();
+    }
+    Ok(DisassembledInstruction {
+        offset,
+        opcode,
+        raw_opcode,
+        operands,
+    })
+}
+
+/// Disassembles a program that starts with a PIE header. The data sections stored after the
+/// code are used to show the strings printed by `prts`.
+pub fn disassemble(program: &[u8]) -> Result<Disassembly, DisassemblerError> {
+    let header = match PieHeader::parse(program) {
+        Ok(header) => header,
+        Err(error) => return Err(DisassemblerError::InvalidHeader { error }),
+    };
+    // Only the code is decoded, anything after it is data
+    let code = &program[..header.code_end()];
+
+    let mut disassembly = Disassembly::default();
+    let mut offset = PIE_HEADER_LENGTH;
+    while offset < code.len() {
+        let instruction = decode_instruction(code, offset)?;
+        offset += instruction.byte_len();
+        disassembly.instructions.push(instruction);
+    }
+    disassembly.find_jump_targets(code.len());
+    disassembly.find_strings(header.ro_data(program), header.data(program));
+    Ok(disassembly)
+}
+
+impl Disassembly {
+    pub fn is_instruction_start(&self, offset: usize) -> bool {
+        self.instructions
+            .binary_search_by_key(&offset, |i| i.offset)
+            .is_ok()
+    }
+
+    /// Jump targets live in registers, so follow `load`s of constants through straight-line
+    /// code to find out where jumps go. Targets are returned as found, even if they are not
+    /// the start of an instruction.
+    pub fn jump_targets(&self) -> Vec<JumpTarget> {
+        // For every register, the constant it holds and the offset of the `load` that put it there
+        let mut known: [Option<(u16, usize)>; 32] = [None; 32];
+        let mut targets = vec![];
+        for instruction in &self.instructions {
+            let next = instruction.offset + instruction.byte_len();
+            let known_value = |index: usize| {
+                instruction
+                    .register(index)
+                    .and_then(|reg_num| known.get(reg_num as usize).copied().flatten())
+            };
+            let mut jump = |target: usize, source: Option<usize>| {
+                targets.push(JumpTarget {
+                    instruction: instruction.offset,
+                    target,
+                    source,
+                })
+            };
+            match instruction.opcode {
+                Opcode::JMP | Opcode::JEQ => {
+                    if let Some((value, load)) = known_value(0) {
+                        jump(value as usize, Some(load));
+                    }
+                }
+                Opcode::JMPF => {
+                    if let Some((value, _)) = known_value(0) {
+                        jump(next + value as usize, None);
+                    }
+                }
+                Opcode::JMPB => {
+                    if let Some((value, _)) = known_value(0) {
+                        if let Some(target) = next.checked_sub(value as usize) {
+                            jump(target, None);
+                        }
+                    }
+                }
+                Opcode::BR | Opcode::BEQ => {
+                    if let Some(target) = instruction.relative_target(0) {
+                        jump(target, Some(instruction.offset));
+                    }
+                }
+                _ => {}
+            }
+
+            match instruction.opcode {
+                Opcode::LOAD => {
+                    if let (Some(reg_num), Some(value)) =
+                        (instruction.register(0), instruction.integer(1))
+                    {
+                        if let Some(slot) = known.get_mut(reg_num as usize) {
+                            *slot = Some((value, instruction.offset));
+                        }
+                    }
+                }
+                Opcode::LEA => {
+                    if let (Some(reg_num), Some(target)) =
+                        (instruction.register(0), instruction.relative_target(1))
+                    {
+                        if let Some(slot) = known.get_mut(reg_num as usize) {
+                            *slot = Some((target as u16, instruction.offset));
+                        }
+                    }
+                }
+                Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                    forget(&mut known, instruction.register(2));
+                }
+                Opcode::INC
+                | Opcode::DEC
+                | Opcode::LW
+                | Opcode::RECV
+                | Opcode::SELF
+                | Opcode::SPAWN => {
+                    forget(&mut known, instruction.register(0));
+                }
+                // Code after an unconditional jump or halt can be reached from anywhere
+                Opcode::JMP
+                | Opcode::JMPF
+                | Opcode::JMPB
+                | Opcode::BR
+                | Opcode::HLT
+                | Opcode::EXIT
+                | Opcode::IGL => {
+                    known = [None; 32];
+                }
+                _ => {}
+            }
+        }
+        targets
+    }
+
+    fn find_jump_targets(&mut self, program_len: usize) {
+        for jump in self.jump_targets() {
+            let target = jump.target;
+            if target >= PIE_HEADER_LENGTH
+                && target < program_len
+                && self.is_instruction_start(target)
+            {
+                self.labels.insert(target, format!("label_{:04x}", target));
+                if let Some(source) = jump.source {
+                    self.label_loads.insert(source, target);
+                }
+            }
+        }
+    }
+
+    fn find_strings(&mut self, ro_data: &[u8], data: &[u8]) {
+        for instruction in &self.instructions {
+            if instruction.opcode != Opcode::PRTS {
+                continue;
+            }
+            let address = match instruction.integer(0) {
+                Some(address) => address as usize,
+                None => continue,
+            };
+            // `prts` reads from the data address space, read-only data first
+            let memory = ro_data.iter().chain(data.iter());
+            let bytes: Vec<u8> = memory
+                .skip(address)
+                .take_while(|byte| **byte != 0)
+                .cloned()
+                .collect();
+            if address < ro_data.len() + data.len() {
+                self.strings.insert(
+                    instruction.offset,
+                    String::from_utf8_lossy(&bytes).to_string(),
+                );
+            }
+        }
+    }
+
+    /// Names labels after the code symbols in `map`. Symbols are shown even where nothing
+    /// jumps to them.
+    pub fn apply_symbol_map(&mut self, map: &SymbolMap) {
+        for entry in &map.entries {
+            let offset = entry.offset as usize;
+            if entry.kind == SymbolKind::Code && self.is_instruction_start(offset) {
+                self.labels.insert(offset, entry.name.clone());
+            }
+        }
+    }
+
+    /// Renders a single instruction the way it would be written in assembly
+    pub fn instruction_text(&self, instruction: &DisassembledInstruction) -> String {
+        let mut text = instruction.opcode.mnemonic().to_string();
+        for operand in &instruction.operands {
+            match operand {
+                Operand::Register(reg_num) => text.push_str(&format!(" ${}", reg_num)),
+                Operand::Integer(value) => match self.label_loads.get(&instruction.offset) {
+                    Some(target) => text.push_str(&format!(" @{}", self.labels[target])),
+                    None => text.push_str(&format!(" #{}", value)),
+                },
+                Operand::Offset(offset) => match self.label_loads.get(&instruction.offset) {
+                    Some(target) => text.push_str(&format!(" @{}", self.labels[target])),
+                    None => text.push_str(&format!(" #{}", offset)),
+                },
+                Operand::Padding(_) => {}
+            }
+        }
+        text
+    }
+}
+
+fn forget(known: &mut [Option<(u16, usize)>; 32], register: Option<u8>) {
+    if let Some(slot) = register.and_then(|reg_num| known.get_mut(reg_num as usize)) {
+        *slot = None;
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in &self.instructions {
+            if let Some(label) = self.labels.get(&instruction.offset) {
+                writeln!(f, "{}:", label)?;
+            }
+            let mut bytes = vec![format!("{:02x}", instruction.raw_opcode)];
+            for operand in &instruction.operands {
+                match operand {
+                    Operand::Register(byte) | Operand::Padding(byte) => {
+                        bytes.push(format!("{:02x}", byte))
+                    }
+                    Operand::Integer(value) => {
+                        bytes.push(format!("{:02x}", value >> 8));
+                        bytes.push(format!("{:02x}", value & 0xff));
+                    }
+                    Operand::Offset(offset) => {
+                        bytes.push(format!("{:02x}", (*offset as u16) >> 8));
+                        bytes.push(format!("{:02x}", (*offset as u16) & 0xff));
+                    }
+                }
+            }
+            write!(
+                f,
+                "    {:04x}  {:<11}  {}",
+                instruction.offset,
+                bytes.join(" "),
+                self.instruction_text(instruction)
+            )?;
+            if instruction.opcode == Opcode::IGL {
+                write!(f, "    ; illegal opcode {:#04x}", instruction.raw_opcode)?;
+            }
+            if let Some(s) = self.strings.get(&instruction.offset) {
+                write!(f, "    ; {:?}", s)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::prepend_header;
+
+    #[test]
+    fn test_decode_instruction() {
+        let program = vec![1, 2, 1, 4, 9, 0, 1, 0];
+        let instruction = decode_instruction(&program, 0).unwrap();
+        assert_eq!(instruction.opcode, Opcode::LOAD);
+        assert_eq!(
+            instruction.operands,
+            vec![Operand::Register(2), Operand::Integer(260)]
+        );
+        let instruction = decode_instruction(&program, 4).unwrap();
+        assert_eq!(instruction.opcode, Opcode::EQ);
+        assert_eq!(instruction.byte_len(), 4);
+        assert_eq!(
+            decode_instruction(&program[..7], 4),
+            Err(DisassemblerError::TruncatedInstruction { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn test_decode_instruction_past_end() {
+        let program = vec![1, 2, 1, 4];
+        assert_eq!(
+            decode_instruction(&program, 4),
+            Err(DisassemblerError::TruncatedInstruction { offset: 4 })
+        );
+        assert_eq!(
+            decode_instruction(&program, 100),
+            Err(DisassemblerError::TruncatedInstruction { offset: 100 })
+        );
+    }
+
+    #[test]
+    fn test_disassemble_requires_header() {
+        assert_eq!(
+            disassemble(&[0, 0, 0]),
+            Err(DisassemblerError::InvalidHeader {
+                error: HeaderError::TooShort { length: 3 }
+            })
+        );
+        assert_eq!(
+            disassemble(&[0; 64]),
+            Err(DisassemblerError::InvalidHeader {
+                error: HeaderError::InvalidPrefix
+            })
+        );
+        let program = prepend_header(vec![1, 0]);
+        assert_eq!(
+            disassemble(&program),
+            Err(DisassemblerError::TruncatedInstruction { offset: 64 })
+        );
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let mut asm = Assembler::new();
+        let source = r"
+            .rodata
+            hello: .asciiz 'Hello'
+            .code
+            load $0 #3
+            loop: dec $0
+            prts @hello
+            load $1 @loop
+            neq $0 $2
+            jeq $1
+            hlt
+            ";
+        let program = asm.assemble(source).unwrap();
+        let disassembly = disassemble(&program).unwrap();
+        assert_eq!(disassembly.instructions.len(), 7);
+        assert_eq!(disassembly.labels.get(&68), Some(&"label_0044".to_string()));
+        assert_eq!(disassembly.strings.get(&70), Some(&"Hello".to_string()));
+
+        let text = disassembly.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "    0040  01 00 00 03  load $0 #3");
+        assert_eq!(lines[1], "label_0044:");
+        assert_eq!(lines[2], "    0044  12 00        dec $0");
+        assert_eq!(lines[3], "    0046  13 00 00     prts #0    ; \"Hello\"");
+        assert_eq!(lines[4], "    0049  16 01 ff fb  lea $1 @label_0044");
+        assert_eq!(lines[5], "    004d  0a 00 02 00  neq $0 $2");
+        assert_eq!(lines[6], "    0051  0f 01        jeq $1");
+        assert_eq!(lines[7], "    0053  00           hlt");
+    }
+
+    #[test]
+    fn test_disassemble_with_symbol_map() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".code\nmain: load $0 #3\nloop: dec $0\njmp @loop\n")
+            .unwrap();
+        let mut disassembly = disassemble(&program).unwrap();
+        disassembly.apply_symbol_map(&asm.symbol_map());
+
+        let text = disassembly.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "main:");
+        assert_eq!(lines[2], "loop:");
+        assert_eq!(lines[4], "    0046  17 ff fe     br @loop");
+    }
+
+    #[test]
+    fn test_disassemble_illegal_opcode() {
+        let program = prepend_header(vec![200, 0]);
+        let text = disassemble(&program).unwrap().to_string();
+        assert!(text.starts_with("    0040  c8           igl    ; illegal opcode 0xc8"));
+    }
+}
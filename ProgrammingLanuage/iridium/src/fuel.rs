@@ -0,0 +1,67 @@
+use crate::instruction::Opcode;
+
+/// Fuel charged for each opcode when the VM runs with a budget. Instructions that do more than
+/// move values between registers cost more, so that programs are metered by the work they
+/// make the VM do rather than by how many instructions they execute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    /// Cost of each opcode, indexed by its encoding. `IGL` comes last.
+    costs: Vec<u64>,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let mut table = CostTable::uniform(1);
+        for opcode in &[Opcode::MUL, Opcode::DIV, Opcode::LW, Opcode::SW] {
+            table.set_cost(*opcode, 2);
+        }
+        // These touch an amount of memory only known at run time
+        table.set_cost(Opcode::PRTS, 10);
+        table.set_cost(Opcode::ALOC, 10);
+        table.set_cost(Opcode::SPAWN, 10);
+        table
+    }
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable::default()
+    }
+
+    /// A table charging `cost` for every opcode, which turns fuel into an instruction count
+    pub fn uniform(cost: u64) -> CostTable {
+        CostTable {
+            costs: vec![cost; Opcode::IGL as usize + 1],
+        }
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_costs() {
+        let table = CostTable::new();
+        assert_eq!(table.cost(Opcode::LOAD), 1);
+        assert_eq!(table.cost(Opcode::DIV), 2);
+        assert_eq!(table.cost(Opcode::PRTS), 10);
+        assert_eq!(table.cost(Opcode::IGL), 1);
+    }
+
+    #[test]
+    fn test_set_cost() {
+        let mut table = CostTable::uniform(3);
+        table.set_cost(Opcode::JMP, 5);
+        assert_eq!(table.cost(Opcode::JMP), 5);
+        assert_eq!(table.cost(Opcode::EXIT), 3);
+    }
+}
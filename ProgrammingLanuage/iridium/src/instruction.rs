@@ -22,6 +22,17 @@ pub enum Opcode {
     INC,
     DEC,
     PRTS,
+    LW,    // Load word from data memory
+    SW,    // Store word to data memory
+    LEA,   // Load the address of an instruction, relative to this one
+    BR,    // Branch relative to this instruction
+    BEQ,   // Branch relative to this instruction if equal
+    EXIT,  // Stop with the status held in a register
+    YIELD, // Let the scheduler run another process
+    SEND,  // Send a word to another process
+    RECV,  // Wait for a message, setting the equal flag if one arrived in time
+    SELF,  // Load the PID of the running process
+    SPAWN, // Start a process running this program from an address
     IGL,
 }
 
@@ -48,6 +59,17 @@ impl From<u8> for Opcode {
             17 => Opcode::INC,
             18 => Opcode::DEC,
             19 => Opcode::PRTS,
+            20 => Opcode::LW,
+            21 => Opcode::SW,
+            22 => Opcode::LEA,
+            23 => Opcode::BR,
+            24 => Opcode::BEQ,
+            25 => Opcode::EXIT,
+            26 => Opcode::YIELD,
+            27 => Opcode::SEND,
+            28 => Opcode::RECV,
+            29 => Opcode::SELF,
+            30 => Opcode::SPAWN,
             _ => Opcode::IGL,
         }
     }
@@ -72,15 +94,127 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("gt") => Opcode::GT,
             CompleteStr("lte") => Opcode::LTQ,
             CompleteStr("lt") => Opcode::LT,
+            CompleteStr("jeq") => Opcode::JEQ,
             CompleteStr("aloc") => Opcode::ALOC,
             CompleteStr("inc") => Opcode::INC,
             CompleteStr("dec") => Opcode::DEC,
             CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("lw") => Opcode::LW,
+            CompleteStr("sw") => Opcode::SW,
+            CompleteStr("lea") => Opcode::LEA,
+            CompleteStr("br") => Opcode::BR,
+            CompleteStr("beq") => Opcode::BEQ,
+            CompleteStr("exit") => Opcode::EXIT,
+            CompleteStr("yield") => Opcode::YIELD,
+            CompleteStr("send") => Opcode::SEND,
+            CompleteStr("recv") => Opcode::RECV,
+            CompleteStr("self") => Opcode::SELF,
+            CompleteStr("spawn") => Opcode::SPAWN,
             _ => Opcode::IGL,
         }
     }
 }
 
+/// The kinds of operand that follow an opcode in bytecode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    // A single byte register number
+    Register,
+    // A big-endian 16 bit integer
+    Integer,
+    // A big-endian, signed 16 bit distance from the start of the instruction
+    Offset,
+    // A byte the VM skips over
+    Padding,
+}
+
+impl OperandKind {
+    /// The number of bytes the operand takes up in bytecode
+    pub fn byte_len(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::Padding => 1,
+            OperandKind::Integer | OperandKind::Offset => 2,
+        }
+    }
+}
+
+impl Opcode {
+    /// The operands the VM reads after this opcode, in order
+    pub fn operands(self) -> &'static [OperandKind] {
+        use self::OperandKind::*;
+        match self {
+            Opcode::HLT | Opcode::YIELD | Opcode::IGL => &[],
+            Opcode::LOAD => &[Register, Integer],
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                &[Register, Register, Register]
+            }
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ => &[Register],
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                &[Register, Register, Padding]
+            }
+            Opcode::ALOC | Opcode::INC | Opcode::DEC | Opcode::EXIT | Opcode::SELF => &[Register],
+            Opcode::PRTS => &[Integer],
+            Opcode::LW | Opcode::SW | Opcode::SEND | Opcode::RECV | Opcode::SPAWN => {
+                &[Register, Register]
+            }
+            Opcode::LEA => &[Register, Offset],
+            Opcode::BR | Opcode::BEQ => &[Offset],
+        }
+    }
+
+    /// Whether the instruction refers to code relative to its own address
+    pub fn is_pc_relative(self) -> bool {
+        matches!(self, Opcode::LEA | Opcode::BR | Opcode::BEQ)
+    }
+
+    /// The number of bytes an instruction with this opcode takes up, opcode included
+    pub fn encoded_len(self) -> usize {
+        1 + self
+            .operands()
+            .iter()
+            .map(|kind| kind.byte_len())
+            .sum::<usize>()
+    }
+
+    /// The name the assembler knows this opcode by
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::HLT => "hlt",
+            Opcode::LOAD => "load",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::JMP => "jmp",
+            Opcode::JMPF => "jmpf",
+            Opcode::JMPB => "jmpb",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GT => "gt",
+            Opcode::LT => "lt",
+            Opcode::GTQ => "gte",
+            Opcode::LTQ => "lte",
+            Opcode::JEQ => "jeq",
+            Opcode::ALOC => "aloc",
+            Opcode::INC => "inc",
+            Opcode::DEC => "dec",
+            Opcode::PRTS => "prts",
+            Opcode::LW => "lw",
+            Opcode::SW => "sw",
+            Opcode::LEA => "lea",
+            Opcode::BR => "br",
+            Opcode::BEQ => "beq",
+            Opcode::EXIT => "exit",
+            Opcode::YIELD => "yield",
+            Opcode::SEND => "send",
+            Opcode::RECV => "recv",
+            Opcode::SELF => "self",
+            Opcode::SPAWN => "spawn",
+            Opcode::IGL => "igl",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -115,4 +249,28 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_every_opcode_has_a_mnemonic() {
+        for byte in 0..=255u8 {
+            let opcode = Opcode::from(byte);
+            if opcode != Opcode::IGL {
+                assert_eq!(opcode as u8, byte);
+                assert_eq!(Opcode::from(CompleteStr(opcode.mnemonic())), opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        assert_eq!(Opcode::HLT.encoded_len(), 1);
+        assert_eq!(Opcode::LOAD.encoded_len(), 4);
+        assert_eq!(Opcode::EQ.encoded_len(), 4);
+        assert_eq!(Opcode::PRTS.encoded_len(), 3);
+        assert_eq!(Opcode::LEA.encoded_len(), 4);
+        assert_eq!(Opcode::BR.encoded_len(), 3);
+        assert_eq!(Opcode::EXIT.encoded_len(), 2);
+        assert_eq!(Opcode::YIELD.encoded_len(), 1);
+        assert_eq!(Opcode::SEND.encoded_len(), 3);
+    }
 }
@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate nom;
+extern crate byteorder;
+
+pub mod assembler;
+mod codec;
+pub mod debug_info;
+pub mod disassembler;
+pub mod fuel;
+pub mod instruction;
+pub mod linker;
+pub mod pie;
+pub mod repl;
+pub mod scheduler;
+pub mod snapshot;
+pub mod symbol_map;
+pub mod verifier;
+pub mod vm;
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::instruction::Opcode;
+use crate::linker::object::{ObjectFile, ObjectSection, ObjectSymbol};
+use crate::pie::{PieHeader, PIE_FLAG_POSITION_INDEPENDENT, PIE_HEADER_LENGTH};
+
+pub mod object;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkerError {
+    // Nothing to link
+    NoObjects,
+    // Label is used or imported, but no object defines it
+    UndefinedSymbol { name: String, object: usize },
+    // More than one object exports the same label
+    DuplicateSymbol { name: String },
+    // Label ended up at an address that doesn't fit in a 16 bit operand
+    AddressOutOfRange { name: String, address: u32 },
+    // Label ended up too far away from a pc-relative instruction using it
+    OffsetOutOfRange { name: String, offset: i64 },
+    // Relocation points outside of the object's code
+    InvalidRelocation { offset: u32, object: usize },
+}
+
+impl fmt::Display for LinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkerError::NoObjects => f.write_str("No object files were given to link"),
+            LinkerError::UndefinedSymbol { ref name, object } => write!(
+                f,
+                "Symbol @{} used by object #{} is not defined by any object",
+                name, object
+            ),
+            LinkerError::DuplicateSymbol { ref name } => {
+                write!(f, "Symbol @{} is exported by more than one object", name)
+            }
+            LinkerError::AddressOutOfRange { ref name, address } => write!(
+                f,
+                "Symbol @{} is at address {:#x}, which does not fit in an operand",
+                name, address
+            ),
+            LinkerError::OffsetOutOfRange { ref name, offset } => write!(
+                f,
+                "Symbol @{} is {} bytes away from where it is used, which does not fit in an operand",
+                name, offset
+            ),
+            LinkerError::InvalidRelocation { offset, object } => write!(
+                f,
+                "Relocation at offset {} is outside of the code of object #{}",
+                offset, object
+            ),
+        }
+    }
+}
+
+impl Error for LinkerError {}
+
+/// Where the sections of one object end up in the linked program
+#[derive(Debug, Default)]
+struct Placement {
+    code: u32,
+    ro: u32,
+    data: u32,
+    bss: u32,
+}
+
+/// Combines object files into a single program. Sections of the same kind are merged in the
+/// order the objects were added, and execution starts at the code of the first object.
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<ObjectFile>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker { objects: vec![] }
+    }
+
+    pub fn add_object(&mut self, object: ObjectFile) {
+        self.objects.push(object);
+    }
+
+    /// Links every object added so far into a program image
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkerError>> {
+        if self.objects.is_empty() {
+            return Err(vec![LinkerError::NoObjects]);
+        }
+        let mut errors = vec![];
+
+        let mut placements = vec![];
+        let mut code = vec![];
+        let mut ro = vec![];
+        let mut data = vec![];
+        let mut bss_size = 0;
+        for object in &self.objects {
+            placements.push(Placement {
+                code: code.len() as u32,
+                ro: ro.len() as u32,
+                data: data.len() as u32,
+                bss: bss_size,
+            });
+            code.extend_from_slice(&object.code);
+            ro.extend_from_slice(&object.ro);
+            data.extend_from_slice(&object.data);
+            bss_size += object.bss_size;
+        }
+
+        // Exported labels, by name, along with the object defining them
+        let mut globals: HashMap<&str, (usize, &ObjectSymbol)> = HashMap::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+                if globals.insert(&symbol.name, (index, symbol)).is_some() {
+                    push_error(
+                        &mut errors,
+                        LinkerError::DuplicateSymbol {
+                            name: symbol.name.clone(),
+                        },
+                    );
+                }
+            }
+        }
+
+        // Data addresses count from the start of the merged .rodata, then .data, then .bss
+        let address = |index: usize, symbol: &ObjectSymbol| {
+            let placement = &placements[index];
+            let base = match symbol.section {
+                ObjectSection::Code => PIE_HEADER_LENGTH as u32 + placement.code,
+                ObjectSection::ReadOnlyData => placement.ro,
+                ObjectSection::Data => ro.len() as u32 + placement.data,
+                ObjectSection::Bss => (ro.len() + data.len()) as u32 + placement.bss,
+            };
+            (base + symbol.offset, symbol.section)
+        };
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for import in &object.imports {
+                if !globals.contains_key(import.as_str()) {
+                    push_error(
+                        &mut errors,
+                        LinkerError::UndefinedSymbol {
+                            name: import.clone(),
+                            object: index,
+                        },
+                    );
+                }
+            }
+
+            for relocation in &object.relocations {
+                // An object's own labels shadow the ones exported by other objects
+                let resolved = match object.symbol(&relocation.symbol) {
+                    Some(symbol) => Some(address(index, symbol)),
+                    None => globals
+                        .get(relocation.symbol.as_str())
+                        .map(|&(defined_in, symbol)| address(defined_in, symbol)),
+                };
+                let (target, section) = match resolved {
+                    Some(resolved) => resolved,
+                    None => {
+                        push_error(
+                            &mut errors,
+                            LinkerError::UndefinedSymbol {
+                                name: relocation.symbol.clone(),
+                                object: index,
+                            },
+                        );
+                        continue;
+                    }
+                };
+                if relocation.offset as usize + 2 > object.code.len()
+                    || relocation.instruction >= relocation.offset
+                {
+                    errors.push(LinkerError::InvalidRelocation {
+                        offset: relocation.offset,
+                        object: index,
+                    });
+                    continue;
+                }
+
+                let instruction = (placements[index].code + relocation.instruction) as usize;
+                let mut opcode = Opcode::from(code[instruction]);
+                // Only now is it known whether an `.extern` label is code, which is loaded
+                // relative to the instruction like the assembler does for its own labels
+                if opcode == Opcode::LOAD && section == ObjectSection::Code {
+                    opcode = Opcode::LEA;
+                    code[instruction] = opcode as u8;
+                }
+
+                let value = if opcode.is_pc_relative() {
+                    let offset = target as i64 - (PIE_HEADER_LENGTH + instruction) as i64;
+                    if offset < i64::from(i16::MIN) || offset > i64::from(i16::MAX) {
+                        push_error(
+                            &mut errors,
+                            LinkerError::OffsetOutOfRange {
+                                name: relocation.symbol.clone(),
+                                offset,
+                            },
+                        );
+                        continue;
+                    }
+                    offset as u16
+                } else {
+                    if target > u32::from(u16::MAX) {
+                        push_error(
+                            &mut errors,
+                            LinkerError::AddressOutOfRange {
+                                name: relocation.symbol.clone(),
+                                address: target,
+                            },
+                        );
+                        continue;
+                    }
+                    target as u16
+                };
+                let position = (placements[index].code + relocation.offset) as usize;
+                BigEndian::write_u16(&mut code[position..], value);
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        // Every code label has been turned into a pc-relative reference
+        Ok(PieHeader::build_image(
+            &code,
+            &ro,
+            &data,
+            bss_size,
+            PIE_FLAG_POSITION_INDEPENDENT,
+        ))
+    }
+}
+
+/// Records `error` unless the same problem was already reported
+fn push_error(errors: &mut Vec<LinkerError>, error: LinkerError) {
+    if !errors.contains(&error) {
+        errors.push(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    fn assemble_object(source: &str) -> ObjectFile {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_objects() {
+        let main = assemble_object(
+            r"
+            .extern counter
+            .extern increment
+            .code
+            load $0 @counter
+            load $1 @increment
+            jmp $1
+            back: hlt
+            .global back
+            ",
+        );
+        let library = assemble_object(
+            r"
+            .global counter
+            .global increment
+            .extern back
+            .rodata
+            padding: .asciiz 'abc'
+            .data
+            counter: .word #41
+            .code
+            increment: lw $2 $0
+            inc $2
+            sw $2 $0
+            load $3 @back
+            jmp $3
+            ",
+        );
+
+        let mut linker = Linker::new();
+        linker.add_object(main);
+        linker.add_object(library);
+        let program = linker.link().unwrap();
+
+        // Loads of code labels from other objects were made pc-relative
+        assert_eq!(program[PIE_HEADER_LENGTH + 4], Opcode::LEA as u8);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run();
+        // .rodata of the library comes first in the data address space
+        assert_eq!(vm.registers[0], 4);
+        assert_eq!(vm.read_word(4), Ok(42));
+    }
+
+    #[test]
+    fn test_link_needs_objects() {
+        assert_eq!(Linker::new().link(), Err(vec![LinkerError::NoObjects]));
+    }
+
+    #[test]
+    fn test_link_reports_duplicate_and_undefined_symbols() {
+        let mut linker = Linker::new();
+        linker.add_object(assemble_object(
+            ".global main\n.extern missing\n.code\nmain: load $0 @missing",
+        ));
+        linker.add_object(assemble_object(".global main\n.code\nmain: hlt"));
+        let errors = linker.link().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                LinkerError::DuplicateSymbol {
+                    name: "main".to_string()
+                },
+                LinkerError::UndefinedSymbol {
+                    name: "missing".to_string(),
+                    object: 0
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::codec::{write_bytes, write_u16, write_u32, Reader, Truncated};
+
+/// Magic bytes at the start of every object file, `-IRO`
+pub const OBJECT_PREFIX: [u8; 4] = [45, 73, 82, 79];
+/// The version of the object file layout written by this assembler
+pub const OBJECT_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectError {
+    // File does not start with `OBJECT_PREFIX`
+    InvalidPrefix,
+    // File was written for a different layout
+    UnsupportedVersion { version: u16 },
+    // File ends in the middle of a field
+    Truncated { offset: usize },
+    // Symbol refers to a section that doesn't exist
+    InvalidSection { section: u8 },
+    // Symbol name is not valid UTF-8
+    InvalidName { offset: usize },
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjectError::InvalidPrefix => f.write_str("File is not an Iridium object file"),
+            ObjectError::UnsupportedVersion { version } => write!(
+                f,
+                "Object file version {} is not supported, expected version {}",
+                version, OBJECT_VERSION
+            ),
+            ObjectError::Truncated { offset } => {
+                write!(f, "Object file ends unexpectedly at offset {}", offset)
+            }
+            ObjectError::InvalidSection { section } => {
+                write!(f, "Object file refers to unknown section {}", section)
+            }
+            ObjectError::InvalidName { offset } => {
+                write!(
+                    f,
+                    "Object file has an invalid symbol name at offset {}",
+                    offset
+                )
+            }
+        }
+    }
+}
+
+impl Error for ObjectError {}
+
+impl From<Truncated> for ObjectError {
+    fn from(error: Truncated) -> ObjectError {
+        ObjectError::Truncated {
+            offset: error.offset,
+        }
+    }
+}
+
+/// The section an object symbol is defined in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectSection {
+    Code,
+    ReadOnlyData,
+    Data,
+    Bss,
+}
+
+impl ObjectSection {
+    fn to_byte(self) -> u8 {
+        match self {
+            ObjectSection::Code => 0,
+            ObjectSection::ReadOnlyData => 1,
+            ObjectSection::Data => 2,
+            ObjectSection::Bss => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<ObjectSection, ObjectError> {
+        match byte {
+            0 => Ok(ObjectSection::Code),
+            1 => Ok(ObjectSection::ReadOnlyData),
+            2 => Ok(ObjectSection::Data),
+            3 => Ok(ObjectSection::Bss),
+            _ => Err(ObjectError::InvalidSection { section: byte }),
+        }
+    }
+}
+
+/// A label defined by an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: ObjectSection,
+    /// Offset from the start of the object's part of `section`
+    pub offset: u32,
+    /// Whether other objects may refer to it (declared with `.global`)
+    pub global: bool,
+}
+
+/// A 16 bit label operand in the code which the linker has to fill in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// Offset of the instruction the operand belongs to from the start of the object's code.
+    /// Its opcode tells whether the label is used as an address or as a pc-relative offset.
+    pub instruction: u32,
+    /// Offset of the operand from the start of the object's code
+    pub offset: u32,
+    /// The label, looked up in the object's own symbols first and then in the exported ones
+    pub symbol: String,
+}
+
+/// The output of assembling a single file that still has to be linked: its sections have not
+/// been placed yet, so every label operand in the code is listed as a relocation.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    pub ro: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: u32,
+    pub symbols: Vec<ObjectSymbol>,
+    /// Labels declared with `.extern`
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn new() -> ObjectFile {
+        ObjectFile::default()
+    }
+
+    /// Whether `bytes` start like an object file
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(&OBJECT_PREFIX)
+    }
+
+    /// Serializes the object. All integers are big-endian, and sections and names are prefixed
+    /// with their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = OBJECT_PREFIX.to_vec();
+        write_u16(&mut out, OBJECT_VERSION);
+        write_bytes(&mut out, &self.code);
+        write_bytes(&mut out, &self.ro);
+        write_bytes(&mut out, &self.data);
+        write_u32(&mut out, self.bss_size);
+
+        write_u32(&mut out, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            write_bytes(&mut out, symbol.name.as_bytes());
+            out.push(symbol.section.to_byte());
+            out.push(symbol.global as u8);
+            write_u32(&mut out, symbol.offset);
+        }
+
+        write_u32(&mut out, self.imports.len() as u32);
+        for import in &self.imports {
+            write_bytes(&mut out, import.as_bytes());
+        }
+
+        write_u32(&mut out, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            write_u32(&mut out, relocation.instruction);
+            write_u32(&mut out, relocation.offset);
+            write_bytes(&mut out, relocation.symbol.as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, ObjectError> {
+        if !ObjectFile::is_object(bytes) {
+            return Err(ObjectError::InvalidPrefix);
+        }
+        let mut reader = Reader::new(bytes, OBJECT_PREFIX.len());
+        let version = reader.read_u16()?;
+        if version != OBJECT_VERSION {
+            return Err(ObjectError::UnsupportedVersion { version });
+        }
+
+        let mut object = ObjectFile {
+            code: reader.read_bytes()?.to_vec(),
+            ro: reader.read_bytes()?.to_vec(),
+            data: reader.read_bytes()?.to_vec(),
+            bss_size: reader.read_u32()?,
+            ..ObjectFile::default()
+        };
+
+        for _ in 0..reader.read_u32()? {
+            let name = reader.read_string(invalid_name)?;
+            let section = ObjectSection::from_byte(reader.read_u8()?)?;
+            let global = reader.read_u8()? != 0;
+            let offset = reader.read_u32()?;
+            object.symbols.push(ObjectSymbol {
+                name,
+                section,
+                offset,
+                global,
+            });
+        }
+
+        for _ in 0..reader.read_u32()? {
+            object.imports.push(reader.read_string(invalid_name)?);
+        }
+
+        for _ in 0..reader.read_u32()? {
+            let instruction = reader.read_u32()?;
+            let offset = reader.read_u32()?;
+            let symbol = reader.read_string(invalid_name)?;
+            object.relocations.push(Relocation {
+                instruction,
+                offset,
+                symbol,
+            });
+        }
+        Ok(object)
+    }
+
+    /// Looks up a symbol defined by this object, exported or not
+    pub fn symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+fn invalid_name(offset: usize) -> ObjectError {
+    ObjectError::InvalidName { offset }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> ObjectFile {
+        ObjectFile {
+            code: vec![1, 0, 0, 0, 0],
+            ro: b"hi\0".to_vec(),
+            data: vec![0, 0, 0, 1],
+            bss_size: 8,
+            symbols: vec![ObjectSymbol {
+                name: "main".to_string(),
+                section: ObjectSection::Code,
+                offset: 0,
+                global: true,
+            }],
+            imports: vec!["print".to_string()],
+            relocations: vec![Relocation {
+                instruction: 0,
+                offset: 2,
+                symbol: "print".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_object_keeps_symbols_and_relocations() {
+        let object = object();
+        let bytes = object.to_bytes();
+        assert!(ObjectFile::is_object(&bytes));
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object));
+    }
+
+    #[test]
+    fn test_object_rejects_other_files() {
+        assert_eq!(
+            ObjectFile::from_bytes(&[0, 1, 2, 3]),
+            Err(ObjectError::InvalidPrefix)
+        );
+        let mut bad_version = object().to_bytes();
+        bad_version[5] = 9;
+        assert_eq!(
+            ObjectFile::from_bytes(&bad_version),
+            Err(ObjectError::UnsupportedVersion { version: 9 })
+        );
+    }
+
+    #[test]
+    fn test_truncated_object_reports_where_it_ends() {
+        let bytes = object().to_bytes();
+        assert_eq!(
+            ObjectFile::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ObjectError::Truncated {
+                offset: bytes.len() - 1
+            })
+        );
+    }
+}
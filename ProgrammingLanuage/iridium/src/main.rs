@@ -1,62 +1,312 @@
 #[macro_use]
-extern crate nom;
-extern crate byteorder;
-#[macro_use]
 extern crate log;
 #[macro_use]
 extern crate clap;
 extern crate env_logger;
+extern crate iridium;
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod scheduler;
-pub mod vm;
+use iridium::linker::object::ObjectFile;
+use iridium::scheduler::Recording;
+use iridium::snapshot::Snapshot;
+use iridium::symbol_map::SymbolMap;
+use iridium::{assembler, disassembler, linker, pie, repl, scheduler, vm};
 
 use clap::App;
-use std::fs::{read_to_string, File};
+use std::fs::{read, read_to_string, write, File};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 fn main() {
     env_logger::init();
     info!("Starting logging!");
     let yaml = load_yaml!("../cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target_file = matches.value_of("INPUT_FILE");
-    match target_file {
-        Some(filename) => {
-            let program = read_file(filename);
+    match matches.subcommand() {
+        ("assemble", Some(sub_matches)) => {
+            let input_file = sub_matches.value_of("INPUT_FILE").unwrap();
+            let is_object = sub_matches.is_present("OBJECT");
+            let output_file = match sub_matches.value_of("OUTPUT_FILE") {
+                Some(output_file) => output_file.to_string(),
+                None => Path::new(input_file)
+                    .with_extension(if is_object { "iro" } else { "irb" })
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let source = read_file(input_file);
             let mut asm = assembler::Assembler::new();
-            let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            vm.ro_data = asm.ro.clone();
-            match program {
-                Ok(p) => {
-                    vm.add_bytes(p);
-                    vm.run();
-                    resume_repl(vm, asm);
+            let output = if is_object {
+                match asm.assemble_object(&source) {
+                    Ok(object) => object.to_bytes(),
+                    Err(errors) => exit_with_errors("Unable to assemble object", errors),
+                }
+            } else if sub_matches.is_present("DEBUG_INFO") {
+                assemble_or_exit(&mut asm, &source, Some(input_file))
+            } else {
+                assemble_or_exit(&mut asm, &source, None)
+            };
+            if let Some(listing_file) = sub_matches.value_of("LISTING_FILE") {
+                write_file(listing_file, asm.listing(&source).as_bytes());
+            }
+            for symbol in asm.unused_labels() {
+                println!("Warning: label @{} is never used", symbol.name());
+            }
+            if let Some(map_file) = sub_matches.value_of("SYMBOL_MAP") {
+                write_file(map_file, asm.symbol_map().to_text().as_bytes());
+            }
+            write_file(&output_file, &output);
+            println!("Wrote {} bytes to {}", output.len(), output_file);
+        }
+        ("link", Some(sub_matches)) => {
+            let mut linker = linker::Linker::new();
+            for input_file in sub_matches.values_of("INPUT_FILES").unwrap() {
+                match ObjectFile::from_bytes(&read_bytes(input_file)) {
+                    Ok(object) => linker.add_object(object),
+                    Err(e) => {
+                        println!("Unable to read object {}: {}", input_file, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            let output_file = sub_matches.value_of("OUTPUT_FILE").unwrap();
+            match linker.link() {
+                Ok(program) => {
+                    write_file(output_file, &program);
+                    println!("Wrote {} bytes to {}", program.len(), output_file);
+                }
+                Err(errors) => exit_with_errors("Unable to link program", errors),
+            }
+        }
+        ("run", Some(sub_matches)) => {
+            let input_file = sub_matches.value_of("INPUT_FILE").unwrap();
+            // The file is either a snapshot to go on from, or a program to start
+            let contents = read_bytes(input_file);
+            let is_restored = Snapshot::is_snapshot(&contents);
+            let mut vm = if is_restored {
+                restore_snapshot(input_file, &contents)
+            } else {
+                let (program, _) = program_from_bytes(input_file, contents);
+                let mut vm = vm::VM::new();
+                load_or_exit(&mut vm, program);
+                vm
+            };
+            vm.require_verification = sub_matches.is_present("VERIFY");
+            if let Some(map_file) = sub_matches.value_of("SYMBOL_MAP") {
+                vm.symbol_map = Some(load_symbol_map(map_file));
+            }
+            vm.fuel = sub_matches
+                .value_of("FUEL")
+                .map(|fuel| parse_number("Fuel", fuel));
+            // The program runs as the first process, so it can spawn and talk to others
+            let mut scheduler = scheduler::Scheduler::new();
+            scheduler.workers = workers(sub_matches.value_of("WORKERS"));
+            let spawned = if is_restored {
+                scheduler.spawn_restored(vm)
+            } else {
+                scheduler.spawn(vm)
+            };
+            let pid = match spawned {
+                Ok(pid) => pid,
+                Err(e) => exit_with_errors("Unable to run program", vec![e]),
+            };
+            if let Some(deadline) = sub_matches.value_of("DEADLINE") {
+                let deadline = Duration::from_millis(parse_number("Deadline", deadline));
+                let _ = scheduler.set_deadline(pid, Some(Instant::now() + deadline));
+            }
+            if let Some(replay) = sub_matches.value_of("REPLAY") {
+                let recording = read_recording(replay);
+                if let Err(e) = scheduler.replay(&recording) {
+                    println!("Unable to replay {}: {}", replay, e);
+                    std::process::exit(1);
+                }
+            }
+            let record = sub_matches.value_of("RECORD");
+            if record.is_some() {
+                scheduler.start_recording();
+            }
+            if let Err(e) = scheduler.wait(pid) {
+                exit_with_errors("Unable to run program", vec![e]);
+            }
+            if let (Some(record), Some(recording)) = (record, scheduler.stop_recording()) {
+                write_file(record, &recording.to_bytes());
+                println!("Saved the recording of the run to {}", record);
+            }
+            let process = scheduler.process(pid).unwrap();
+            let outcome = process.outcome.clone().unwrap_or(vm::RunOutcome::Killed);
+            if outcome == vm::RunOutcome::OutOfFuel || outcome == vm::RunOutcome::DeadlineExceeded {
+                println!(
+                    "{} at {}",
+                    outcome,
+                    process.vm.describe_location(process.vm.pc)
+                );
+                if let Some(checkpoint) = sub_matches.value_of("CHECKPOINT") {
+                    write_file(checkpoint, &process.vm.snapshot().to_bytes());
+                    println!("Saved the state of the program to {}", checkpoint);
                 }
-                _ => {}
             }
+            std::process::exit(outcome.exit_code());
         }
-        None => {
-            start_repl();
+        _ => match matches.value_of("INPUT_FILE") {
+            Some(filename) => {
+                let (program, asm) = load_program(filename);
+                if let (Some((asm, source)), Some(listing_file)) =
+                    (&asm, matches.value_of("LISTING_FILE"))
+                {
+                    write_file(listing_file, asm.listing(source).as_bytes());
+                }
+                let symbol_map = matches.value_of("SYMBOL_MAP").map(load_symbol_map);
+                if matches.is_present("DISASSEMBLE") {
+                    match disassembler::disassemble(&program) {
+                        Ok(mut disassembly) => {
+                            if let Some(ref symbol_map) = symbol_map {
+                                disassembly.apply_symbol_map(symbol_map);
+                            }
+                            print!("{}", disassembly)
+                        }
+                        Err(e) => println!("Unable to disassemble program: {}", e),
+                    }
+                    return;
+                }
+                let mut vm = vm::VM::new();
+                vm.require_verification = matches.is_present("VERIFY");
+                vm.symbol_map = symbol_map;
+                load_or_exit(&mut vm, program);
+                vm.run();
+                let asm = match asm {
+                    Some((asm, _)) => asm,
+                    None => assembler::Assembler::new(),
+                };
+                resume_repl(vm, asm, workers(matches.value_of("WORKERS")));
+            }
+            None => {
+                start_repl(workers(matches.value_of("WORKERS")));
+            }
+        },
+    }
+}
+
+/// Reads a program from `filename`, which is either an assembled bytecode file or source code.
+/// Source code is assembled with debug info, and the assembler is returned along with the source.
+fn load_program(filename: &str) -> (Vec<u8>, Option<(assembler::Assembler, String)>) {
+    program_from_bytes(filename, read_bytes(filename))
+}
+
+/// Like `load_program`, for the `contents` already read from `filename`
+fn program_from_bytes(
+    filename: &str,
+    contents: Vec<u8>,
+) -> (Vec<u8>, Option<(assembler::Assembler, String)>) {
+    if pie::is_pie(&contents) {
+        return (contents, None);
+    }
+    let source = match String::from_utf8(contents) {
+        Ok(source) => source,
+        Err(_) => {
+            println!(
+                "{} is neither an Iridium bytecode file nor source code",
+                filename
+            );
+            std::process::exit(1);
+        }
+    };
+    let mut asm = assembler::Assembler::new();
+    let program = assemble_or_exit(&mut asm, &source, Some(filename));
+    (program, Some((asm, source)))
+}
+
+/// Restores the VM saved in the snapshot file `filename`, whose `contents` were already read
+fn restore_snapshot(filename: &str, contents: &[u8]) -> vm::VM {
+    match Snapshot::from_bytes(contents) {
+        Ok(snapshot) => vm::VM::from_snapshot(snapshot),
+        Err(e) => {
+            println!("Unable to read snapshot {}: {}", filename, e);
+            std::process::exit(1);
         }
     }
 }
 
-fn start_repl() {
+fn read_recording(filename: &str) -> Recording {
+    match Recording::from_bytes(&read_bytes(filename)) {
+        Ok(recording) => recording,
+        Err(e) => {
+            println!("Unable to read recording {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Assembles `source`, adding debug info that names `debug_file` if one is given
+fn assemble_or_exit(
+    asm: &mut assembler::Assembler,
+    source: &str,
+    debug_file: Option<&str>,
+) -> Vec<u8> {
+    let result = match debug_file {
+        Some(file) => asm.assemble_with_debug_info(source, file),
+        None => asm.assemble(source),
+    };
+    match result {
+        Ok(program) => program,
+        Err(errors) => exit_with_errors("Unable to assemble program", errors),
+    }
+}
+
+fn exit_with_errors<E: std::fmt::Display>(context: &str, errors: Vec<E>) -> ! {
+    for error in errors {
+        println!("{}: {}", context, error);
+    }
+    std::process::exit(1);
+}
+
+fn load_or_exit(vm: &mut vm::VM, program: Vec<u8>) {
+    if let Err(e) = vm.load_program(program) {
+        println!("Unable to load program: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn load_symbol_map(filename: &str) -> SymbolMap {
+    match SymbolMap::from_text(&read_file(filename)) {
+        Ok(symbol_map) => symbol_map,
+        Err(e) => {
+            println!("Unable to read symbol map {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> T {
+    match value.parse() {
+        Ok(number) => number,
+        Err(_) => {
+            println!("{} must be a whole number, got {}", option, value);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The number of scheduler threads asked for, or one per core
+fn workers(value: Option<&str>) -> usize {
+    match value {
+        Some(value) => parse_number("Workers", value),
+        None => std::thread::available_parallelism().map_or(1, |cores| cores.get()),
+    }
+}
+
+fn start_repl(workers: usize) {
     let mut repl = repl::REPL::new();
+    repl.scheduler.workers = workers;
     repl.run();
 }
 
-fn resume_repl(vm: vm::VM, asm: assembler::Assembler) {
+fn resume_repl(vm: vm::VM, asm: assembler::Assembler, workers: usize) {
     let mut repl = repl::REPL::new();
     repl.vm = vm;
     repl.asm = asm;
+    repl.scheduler.workers = workers;
     repl.run();
 }
 
+#[allow(clippy::needless_return)]
 fn read_file(tmp: &str) -> String {
     let filename = Path::new(tmp);
     match File::open(filename) {
@@ -75,3 +325,20 @@ fn read_file(tmp: &str) -> String {
         }
     }
 }
+
+fn write_file(tmp: &str, contents: &[u8]) {
+    if let Err(e) = write(Path::new(tmp), contents) {
+        println!("There was an error writing file: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn read_bytes(tmp: &str) -> Vec<u8> {
+    match read(Path::new(tmp)) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("There was an error reading file: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
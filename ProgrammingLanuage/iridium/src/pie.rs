@@ -0,0 +1,519 @@
+use std::error::Error;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+/// The version of the header layout written by this assembler
+pub const PIE_VERSION: u16 = 2;
+/// Set when code addresses are only ever computed relative to the running instruction, so the
+/// code works wherever it is loaded
+pub const PIE_FLAG_POSITION_INDEPENDENT: u16 = 0x0001;
+/// Every flag bit this VM understands. Programs with other bits set are rejected.
+pub const PIE_KNOWN_FLAGS: u16 = PIE_FLAG_POSITION_INDEPENDENT;
+
+// Byte offsets of the header fields. All fields are big-endian, like instruction operands.
+const VERSION_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 6;
+const ENTRY_POINT_OFFSET: usize = 8;
+const CODE_LENGTH_OFFSET: usize = 12;
+const RO_OFFSET_OFFSET: usize = 16;
+const RO_LENGTH_OFFSET: usize = 20;
+const CHECKSUM_OFFSET: usize = 24;
+const DATA_OFFSET_OFFSET: usize = 28;
+const DATA_LENGTH_OFFSET: usize = 32;
+const BSS_SIZE_OFFSET: usize = 36;
+const DEBUG_OFFSET_OFFSET: usize = 40;
+const DEBUG_LENGTH_OFFSET: usize = 44;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderError {
+    // Program is shorter than the header
+    TooShort {
+        length: usize,
+    },
+    // Program does not start with `PIE_HEADER_PREFIX`
+    InvalidPrefix,
+    // Header was written for a different layout
+    UnsupportedVersion {
+        version: u16,
+    },
+    // Flag bits this VM doesn't know about are set
+    UnknownFlags {
+        flags: u16,
+    },
+    // Code does not fit in the program
+    CodeLengthOutOfBounds {
+        code_length: u32,
+        program_length: usize,
+    },
+    // Entry point is not inside the code
+    EntryPointOutOfBounds {
+        entry_point: u32,
+    },
+    // Read-only data overlaps the code or does not fit in the program
+    ReadOnlyDataOutOfBounds {
+        offset: u32,
+        length: u32,
+    },
+    // Initialized data overlaps the read-only data or does not fit in the program
+    DataOutOfBounds {
+        offset: u32,
+        length: u32,
+    },
+    // Debug info overlaps the data or does not fit in the program
+    DebugInfoOutOfBounds {
+        offset: u32,
+        length: u32,
+    },
+    // Program bytes were changed after the header was written
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::TooShort { length } => write!(
+                f,
+                "Program is {} bytes long, too short to contain the {} byte header",
+                length, PIE_HEADER_LENGTH
+            ),
+            HeaderError::InvalidPrefix => {
+                f.write_str("Program does not start with the PIE header prefix")
+            }
+            HeaderError::UnsupportedVersion { version } => write!(
+                f,
+                "Header version {} is not supported, expected version {}",
+                version, PIE_VERSION
+            ),
+            HeaderError::UnknownFlags { flags } => {
+                write!(f, "Header has unknown flags set: {:#06x}", flags)
+            }
+            HeaderError::CodeLengthOutOfBounds {
+                code_length,
+                program_length,
+            } => write!(
+                f,
+                "Header code length {} does not fit in a program of {} bytes",
+                code_length, program_length
+            ),
+            HeaderError::EntryPointOutOfBounds { entry_point } => write!(
+                f,
+                "Header entry point {} is outside of the code",
+                entry_point
+            ),
+            HeaderError::ReadOnlyDataOutOfBounds { offset, length } => write!(
+                f,
+                "Header read-only data ({} bytes at offset {}) is outside of the program",
+                length, offset
+            ),
+            HeaderError::DataOutOfBounds { offset, length } => write!(
+                f,
+                "Header data ({} bytes at offset {}) is outside of the program",
+                length, offset
+            ),
+            HeaderError::DebugInfoOutOfBounds { offset, length } => write!(
+                f,
+                "Header debug info ({} bytes at offset {}) is outside of the program",
+                length, offset
+            ),
+            HeaderError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Header checksum is {:#010x}, but the program's checksum is {:#010x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for HeaderError {}
+
+/// The fixed size header at the start of every assembled program. The code follows the header
+/// directly, then the read-only data and the initialized data, optionally followed by debug info;
+/// offsets are counted from the start of the program, header included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieHeader {
+    pub version: u16,
+    pub flags: u16,
+    /// Where execution starts
+    pub entry_point: u32,
+    /// Number of code bytes after the header
+    pub code_length: u32,
+    pub ro_offset: u32,
+    pub ro_length: u32,
+    pub data_offset: u32,
+    pub data_length: u32,
+    /// Number of zeroed bytes to reserve after the initialized data. They are not stored in the program.
+    pub bss_size: u32,
+    /// Where the debug info is stored. A length of 0 means the program has none.
+    pub debug_offset: u32,
+    pub debug_length: u32,
+    /// Checksum of the code and data sections
+    pub checksum: u32,
+}
+
+impl PieHeader {
+    /// Creates a header for a program consisting of the header followed by `body`, which holds
+    /// `code_length` bytes of code and nothing else. Execution starts at the first instruction.
+    pub fn new(body: &[u8], code_length: u32) -> PieHeader {
+        let code_end = PIE_HEADER_LENGTH as u32 + code_length;
+        PieHeader {
+            version: PIE_VERSION,
+            flags: 0,
+            entry_point: PIE_HEADER_LENGTH as u32,
+            code_length,
+            ro_offset: code_end,
+            ro_length: 0,
+            data_offset: code_end,
+            data_length: 0,
+            bss_size: 0,
+            debug_offset: 0,
+            debug_length: 0,
+            checksum: checksum(body),
+        }
+    }
+
+    /// Builds a complete program image: the header, then `code`, `ro` and `data`
+    pub fn build_image(code: &[u8], ro: &[u8], data: &[u8], bss_size: u32, flags: u16) -> Vec<u8> {
+        let mut body = Vec::with_capacity(code.len() + ro.len() + data.len());
+        body.extend_from_slice(code);
+        body.extend_from_slice(ro);
+        body.extend_from_slice(data);
+
+        let mut header = PieHeader::new(&body, code.len() as u32);
+        header.flags = flags;
+        header.ro_length = ro.len() as u32;
+        header.data_offset = header.ro_offset + header.ro_length;
+        header.data_length = data.len() as u32;
+        header.bss_size = bss_size;
+
+        let mut image = header.to_bytes();
+        image.append(&mut body);
+        image
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = vec![0; PIE_HEADER_LENGTH];
+        header[..PIE_HEADER_PREFIX.len()].copy_from_slice(&PIE_HEADER_PREFIX);
+        BigEndian::write_u16(&mut header[VERSION_OFFSET..], self.version);
+        BigEndian::write_u16(&mut header[FLAGS_OFFSET..], self.flags);
+        BigEndian::write_u32(&mut header[ENTRY_POINT_OFFSET..], self.entry_point);
+        BigEndian::write_u32(&mut header[CODE_LENGTH_OFFSET..], self.code_length);
+        BigEndian::write_u32(&mut header[RO_OFFSET_OFFSET..], self.ro_offset);
+        BigEndian::write_u32(&mut header[RO_LENGTH_OFFSET..], self.ro_length);
+        BigEndian::write_u32(&mut header[CHECKSUM_OFFSET..], self.checksum);
+        BigEndian::write_u32(&mut header[DATA_OFFSET_OFFSET..], self.data_offset);
+        BigEndian::write_u32(&mut header[DATA_LENGTH_OFFSET..], self.data_length);
+        BigEndian::write_u32(&mut header[BSS_SIZE_OFFSET..], self.bss_size);
+        BigEndian::write_u32(&mut header[DEBUG_OFFSET_OFFSET..], self.debug_offset);
+        BigEndian::write_u32(&mut header[DEBUG_LENGTH_OFFSET..], self.debug_length);
+        header
+    }
+
+    /// Reads the header at the start of `program` without validating its fields
+    pub fn from_bytes(program: &[u8]) -> Result<PieHeader, HeaderError> {
+        if program.len() < PIE_HEADER_LENGTH {
+            return Err(HeaderError::TooShort {
+                length: program.len(),
+            });
+        }
+        if program[..PIE_HEADER_PREFIX.len()] != PIE_HEADER_PREFIX {
+            return Err(HeaderError::InvalidPrefix);
+        }
+        Ok(PieHeader {
+            version: BigEndian::read_u16(&program[VERSION_OFFSET..]),
+            flags: BigEndian::read_u16(&program[FLAGS_OFFSET..]),
+            entry_point: BigEndian::read_u32(&program[ENTRY_POINT_OFFSET..]),
+            code_length: BigEndian::read_u32(&program[CODE_LENGTH_OFFSET..]),
+            ro_offset: BigEndian::read_u32(&program[RO_OFFSET_OFFSET..]),
+            ro_length: BigEndian::read_u32(&program[RO_LENGTH_OFFSET..]),
+            data_offset: BigEndian::read_u32(&program[DATA_OFFSET_OFFSET..]),
+            data_length: BigEndian::read_u32(&program[DATA_LENGTH_OFFSET..]),
+            bss_size: BigEndian::read_u32(&program[BSS_SIZE_OFFSET..]),
+            debug_offset: BigEndian::read_u32(&program[DEBUG_OFFSET_OFFSET..]),
+            debug_length: BigEndian::read_u32(&program[DEBUG_LENGTH_OFFSET..]),
+            checksum: BigEndian::read_u32(&program[CHECKSUM_OFFSET..]),
+        })
+    }
+
+    /// Reads the header at the start of `program` and checks every field against it
+    pub fn parse(program: &[u8]) -> Result<PieHeader, HeaderError> {
+        let header = PieHeader::from_bytes(program)?;
+        header.validate(program)?;
+        Ok(header)
+    }
+
+    /// Checks that the fields of this header describe `program`
+    pub fn validate(&self, program: &[u8]) -> Result<(), HeaderError> {
+        if self.version != PIE_VERSION {
+            return Err(HeaderError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+        if self.flags & !PIE_KNOWN_FLAGS != 0 {
+            return Err(HeaderError::UnknownFlags { flags: self.flags });
+        }
+        let code_end = PIE_HEADER_LENGTH as u64 + self.code_length as u64;
+        if code_end > program.len() as u64 {
+            return Err(HeaderError::CodeLengthOutOfBounds {
+                code_length: self.code_length,
+                program_length: program.len(),
+            });
+        }
+        // An entry point at the very end of the code is allowed: the program simply has nothing to do
+        if (self.entry_point as u64) < PIE_HEADER_LENGTH as u64
+            || self.entry_point as u64 > code_end
+        {
+            return Err(HeaderError::EntryPointOutOfBounds {
+                entry_point: self.entry_point,
+            });
+        }
+        let ro_end = self.ro_offset as u64 + self.ro_length as u64;
+        if (self.ro_offset as u64) < code_end || ro_end > program.len() as u64 {
+            return Err(HeaderError::ReadOnlyDataOutOfBounds {
+                offset: self.ro_offset,
+                length: self.ro_length,
+            });
+        }
+        let data_end = self.data_offset as u64 + self.data_length as u64;
+        if (self.data_offset as u64) < ro_end || data_end > program.len() as u64 {
+            return Err(HeaderError::DataOutOfBounds {
+                offset: self.data_offset,
+                length: self.data_length,
+            });
+        }
+        let debug_end = self.debug_offset as u64 + self.debug_length as u64;
+        if self.debug_length != 0
+            && ((self.debug_offset as u64) < data_end || debug_end > program.len() as u64)
+        {
+            return Err(HeaderError::DebugInfoOutOfBounds {
+                offset: self.debug_offset,
+                length: self.debug_length,
+            });
+        }
+        // Anything after the described sections (e.g. modules loaded next to the program) isn't covered
+        let actual = checksum(&program[PIE_HEADER_LENGTH..data_end as usize]);
+        if actual != self.checksum {
+            return Err(HeaderError::ChecksumMismatch {
+                expected: self.checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_position_independent(&self) -> bool {
+        self.flags & PIE_FLAG_POSITION_INDEPENDENT != 0
+    }
+
+    /// The offset just past the last byte of code
+    pub fn code_end(&self) -> usize {
+        PIE_HEADER_LENGTH + self.code_length as usize
+    }
+
+    /// The read-only data stored in `program`, which must have been validated against this header
+    pub fn ro_data<'a>(&self, program: &'a [u8]) -> &'a [u8] {
+        let start = self.ro_offset as usize;
+        &program[start..start + self.ro_length as usize]
+    }
+
+    /// The initialized data stored in `program`, which must have been validated against this header
+    pub fn data<'a>(&self, program: &'a [u8]) -> &'a [u8] {
+        let start = self.data_offset as usize;
+        &program[start..start + self.data_length as usize]
+    }
+
+    /// The encoded debug info stored in `program`, if there is any. `program` must have been
+    /// validated against this header.
+    pub fn debug_info<'a>(&self, program: &'a [u8]) -> Option<&'a [u8]> {
+        if self.debug_length == 0 {
+            return None;
+        }
+        let start = self.debug_offset as usize;
+        Some(&program[start..start + self.debug_length as usize])
+    }
+
+    /// Appends encoded debug info to a program image and points its header at it. The
+    /// checksum stays the same, as debug info is not covered by it.
+    pub fn append_debug_info(image: &mut Vec<u8>, debug_info: &[u8]) -> Result<(), HeaderError> {
+        let mut header = PieHeader::parse(image)?;
+        header.debug_offset = image.len() as u32;
+        header.debug_length = debug_info.len() as u32;
+        image[..PIE_HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+        image.extend_from_slice(debug_info);
+        Ok(())
+    }
+}
+
+/// Whether `bytes` start like an assembled program rather than source code
+pub fn is_pie(bytes: &[u8]) -> bool {
+    bytes.starts_with(&PIE_HEADER_PREFIX)
+}
+
+/// Adler-32 checksum of `bytes`
+pub fn checksum(bytes: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for byte in bytes {
+        a = (a + *byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(code: &[u8]) -> Vec<u8> {
+        let mut program = PieHeader::new(code, code.len() as u32).to_bytes();
+        program.extend_from_slice(code);
+        program
+    }
+
+    #[test]
+    fn test_checksum() {
+        // Reference value from the Adler-32 specification
+        assert_eq!(checksum(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(checksum(&[]), 1);
+    }
+
+    #[test]
+    fn test_is_pie() {
+        assert!(is_pie(&program(&[0])));
+        assert!(!is_pie(b"load $0 #1"));
+        assert!(!is_pie(&[45, 50]));
+    }
+
+    #[test]
+    fn test_header_describes_program() {
+        let program = program(&[1, 0, 0, 100, 0]);
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 5);
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.version, PIE_VERSION);
+        assert_eq!(header.entry_point, 64);
+        assert_eq!(header.code_length, 5);
+        assert_eq!(header.ro_offset, 69);
+        assert_eq!(header.ro_length, 0);
+        assert_eq!(header.code_end(), 69);
+    }
+
+    #[test]
+    fn test_build_image() {
+        let image = PieHeader::build_image(
+            &[0],
+            b"hi\0",
+            &[0, 0, 0, 7],
+            8,
+            PIE_FLAG_POSITION_INDEPENDENT,
+        );
+        assert_eq!(image.len(), PIE_HEADER_LENGTH + 8);
+        let header = PieHeader::parse(&image).unwrap();
+        assert_eq!(header.code_end(), 65);
+        assert_eq!(header.ro_offset, 65);
+        assert_eq!(header.ro_data(&image), b"hi\0");
+        assert_eq!(header.data_offset, 68);
+        assert_eq!(header.data(&image), &[0, 0, 0, 7]);
+        assert_eq!(header.bss_size, 8);
+        assert!(header.is_position_independent());
+        assert_eq!(header.debug_info(&image), None);
+    }
+
+    #[test]
+    fn test_append_debug_info() {
+        let mut image = PieHeader::build_image(&[0], b"hi\0", &[], 0, 0);
+        PieHeader::append_debug_info(&mut image, &[1, 2, 3]).unwrap();
+        let header = PieHeader::parse(&image).unwrap();
+        assert_eq!(header.debug_offset, 68);
+        assert_eq!(header.debug_info(&image), Some(&[1, 2, 3][..]));
+
+        image.truncate(70);
+        assert_eq!(
+            PieHeader::parse(&image),
+            Err(HeaderError::DebugInfoOutOfBounds {
+                offset: 68,
+                length: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_header_rejects_other_files() {
+        assert_eq!(
+            PieHeader::parse(&[45, 50]),
+            Err(HeaderError::TooShort { length: 2 })
+        );
+        assert_eq!(PieHeader::parse(&[0; 64]), Err(HeaderError::InvalidPrefix));
+
+        let valid = program(&[0, 0, 0, 0]);
+        let mut bad_version = valid.clone();
+        bad_version[VERSION_OFFSET + 1] = 9;
+        assert_eq!(
+            PieHeader::parse(&bad_version),
+            Err(HeaderError::UnsupportedVersion { version: 9 })
+        );
+
+        let mut bad_flags = valid;
+        bad_flags[FLAGS_OFFSET] = 0x80;
+        assert_eq!(
+            PieHeader::parse(&bad_flags),
+            Err(HeaderError::UnknownFlags { flags: 0x8000 })
+        );
+    }
+
+    #[test]
+    fn test_header_sections_must_lie_in_program() {
+        let valid = program(&[0, 0, 0, 0]);
+        let mut bad_entry = valid.clone();
+        bad_entry[ENTRY_POINT_OFFSET + 3] = 10;
+        assert_eq!(
+            PieHeader::parse(&bad_entry),
+            Err(HeaderError::EntryPointOutOfBounds { entry_point: 10 })
+        );
+
+        let mut bad_code_length = valid.clone();
+        bad_code_length[CODE_LENGTH_OFFSET + 3] = 5;
+        assert_eq!(
+            PieHeader::parse(&bad_code_length),
+            Err(HeaderError::CodeLengthOutOfBounds {
+                code_length: 5,
+                program_length: 68
+            })
+        );
+
+        let mut bad_ro = valid.clone();
+        bad_ro[RO_LENGTH_OFFSET + 3] = 1;
+        assert_eq!(
+            PieHeader::parse(&bad_ro),
+            Err(HeaderError::ReadOnlyDataOutOfBounds {
+                offset: 68,
+                length: 1
+            })
+        );
+
+        let mut bad_data = valid;
+        bad_data[DATA_LENGTH_OFFSET + 3] = 1;
+        assert_eq!(
+            PieHeader::parse(&bad_data),
+            Err(HeaderError::DataOutOfBounds {
+                offset: 68,
+                length: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_header_checksum_covers_body() {
+        let mut bad_checksum = program(&[0, 0, 0, 0]);
+        bad_checksum[PIE_HEADER_LENGTH] = 1;
+        match PieHeader::parse(&bad_checksum) {
+            Err(HeaderError::ChecksumMismatch { .. }) => {}
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        }
+    }
+}
@@ -1,14 +1,21 @@
-use crate::assembler::program_parsers::program;
 use crate::assembler::Assembler;
-use crate::scheduler::Scheduler;
+use crate::disassembler::disassemble;
+use crate::scheduler::{Pid, Scheduler};
+use crate::snapshot::Snapshot;
+use crate::symbol_map::SymbolMap;
+use crate::verifier::verify;
 use crate::vm::VM;
 use std;
-use std::fs::read_to_string;
+use std::fs::{read, read_to_string, write};
 use std::io;
 use std::io::Write;
 use std::path::Path;
 
-use nom::types::CompleteStr;
+/// Fuel given to processes spawned from the REPL without a budget of their own. `.schedule`
+/// and `.join` run on the REPL's thread, so a process that never stops would otherwise hang it
+/// before it could be killed.
+pub const REPL_FUEL: u64 = 10_000_000;
+
 pub struct REPL {
     pub command_buffer: Vec<String>,
     pub vm: VM,
@@ -17,6 +24,7 @@ pub struct REPL {
 }
 
 impl REPL {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> REPL {
         REPL {
             vm: VM::new(),
@@ -80,16 +88,44 @@ impl REPL {
                     }
                     println!("End of Program Listing");
                 }
+                ".disassemble" => match disassemble(&self.vm.program) {
+                    Ok(disassembly) => print!("{}", disassembly),
+                    Err(e) => println!("Unable to disassemble program: {}", e),
+                },
+                ".verify" => {
+                    let errors = verify(&self.vm.program);
+                    for error in &errors {
+                        println!("{}", error);
+                    }
+                    println!("Found {} problems", errors.len());
+                }
                 ".ro" => {
                     println!("Listing ro of VM:");
                     println!("{:?}", self.vm.ro_data);
                     println!("End of ro Listing");
                 }
+                ".data" => {
+                    println!("Listing writable data of VM:");
+                    println!("{:?}", self.vm.data);
+                    println!("End of data Listing");
+                }
                 ".symbols" => {
                     println!("Listing symbols of VM:");
-                    println!("{:?}", self.asm.symbols);
+                    match self.vm.symbol_map {
+                        Some(ref symbol_map) => print!("{}", symbol_map.to_text()),
+                        None => print!("{}", self.asm.symbol_map().to_text()),
+                    }
                     println!("End of symbols Listing");
                 }
+                ".load_symbols" => {
+                    let contents = self.get_data_from_load();
+                    if let Some(contents) = contents {
+                        match SymbolMap::from_text(&contents) {
+                            Ok(symbol_map) => self.vm.symbol_map = Some(symbol_map),
+                            Err(e) => println!("Unable to load symbol map: {}", e),
+                        }
+                    }
+                }
                 ".find_symbol" => {
                     let mut b = String::new();
                     let _stdin = io::stdin();
@@ -111,41 +147,125 @@ impl REPL {
                 ".pc" => {
                     println!("{}", self.vm.pc);
                 }
+                ".where" => {
+                    println!("{}", self.vm.describe_location(self.vm.pc));
+                }
                 ".run_once" => {
                     self.vm.run_once();
                 }
                 ".run" => {
-                    self.vm.run();
+                    println!("{}", self.vm.run());
+                }
+                ".resume" => {
+                    println!("{}", self.vm.resume());
+                }
+                ".fuel" => {
+                    let mut b = String::new();
+                    print!("input fuel, or nothing to run without a budget: ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+                    io::stdin()
+                        .read_line(&mut b)
+                        .expect("Unable to read line from user");
+                    match b.trim() {
+                        "" => self.vm.fuel = None,
+                        fuel => match fuel.parse() {
+                            Ok(fuel) => self.vm.fuel = Some(fuel),
+                            Err(e) => println!("Unable to parse fuel: {}", e),
+                        },
+                    }
+                }
+                ".save" => {
+                    let path = self.get_path("Please enter the path to save the VM's state to: ");
+                    match write(&path, self.vm.snapshot().to_bytes()) {
+                        Ok(()) => println!("Saved the VM's state to {}", path),
+                        Err(e) => println!("There was an error writing that file: {:?}", e),
+                    }
+                }
+                ".restore" => {
+                    let path = self.get_path("Please enter the path of the snapshot to restore: ");
+                    let restored = match read(&path) {
+                        Ok(bytes) => Snapshot::from_bytes(&bytes),
+                        Err(e) => {
+                            println!("There was an error opening that file: {:?}", e);
+                            continue;
+                        }
+                    };
+                    match restored {
+                        Ok(snapshot) => {
+                            self.vm = VM::from_snapshot(snapshot);
+                            println!("Restored the VM, go on running it with .resume");
+                        }
+                        Err(e) => println!("Unable to restore snapshot: {}", e),
+                    }
                 }
                 ".load_file" => {
                     let contents = self.get_data_from_load();
                     if let Some(contents) = contents {
-                        let program = match program(CompleteStr(&contents)) {
-                            // Rusts pattern matching is pretty powerful an can even be nested
-                            Ok((_, program)) => program,
-                            Err(e) => {
-                                println!("Unable to parse input: {:?}", e);
-                                continue;
-                            }
-                        };
-                        self.vm
-                            .program
-                            .append(&mut program.to_bytes(&self.asm.symbols));
+                        self.add_code(&contents);
                     } else {
                         continue;
                     }
                 }
+                ".schedule" => {
+                    self.scheduler.run();
+                    self.list_processes();
+                }
+                ".ps" => {
+                    self.list_processes();
+                }
+                ".status" => {
+                    if let Some(pid) = self.get_pid() {
+                        match self.scheduler.process(pid) {
+                            Some(process) => {
+                                println!("State: {:?}", process.state);
+                                println!("Instructions: {}", process.instructions());
+                                println!("Running for: {:?}", process.started.elapsed());
+                                println!("At: {}", process.vm.describe_location(process.vm.pc));
+                                if let Some(ref outcome) = process.outcome {
+                                    println!("Outcome: {}", outcome);
+                                }
+                            }
+                            None => println!("There is no process {}", pid),
+                        }
+                    }
+                }
+                ".join" => {
+                    if let Some(pid) = self.get_pid() {
+                        match self.scheduler.join(pid) {
+                            Ok(outcome) => println!("Process {}: {}", pid, outcome),
+                            Err(e) => println!("Unable to join process: {}", e),
+                        }
+                    }
+                }
+                ".kill" => {
+                    if let Some(pid) = self.get_pid() {
+                        if let Err(e) = self.scheduler.kill(pid) {
+                            println!("Unable to kill process: {}", e);
+                        }
+                    }
+                }
                 ".spawn" => {
                     // Load file
                     let contents = self.get_data_from_load();
                     if let Some(contents) = contents {
                         // Assemble file content
                         match self.asm.assemble(&contents) {
-                            Ok(mut assembled_program) => {
+                            Ok(assembled_program) => {
                                 println!("Sending assembled program to VM");
-                                self.vm.program.append(&mut assembled_program);
+                                if let Err(e) = self.vm.load_program(assembled_program) {
+                                    println!("Unable to load program: {}", e);
+                                    continue;
+                                }
                                 println!("{:#?}", self.vm.program);
-                                self.scheduler.get_thread(self.vm.clone());
+                                let mut vm = self.vm.clone();
+                                let fuel = *vm.fuel.get_or_insert(REPL_FUEL);
+                                match self.scheduler.spawn(vm) {
+                                    Ok(pid) => println!(
+                                        "Spawned process {} with {} fuel, run it with .schedule",
+                                        pid, fuel
+                                    ),
+                                    Err(e) => println!("Unable to spawn process: {}", e),
+                                }
                             }
                             Err(errors) => {
                                 for error in errors {
@@ -159,23 +279,71 @@ impl REPL {
                     }
                 }
                 _ => {
-                    let program = match program(buffer.into()) {
-                        Ok((_, program)) => {
-                            println!("{:?}", program);
-                            program
-                        }
-                        Err(e) => {
-                            println!("Unable to parse input");
-                            println!("{}", e);
-                            continue;
-                        }
-                    };
+                    if self.add_code(buffer) {
+                        self.vm.run_once();
+                    }
+                }
+            }
+        }
+    }
 
-                    self.vm
-                        .program
-                        .append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
+    fn get_path(&mut self, prompt: &str) -> String {
+        let mut b = String::new();
+        print!("{}", prompt);
+        io::stdout().flush().expect("Unable to flush stdout");
+        io::stdin()
+            .read_line(&mut b)
+            .expect("Unable to read line from user");
+        b.trim().to_string()
+    }
+
+    fn get_pid(&mut self) -> Option<Pid> {
+        let mut b = String::new();
+        print!("input PID: ");
+        io::stdout().flush().expect("Unable to flush stdout");
+        io::stdin()
+            .read_line(&mut b)
+            .expect("Unable to read line from user");
+        match b.trim().parse() {
+            Ok(pid) => Some(pid),
+            Err(e) => {
+                println!("Unable to parse PID: {}", e);
+                None
+            }
+        }
+    }
+
+    fn list_processes(&self) {
+        println!("PID STATE INSTRUCTIONS OUTCOME");
+        for (pid, process) in self.scheduler.processes() {
+            let outcome = match process.outcome {
+                Some(ref outcome) => outcome.to_string(),
+                None => "-".to_string(),
+            };
+            println!(
+                "{} {:?} {} {}",
+                pid,
+                process.state,
+                process.instructions(),
+                outcome
+            );
+        }
+    }
+
+    /// Assembles `source` and appends it to the VM's program, extending the symbols of what
+    /// was assembled before. Returns whether it could be assembled.
+    fn add_code(&mut self, source: &str) -> bool {
+        let address = self.vm.program.len() as u32;
+        match self.asm.assemble_more(source, address) {
+            Ok(mut code) => {
+                self.vm.program.append(&mut code);
+                true
+            }
+            Err(errors) => {
+                for error in errors {
+                    println!("Unable to assemble input: {}", error);
                 }
+                false
             }
         }
     }
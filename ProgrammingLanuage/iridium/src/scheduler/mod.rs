@@ -1,22 +1,1100 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::vm::VM;
+use crate::vm::{RunOutcome, Trap, VM};
 
-#[derive(Default)]
+mod pool;
+mod replay;
+mod supervisor;
+
+pub use self::replay::{
+    Entry, Recording, RecordingError, ReplayError, TrapRecord, TrapResult, Turn, TurnEnd,
+};
+pub use self::supervisor::{Strategy, Supervisor, SupervisorId};
+
+/// Fuel a process may use per turn unless the scheduler is configured otherwise
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+/// Identifies a process for as long as it is in the process table
+pub type Pid = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchedulerError {
+    // No process with this PID is in the table
+    NoSuchProcess { pid: Pid },
+    // Every PID up to `max_pid` belongs to a process in the table
+    TooManyProcesses { max_pid: Pid },
+    // Process has already finished
+    AlreadyFinished { pid: Pid },
+    // Process can't finish because no process is ready to run
+    Deadlock { pid: Pid },
+    // No supervisor with this ID was added
+    NoSuchSupervisor { id: SupervisorId },
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchedulerError::NoSuchProcess { pid } => write!(f, "There is no process {}", pid),
+            SchedulerError::TooManyProcesses { max_pid } => write!(
+                f,
+                "All PIDs up to {} are taken, join finished processes to free them",
+                max_pid
+            ),
+            SchedulerError::AlreadyFinished { pid } => {
+                write!(f, "Process {} has already finished", pid)
+            }
+            SchedulerError::Deadlock { pid } => write!(
+                f,
+                "Process {} can't finish because no process is ready to run",
+                pid
+            ),
+            SchedulerError::NoSuchSupervisor { id } => write!(f, "There is no supervisor {}", id),
+        }
+    }
+}
+
+impl Error for SchedulerError {}
+
+/// Where a process is in its life
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProcessState {
+    // Waiting for its turn to run
+    Ready,
+    // Having its turn, its VM is taken out of the process table until the turn ends
+    Running,
+    // Waiting for a message before it can run again
+    Blocked,
+    // Stopped for good, its outcome is recorded
+    Finished,
+}
+
+/// A program run by the scheduler, taking turns with the other processes
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub vm: VM,
+    pub state: ProcessState,
+    /// When the process was spawned
+    pub started: Instant,
+    /// Fuel left of the budget the VM was spawned with, or `None` if it had none
+    budget: Option<u64>,
+    /// Fuel the VM was given for the turn it is having
+    turn_fuel: u64,
+    /// How the program ended, once the process is finished
+    pub outcome: Option<RunOutcome>,
+    /// Words sent to the process that it hasn't received yet, oldest first
+    pub mailbox: VecDeque<i32>,
+    /// The `recv` the process is blocked in
+    receive: Option<Receive>,
+    /// When the process must have finished by, or `None` to let it run for as long as it needs
+    deadline: Option<Instant>,
+    /// Shared with the VM, raised to stop the process from any thread
+    cancel: Arc<AtomicBool>,
+    /// Processes that end along with this one if either ends abnormally
+    links: Vec<Pid>,
+    /// Processes that are sent this one's PID once it finishes
+    monitors: Vec<Pid>,
+    /// The supervisor that restarts the process if it ends abnormally
+    supervisor: Option<SupervisorId>,
+    /// Whether the VM is out of the table for a turn
+    in_turn: bool,
+    /// Whether to take the process out of the table as soon as its turn ends
+    reap: bool,
+    /// What the `recv` the process was blocked in got when it was woken, until its next turn
+    woken: Option<Option<i32>>,
+    /// Index of the turn being recorded, while the process has one
+    turn: usize,
+}
+
+/// A `recv` waiting for a message
+#[derive(Debug, Clone, Copy)]
+struct Receive {
+    register: u8,
+    /// When to give up waiting, or `None` to wait forever
+    deadline: Option<Instant>,
+}
+
+impl Receive {
+    /// A `recv` into `register` that waits for `timeout` milliseconds, or forever if it is
+    /// negative
+    fn new(register: u8, timeout: i32) -> Receive {
+        let deadline = if timeout >= 0 {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
+        Receive { register, deadline }
+    }
+}
+
+impl Process {
+    fn new(mut vm: VM, budget: Option<u64>) -> Process {
+        // A VM cloned from another one would share its flag
+        let cancel = Arc::new(AtomicBool::new(false));
+        vm.cancel = cancel.clone();
+        Process {
+            vm,
+            state: ProcessState::Ready,
+            started: Instant::now(),
+            budget,
+            turn_fuel: 0,
+            outcome: None,
+            mailbox: VecDeque::new(),
+            receive: None,
+            deadline: None,
+            cancel,
+            links: vec![],
+            monitors: vec![],
+            supervisor: None,
+            in_turn: false,
+            reap: false,
+            woken: None,
+            turn: 0,
+        }
+    }
+
+    /// Gives the VM the fuel for a turn of at most `quantum` that ends by `slice_end`
+    fn begin_turn(&mut self, quantum: u64, slice_end: Option<Instant>) {
+        let turn = match self.budget {
+            Some(budget) => budget.min(quantum),
+            None => quantum,
+        };
+        self.vm.fuel = Some(turn);
+        self.vm.deadline = earliest(self.deadline, slice_end);
+        self.turn_fuel = turn;
+    }
+
+    /// Takes back the fuel left of a turn, charging what was used to the budget. Returns how
+    /// much was used.
+    fn end_turn(&mut self) -> u64 {
+        let used = self.turn_fuel - self.vm.fuel.take().unwrap_or(0);
+        if let Some(budget) = self.budget.as_mut() {
+            *budget -= used;
+        }
+        used
+    }
+
+    /// Hands half of the fuel left of the budget to a child spawned during the turn, where
+    /// `vm` is the process's VM. Returns the child's budget, or `None` if there is no budget.
+    fn share_budget(&mut self, vm: &mut VM) -> Option<u64> {
+        let budget = self.budget?;
+        let fuel = vm.fuel.unwrap_or(0);
+        let left = budget - (self.turn_fuel - fuel);
+        let share = left / 2;
+        self.budget = Some(budget - share);
+        // The rest of the turn can't use the fuel the child was given, which isn't charged
+        // as used by the turn since it already came out of the budget
+        let kept = fuel.min(left - share);
+        self.turn_fuel -= fuel - kept;
+        vm.fuel = Some(kept);
+        Some(share)
+    }
+
+    /// Unblocks the process, handing its `recv` the message if one arrived in time
+    fn wake(&mut self, message: Option<i32>) {
+        if let Some(receive) = self.receive.take() {
+            self.vm.complete_receive(receive.register, message);
+            self.state = ProcessState::Ready;
+            self.woken = Some(message);
+        }
+    }
+
+    fn is_past_deadline(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// When a blocked process stops waiting, because its `recv` timed out or its deadline
+    /// passed
+    fn wakes_at(&self) -> Option<Instant> {
+        earliest(self.receive?.deadline, self.deadline)
+    }
+
+    fn finish(&mut self, outcome: RunOutcome) {
+        self.state = ProcessState::Finished;
+        self.outcome = Some(outcome);
+    }
+
+    /// Number of instructions the process has executed
+    pub fn instructions(&self) -> u64 {
+        self.vm.instruction_count
+    }
+}
+
+/// Sets the equal flag of `vm` to whether `spawn` started `child`, and puts its PID in `register`
+fn complete_spawn(vm: &mut VM, register: u8, child: Option<Pid>) {
+    vm.set_equal_flag(child.is_some());
+    if let Some(child) = child {
+        vm.registers[register as usize] = child as i32;
+    }
+}
+
+fn earliest(first: Option<Instant>, second: Option<Instant>) -> Option<Instant> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(first.min(second)),
+        _ => first.or(second),
+    }
+}
+
+/// Runs many VMs as lightweight processes. Each ready process gets a turn of `quantum` fuel,
+/// and gives up the rest of its turn when it yields or its `timeslice` is over. The processes
+/// run on the calling thread, or on a pool of `workers` threads that steal turns from each
+/// other.
+///
+/// Finished processes stay in the process table, keeping their PID, until they are joined.
 pub struct Scheduler {
-    next_pid: u32,
-    max_pid: u32,
+    /// The PID to try first when spawning the next process
+    next_pid: Pid,
+    /// The highest PID handed out, after which PIDs of joined processes are reused
+    max_pid: Pid,
+    /// Fuel each process gets per turn. It should be at least the cost of the most
+    /// expensive instruction, or a process about to execute one never gets further.
+    pub quantum: u64,
+    /// Longest a turn may take, or `None` to only limit turns by fuel. Like deadlines, it is
+    /// checked every few hundred instructions.
+    pub timeslice: Option<Duration>,
+    /// Number of OS threads `run` and `wait` spread the processes over. With one, processes
+    /// run on the calling thread in a predictable order.
+    pub workers: usize,
+    processes: BTreeMap<Pid, Process>,
+    /// Processes waiting for their turn
+    ready: VecDeque<Pid>,
+    supervisors: Vec<Supervisor>,
+    /// Everything that happened that a replay can't work out for itself, while recording
+    recording: Option<Recording>,
+    /// Whether turns are being replayed from a recording rather than run
+    replaying: bool,
+    /// Deadlines of blocked processes, soonest first. Entries of processes that were woken
+    /// by a message are left behind and skipped.
+    timers: BinaryHeap<Reverse<(Instant, Pid)>>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
+        Scheduler::with_max_pid(50000)
+    }
+
+    pub fn with_max_pid(max_pid: Pid) -> Scheduler {
         Scheduler {
             next_pid: 0,
-            max_pid: 50000,
+            max_pid,
+            quantum: DEFAULT_QUANTUM,
+            timeslice: None,
+            workers: 1,
+            processes: BTreeMap::new(),
+            ready: VecDeque::new(),
+            supervisors: vec![],
+            recording: None,
+            replaying: false,
+            timers: BinaryHeap::new(),
         }
     }
 
-    pub fn get_thread(&self, mut vm: VM) -> thread::JoinHandle<u32> {
-        thread::spawn(move || vm.run())
+    /// Adds the program loaded in `vm` as a process and returns its PID. Fuel the VM has
+    /// becomes the budget of the whole process, which finishes once it is used up. Each child
+    /// it spawns is handed half of what is left of it.
+    pub fn spawn(&mut self, vm: VM) -> Result<Pid, SchedulerError> {
+        self.start_process(vm, None)
+    }
+
+    /// Adds a process that goes on from wherever `vm` was stopped, such as a VM restored from
+    /// a snapshot, rather than from the entry point of its program
+    pub fn spawn_restored(&mut self, mut vm: VM) -> Result<Pid, SchedulerError> {
+        let pid = self.allocate_pid()?;
+        let budget = vm.fuel.take();
+        self.processes.insert(pid, Process::new(vm, budget));
+        self.ready.push_back(pid);
+        Ok(pid)
+    }
+
+    /// Adds a process started from the entry point of the program in `vm`, supervised by
+    /// `supervisor` if it is given
+    fn start_process(
+        &mut self,
+        mut vm: VM,
+        supervisor: Option<SupervisorId>,
+    ) -> Result<Pid, SchedulerError> {
+        let pid = self.allocate_pid()?;
+        if let Some(id) = supervisor {
+            self.supervisors[id].children.push((pid, vm.clone()));
+        }
+        let budget = vm.fuel.take();
+        let mut process = Process::new(vm, budget);
+        process.supervisor = supervisor;
+        let failed = process.vm.start();
+        self.processes.insert(pid, process);
+        match failed {
+            Some(outcome) => self.finish(pid, outcome),
+            None => self.ready.push_back(pid),
+        }
+        Ok(pid)
+    }
+
+    /// Finds the first free PID from `next_pid` on, wrapping around after `max_pid`
+    fn allocate_pid(&mut self) -> Result<Pid, SchedulerError> {
+        for _ in 0..=self.max_pid {
+            let pid = self.next_pid;
+            self.next_pid = if pid >= self.max_pid { 0 } else { pid + 1 };
+            if !self.processes.contains_key(&pid) {
+                return Ok(pid);
+            }
+        }
+        Err(SchedulerError::TooManyProcesses {
+            max_pid: self.max_pid,
+        })
+    }
+
+    pub fn process(&self, pid: Pid) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    /// Every process in the table, ordered by PID
+    pub fn processes(&self) -> impl Iterator<Item = (Pid, &Process)> {
+        self.processes.iter().map(|(pid, process)| (*pid, process))
+    }
+
+    /// Gives the next ready process its turn, first waiting for a blocked process to time out
+    /// if none is ready. Returns false if no process was ready or waiting for a timeout.
+    pub fn run_next(&mut self) -> bool {
+        self.wake_timed_out(Instant::now());
+        if self.ready.is_empty() {
+            match self.next_deadline() {
+                Some(deadline) => {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    self.wake_timed_out(deadline);
+                }
+                None => return false,
+            }
+        }
+        let pid = match self.ready.pop_front() {
+            Some(pid) => pid,
+            None => return true,
+        };
+        let mut vm = match self.begin_turn(pid) {
+            Some(vm) => vm,
+            None => return true,
+        };
+        let outcome = loop {
+            match vm.resume() {
+                RunOutcome::Trapped { trap } => {
+                    if !self.handle_trap(pid, &mut vm, trap) {
+                        break None;
+                    }
+                }
+                outcome => break Some(outcome),
+            }
+        };
+        if self.end_turn(pid, vm, outcome) {
+            self.ready.push_back(pid);
+        }
+        true
+    }
+
+    /// Marks `pid` as running and takes its VM out of the table for the turn. Returns `None`
+    /// if the process can't run.
+    fn begin_turn(&mut self, pid: Pid) -> Option<VM> {
+        let process = self.processes.get_mut(&pid)?;
+        if process.state != ProcessState::Ready {
+            return None;
+        }
+        let slice_end = self.timeslice.map(|timeslice| Instant::now() + timeslice);
+        process.begin_turn(self.quantum, slice_end);
+        process.state = ProcessState::Running;
+        process.in_turn = true;
+        let woken = process.woken.take();
+        if let Some(recording) = self.recording.as_mut() {
+            process.turn = recording.begin_turn(pid, woken);
+        }
+        // Messages sent during the turn go to the mailbox, which stays in the table
+        Some(mem::take(&mut process.vm))
+    }
+
+    /// Puts the VM back after a turn that ended with `outcome`, or in a `recv` if there is no
+    /// outcome. Returns whether the process is ready to run again.
+    fn end_turn(&mut self, pid: Pid, vm: VM, outcome: Option<RunOutcome>) -> bool {
+        let process = self.processes.get_mut(&pid).unwrap();
+        process.vm = vm;
+        let used = process.end_turn();
+        process.in_turn = false;
+        let end = match outcome {
+            None => TurnEnd::Blocked,
+            Some(RunOutcome::Yielded) => TurnEnd::Yielded,
+            Some(RunOutcome::OutOfFuel) if process.budget != Some(0) => TurnEnd::Preempted,
+            // The timeslice is over, rather than the process's time
+            Some(RunOutcome::DeadlineExceeded) if !process.is_past_deadline(Instant::now()) => {
+                TurnEnd::Preempted
+            }
+            Some(ref outcome) => TurnEnd::Finished {
+                outcome: outcome.to_string(),
+            },
+        };
+        if let Some(recording) = self.recording.as_mut() {
+            recording.end_turn(process.turn, &process.vm, used, end.clone());
+        }
+        // Ended during its turn because a linked process did
+        if process.state == ProcessState::Finished {
+            if process.reap {
+                self.processes.remove(&pid);
+            }
+            return false;
+        }
+        process.state = ProcessState::Ready;
+        match end {
+            TurnEnd::Blocked => {
+                // A message may have arrived since the `recv` found the mailbox empty
+                if let Some(message) = process.mailbox.pop_front() {
+                    process.wake(Some(message));
+                    return true;
+                }
+                process.state = ProcessState::Blocked;
+                if let Some(deadline) = process.wakes_at() {
+                    self.timers.push(Reverse((deadline, pid)));
+                }
+                false
+            }
+            TurnEnd::Yielded | TurnEnd::Preempted => true,
+            TurnEnd::Finished { .. } => {
+                self.finish(pid, outcome.unwrap());
+                false
+            }
+        }
+    }
+
+    /// Records how `pid` ended and tells the processes that care: monitors are sent its PID,
+    /// linked processes end too if it ended abnormally, and its supervisor may restart it
+    fn finish(&mut self, pid: Pid, outcome: RunOutcome) {
+        let abnormal = outcome.exit_code() != 0;
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) if process.state != ProcessState::Finished => process,
+            _ => return,
+        };
+        if process.state == ProcessState::Ready {
+            self.ready.retain(|ready| *ready != pid);
+        }
+        process.finish(outcome);
+        process.receive = None;
+        // A VM having its turn on a worker stops before its next instruction. A restarted
+        // process is a new one, with a flag of its own.
+        if process.in_turn {
+            process.cancel.store(true, Ordering::SeqCst);
+        }
+        let links = mem::take(&mut process.links);
+        let monitors = mem::take(&mut process.monitors);
+        let supervisor = process.supervisor;
+        for watcher in monitors {
+            let _ = self.send(watcher, pid as i32);
+        }
+        for linked in links {
+            if let Some(process) = self.processes.get_mut(&linked) {
+                process.links.retain(|link| *link != pid);
+            }
+            if abnormal {
+                self.finish(linked, RunOutcome::LinkedExit { pid });
+            }
+        }
+        if let Some(id) = supervisor {
+            self.child_finished(id, pid, abnormal);
+        }
+    }
+
+    /// Takes a finished process out of the table, once its turn is over if it is having one
+    fn reap(&mut self, pid: Pid) {
+        match self.processes.get_mut(&pid) {
+            Some(process) if process.in_turn => process.reap = true,
+            Some(_) => {
+                self.processes.remove(&pid);
+            }
+            None => {}
+        }
+    }
+
+    /// Links two processes, so that if either ends abnormally the other ends too
+    pub fn link(&mut self, first: Pid, second: Pid) -> Result<(), SchedulerError> {
+        for pid in &[first, second] {
+            match self.processes.get(pid) {
+                None => return Err(SchedulerError::NoSuchProcess { pid: *pid }),
+                Some(process) if process.state == ProcessState::Finished => {
+                    return Err(SchedulerError::AlreadyFinished { pid: *pid })
+                }
+                Some(_) => {}
+            }
+        }
+        if first != second {
+            self.processes.get_mut(&first).unwrap().links.push(second);
+            self.processes.get_mut(&second).unwrap().links.push(first);
+        }
+        Ok(())
+    }
+
+    /// Has `watcher` sent the PID of `watched` once it finishes, right away if it already has
+    pub fn monitor(&mut self, watcher: Pid, watched: Pid) -> Result<(), SchedulerError> {
+        if !self.processes.contains_key(&watcher) {
+            return Err(SchedulerError::NoSuchProcess { pid: watcher });
+        }
+        match self.processes.get_mut(&watched) {
+            None => Err(SchedulerError::NoSuchProcess { pid: watched }),
+            Some(process) if process.state == ProcessState::Finished => {
+                self.send(watcher, watched as i32)
+            }
+            Some(process) => {
+                process.monitors.push(watcher);
+                Ok(())
+            }
+        }
+    }
+
+    /// Carries out a request the process `pid` made while running `vm`, returning whether it
+    /// can go on running. A `recv` that has to wait leaves the process to be blocked by
+    /// `end_turn`, so that nothing touches the VM before it is back in the table.
+    fn handle_trap(&mut self, pid: Pid, vm: &mut VM, trap: Trap) -> bool {
+        let instruction = vm.instruction_count;
+        let result = self.carry_out_trap(pid, vm, trap.clone());
+        if let Some(recording) = self.recording.as_mut() {
+            let turn = self.processes[&pid].turn;
+            recording.record_trap(turn, instruction, trap, result);
+        }
+        result != TrapResult::Blocked
+    }
+
+    fn carry_out_trap(&mut self, pid: Pid, vm: &mut VM, trap: Trap) -> TrapResult {
+        match trap {
+            Trap::Send { pid: to, message } => {
+                let delivered = self.send(to as Pid, message).is_ok();
+                vm.set_equal_flag(delivered);
+                TrapResult::Sent { delivered }
+            }
+            Trap::Receive { register, timeout } => {
+                let process = self.processes.get_mut(&pid).unwrap();
+                let message = process.mailbox.pop_front();
+                if message.is_some() || timeout == 0 {
+                    vm.complete_receive(register, message);
+                    return TrapResult::Received { message };
+                }
+                process.receive = Some(Receive::new(register, timeout));
+                TrapResult::Blocked
+            }
+            Trap::SelfPid { register } => {
+                vm.registers[register as usize] = pid as i32;
+                TrapResult::Done
+            }
+            Trap::Spawn { register, entry } => {
+                let child = self.allocate_pid().ok();
+                let mut budget = None;
+                if let Some(child) = child {
+                    budget = self.processes.get_mut(&pid).unwrap().share_budget(vm);
+                    let process = self.child_process(pid, vm, entry, budget);
+                    self.processes.insert(child, process);
+                    self.ready.push_back(child);
+                }
+                complete_spawn(vm, register, child);
+                TrapResult::Spawned { child, budget }
+            }
+        }
+    }
+
+    /// A process running the program of `parent`, whose VM is `vm`, from `entry` with the
+    /// share of its budget it was handed
+    fn child_process(&self, parent: Pid, vm: &VM, entry: usize, budget: Option<u64>) -> Process {
+        // The child gets a copy of the registers, which is how it is passed arguments
+        let mut child_vm = vm.clone();
+        child_vm.pc = entry;
+        child_vm.fuel = None;
+        child_vm.instruction_count = 0;
+        let mut process = Process::new(child_vm, budget);
+        // Spawning can't be used to outlive a deadline or a budget
+        process.deadline = self.processes[&parent].deadline;
+        process
+    }
+
+    /// Puts the word `message` in the mailbox of `pid`, waking it if it is waiting for one
+    pub fn send(&mut self, pid: Pid, message: i32) -> Result<(), SchedulerError> {
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) => process,
+            None => return Err(SchedulerError::NoSuchProcess { pid }),
+        };
+        // What each `recv` got is part of the recording
+        if self.replaying {
+            return Ok(());
+        }
+        match process.state {
+            ProcessState::Finished => Err(SchedulerError::AlreadyFinished { pid }),
+            ProcessState::Blocked => {
+                process.wake(Some(message));
+                self.ready.push_back(pid);
+                Ok(())
+            }
+            ProcessState::Ready | ProcessState::Running => {
+                process.mailbox.push_back(message);
+                Ok(())
+            }
+        }
+    }
+
+    /// The soonest deadline of a process that is still blocked
+    fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(Reverse((deadline, pid))) = self.timers.peek().cloned() {
+            if self.is_waiting_until(pid, deadline) {
+                return Some(deadline);
+            }
+            self.timers.pop();
+        }
+        None
+    }
+
+    /// Wakes the blocked processes whose `recv` timed out at or before `now`, and finishes
+    /// those whose deadline passed
+    fn wake_timed_out(&mut self, now: Instant) {
+        while let Some(Reverse((deadline, pid))) = self.timers.peek().cloned() {
+            if deadline > now {
+                return;
+            }
+            self.timers.pop();
+            if !self.is_waiting_until(pid, deadline) {
+                continue;
+            }
+            let process = self.processes.get_mut(&pid).unwrap();
+            if process.is_past_deadline(now) {
+                if let Some(recording) = self.recording.as_mut() {
+                    recording.entries.push(Entry::PastDeadline { pid });
+                }
+                self.finish(pid, RunOutcome::DeadlineExceeded);
+            } else {
+                process.wake(None);
+                self.ready.push_back(pid);
+            }
+        }
+    }
+
+    fn is_waiting_until(&self, pid: Pid, deadline: Instant) -> bool {
+        self.processes
+            .get(&pid)
+            .filter(|process| process.state == ProcessState::Blocked)
+            .is_some_and(|process| process.wakes_at() == Some(deadline))
+    }
+
+    /// Sets when `pid` must have finished by, after which it ends with
+    /// `RunOutcome::DeadlineExceeded`. `None` lets it run for as long as it needs.
+    pub fn set_deadline(
+        &mut self,
+        pid: Pid,
+        deadline: Option<Instant>,
+    ) -> Result<(), SchedulerError> {
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) => process,
+            None => return Err(SchedulerError::NoSuchProcess { pid }),
+        };
+        if process.state == ProcessState::Finished {
+            return Err(SchedulerError::AlreadyFinished { pid });
+        }
+        process.deadline = deadline;
+        if process.state == ProcessState::Blocked {
+            if let Some(wakes_at) = process.wakes_at() {
+                self.timers.push(Reverse((wakes_at, pid)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The flag that stops `pid` before its next instruction once it is raised, which can be
+    /// done from any thread, even while the process runs on a worker. A process blocked in
+    /// `recv` stops once it wakes. It ends with `RunOutcome::Cancelled`.
+    pub fn cancel_flag(&self, pid: Pid) -> Result<Arc<AtomicBool>, SchedulerError> {
+        match self.processes.get(&pid) {
+            Some(process) => Ok(process.cancel.clone()),
+            None => Err(SchedulerError::NoSuchProcess { pid }),
+        }
+    }
+
+    /// Runs processes until none is ready
+    pub fn run(&mut self) {
+        if self.workers > 1 {
+            pool::run(self, None);
+        } else {
+            while self.run_next() {}
+        }
+    }
+
+    fn is_finished(&self, pid: Pid) -> bool {
+        self.processes
+            .get(&pid)
+            .is_none_or(|process| process.state == ProcessState::Finished)
+    }
+
+    /// Runs processes until `pid` has finished, leaving it in the table
+    pub fn wait(&mut self, pid: Pid) -> Result<&Process, SchedulerError> {
+        if self.workers > 1 {
+            pool::run(self, Some(pid));
+        }
+        loop {
+            match self.processes.get(&pid) {
+                None => return Err(SchedulerError::NoSuchProcess { pid }),
+                Some(process) if process.state == ProcessState::Finished => break,
+                Some(_) => {}
+            }
+            if !self.run_next() {
+                return Err(SchedulerError::Deadlock { pid });
+            }
+        }
+        Ok(&self.processes[&pid])
+    }
+
+    /// Runs processes until `pid` has finished, then takes it out of the table and returns
+    /// how it ended
+    pub fn join(&mut self, pid: Pid) -> Result<RunOutcome, SchedulerError> {
+        self.wait(pid)?;
+        let process = self.processes.remove(&pid).unwrap();
+        Ok(process.outcome.unwrap_or(RunOutcome::Killed))
+    }
+
+    /// Stops a process for good. It stays in the table, so it can still be joined.
+    pub fn kill(&mut self, pid: Pid) -> Result<(), SchedulerError> {
+        let process = match self.processes.get(&pid) {
+            Some(process) => process,
+            None => return Err(SchedulerError::NoSuchProcess { pid }),
+        };
+        if process.state == ProcessState::Finished {
+            return Err(SchedulerError::AlreadyFinished { pid });
+        }
+        if let Some(recording) = self.recording.as_mut() {
+            recording.entries.push(Entry::Killed { pid });
+        }
+        self.finish(pid, RunOutcome::Killed);
+        Ok(())
+    }
+}
+
+/// Assembles `source` and loads it into a new VM, for the tests of the scheduler's modules
+#[cfg(test)]
+pub(crate) fn load(source: &str) -> VM {
+    let mut vm = VM::new();
+    vm.load_program(crate::assembler::Assembler::new().assemble(source).unwrap())
+        .unwrap();
+    vm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNT_FOREVER: &str = r"
+        .code
+        loop: inc $0
+        jmp @loop
+        ";
+
+    #[test]
+    fn test_processes_take_turns() {
+        let mut scheduler = Scheduler::new();
+        scheduler.quantum = 10;
+        let first = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        let second = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        assert!(scheduler.run_next());
+        assert!(scheduler.run_next());
+        // Each turn is 10 instructions: five `inc` and five `jmp`
+        assert_eq!(scheduler.process(first).unwrap().vm.registers[0], 5);
+        assert_eq!(scheduler.process(second).unwrap().vm.registers[0], 5);
+        assert_eq!(scheduler.process(first).unwrap().instructions(), 10);
+        assert_eq!(scheduler.process(first).unwrap().state, ProcessState::Ready);
+    }
+
+    #[test]
+    fn test_yield_ends_turn() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                inc $0
+                yield
+                inc $0
+                ",
+            ))
+            .unwrap();
+        scheduler.run_next();
+        assert_eq!(scheduler.process(pid).unwrap().vm.registers[0], 1);
+        scheduler.run();
+        let process = scheduler.process(pid).unwrap();
+        assert_eq!(process.vm.registers[0], 2);
+        assert_eq!(process.state, ProcessState::Finished);
+        assert_eq!(process.outcome, Some(RunOutcome::Halted));
+    }
+
+    #[test]
+    fn test_budget_finishes_process() {
+        let mut scheduler = Scheduler::new();
+        scheduler.quantum = 4;
+        let mut vm = load(COUNT_FOREVER);
+        vm.fuel = Some(10);
+        let pid = scheduler.spawn(vm).unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::OutOfFuel));
+    }
+
+    #[test]
+    fn test_spawn_shares_budget() {
+        let mut scheduler = Scheduler::new();
+        let mut vm = load(
+            r"
+            .code
+            load $1 @loop
+            spawn $2 $1
+            loop: inc $0
+            jmp @loop
+            ",
+        );
+        vm.fuel = Some(100);
+        let parent = scheduler.spawn(vm).unwrap();
+        scheduler.run();
+        let parent = scheduler.process(parent).unwrap();
+        let child = scheduler.process(parent.vm.registers[2] as Pid).unwrap();
+        assert_eq!(parent.outcome, Some(RunOutcome::OutOfFuel));
+        assert_eq!(child.outcome, Some(RunOutcome::OutOfFuel));
+        // Each count costs two fuel, and the whole budget is 100
+        assert!(child.vm.registers[0] > 0);
+        assert!(parent.vm.registers[0] + child.vm.registers[0] <= 50);
+    }
+
+    #[test]
+    fn test_spawn_bad_program() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn(VM::new()).unwrap();
+        assert!(!scheduler.run_next());
+        assert_eq!(
+            scheduler.process(pid).unwrap().state,
+            ProcessState::Finished
+        );
+    }
+
+    #[test]
+    fn test_join_frees_pid() {
+        let mut scheduler = Scheduler::with_max_pid(1);
+        let exit = r"
+            .code
+            load $0 #4
+            exit $0
+            ";
+        assert_eq!(scheduler.spawn(load(exit)), Ok(0));
+        assert_eq!(scheduler.spawn(load(exit)), Ok(1));
+        assert_eq!(
+            scheduler.spawn(load(exit)),
+            Err(SchedulerError::TooManyProcesses { max_pid: 1 })
+        );
+        assert_eq!(scheduler.join(1), Ok(RunOutcome::Exited { code: 4 }));
+        assert_eq!(
+            scheduler.join(1),
+            Err(SchedulerError::NoSuchProcess { pid: 1 })
+        );
+        assert_eq!(scheduler.spawn(load(exit)), Ok(1));
+        assert_eq!(scheduler.processes().count(), 2);
+    }
+
+    #[test]
+    fn test_spawned_child_sends_to_parent() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                self $5
+                load $1 @child
+                spawn $6 $1
+                load $2 #1000
+                recv $3 $2
+                exit $3
+                child: load $4 #42
+                send $5 $4
+                ",
+            ))
+            .unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 42 }));
+        // The child got the next PID and halted after sending
+        let child = scheduler.process(1).unwrap();
+        assert_eq!(child.outcome, Some(RunOutcome::Halted));
+        assert_eq!(child.vm.registers[5], pid as i32);
+    }
+
+    #[test]
+    fn test_recv_times_out() {
+        let mut scheduler = Scheduler::new();
+        let started = Instant::now();
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                load $2 #20
+                load $3 #7
+                recv $3 $2
+                exit $3
+                ",
+            ))
+            .unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 7 }));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_host_send_wakes_process() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                dec $2
+                recv $3 $2
+                exit $3
+                ",
+            ))
+            .unwrap();
+        assert_eq!(scheduler.join(pid), Err(SchedulerError::Deadlock { pid }));
+        assert_eq!(scheduler.process(pid).unwrap().state, ProcessState::Blocked);
+        assert_eq!(scheduler.send(pid, 5), Ok(()));
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 5 }));
+        assert_eq!(
+            scheduler.send(pid, 5),
+            Err(SchedulerError::NoSuchProcess { pid })
+        );
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        scheduler.run_next();
+        assert_eq!(scheduler.kill(pid), Ok(()));
+        assert_eq!(
+            scheduler.kill(pid),
+            Err(SchedulerError::AlreadyFinished { pid })
+        );
+        assert!(!scheduler.run_next());
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Killed));
+    }
+
+    #[test]
+    fn test_link_ends_partner_on_fault() {
+        let mut scheduler = Scheduler::new();
+        let counting = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        let crashing = scheduler.spawn(load(".code\nigl\n")).unwrap();
+        assert_eq!(scheduler.link(counting, crashing), Ok(()));
+        scheduler.run();
+        assert_eq!(
+            scheduler.join(counting),
+            Ok(RunOutcome::LinkedExit { pid: crashing })
+        );
+    }
+
+    #[test]
+    fn test_link_survives_normal_exit() {
+        let mut scheduler = Scheduler::new();
+        scheduler.quantum = 10;
+        let counting = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        let halting = scheduler.spawn(load(".code\nhlt\n")).unwrap();
+        assert_eq!(scheduler.link(counting, halting), Ok(()));
+        assert_eq!(scheduler.join(halting), Ok(RunOutcome::Halted));
+        assert_eq!(
+            scheduler.process(counting).unwrap().state,
+            ProcessState::Ready
+        );
+        assert_eq!(
+            scheduler.link(counting, halting),
+            Err(SchedulerError::NoSuchProcess { pid: halting })
+        );
+    }
+
+    #[test]
+    fn test_monitor_is_sent_pid() {
+        let mut scheduler = Scheduler::new();
+        let watcher = scheduler
+            .spawn(load(
+                r"
+                .code
+                load $0 #1000
+                recv $1 $0
+                exit $1
+                ",
+            ))
+            .unwrap();
+        let watched = scheduler.spawn(load(".code\nhlt\n")).unwrap();
+        assert_eq!(scheduler.monitor(watcher, watched), Ok(()));
+        assert_eq!(
+            scheduler.join(watcher),
+            Ok(RunOutcome::Exited {
+                code: watched as i32
+            })
+        );
+    }
+
+    #[test]
+    fn test_timeslice_ends_turn() {
+        let mut scheduler = Scheduler::new();
+        scheduler.quantum = u64::MAX;
+        scheduler.timeslice = Some(Duration::from_millis(5));
+        let first = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        let second = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        assert!(scheduler.run_next());
+        assert!(scheduler.run_next());
+        for pid in &[first, second] {
+            let process = scheduler.process(*pid).unwrap();
+            assert_eq!(process.state, ProcessState::Ready);
+            assert!(process.instructions() > 0);
+        }
+    }
+
+    #[test]
+    fn test_deadline_finishes_process() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn(load(COUNT_FOREVER)).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(scheduler.set_deadline(pid, Some(deadline)), Ok(()));
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::DeadlineExceeded));
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn test_deadline_finishes_blocked_process() {
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                load $0 #0
+                dec $0
+                recv $1 $0
+                hlt
+                ",
+            ))
+            .unwrap();
+        scheduler.run_next();
+        assert_eq!(scheduler.process(pid).unwrap().state, ProcessState::Blocked);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(scheduler.set_deadline(pid, Some(deadline)), Ok(()));
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::DeadlineExceeded));
+    }
+
+    #[test]
+    fn test_spawn_restored_goes_on() {
+        let mut vm = load(
+            r"
+            .code
+            load $0 #7
+            yield
+            exit $0
+            ",
+        );
+        assert_eq!(vm.run(), RunOutcome::Yielded);
+        vm.registers[0] = 9;
+        let mut scheduler = Scheduler::new();
+        let pid = scheduler.spawn_restored(vm).unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 9 }));
     }
 }
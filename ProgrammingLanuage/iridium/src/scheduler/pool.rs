@@ -0,0 +1,339 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Pid, Scheduler};
+use crate::vm::{Fault, RunOutcome};
+
+/// Longest an idle worker sleeps before looking for work again
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// What the worker threads of a `run` share. The scheduler is locked only to start and end
+/// turns and to carry out traps; the VMs run outside the lock.
+struct Pool<'a> {
+    scheduler: Mutex<&'a mut Scheduler>,
+    /// Each worker's own processes that are ready to run. A worker takes from the front of its
+    /// own queue and steals from the back of the others'. Processes that become ready some
+    /// other way, by being spawned or sent a message, go to the scheduler's queue.
+    queues: Vec<Mutex<VecDeque<Pid>>>,
+    /// Workers that may be about to run a turn, and so may still make work for the others
+    busy: AtomicUsize,
+    idle: Condvar,
+    /// The process to stop at once it has finished, or `None` to run until nothing is ready
+    until: Option<Pid>,
+    /// Set once `until` has finished, or there is nothing left to run
+    stop: AtomicBool,
+}
+
+/// Runs the scheduler's processes on `workers` threads until `until` has finished, or until
+/// no process is ready or waiting for a timeout. Processes still ready when it returns are
+/// back in the scheduler's queue.
+pub(super) fn run(scheduler: &mut Scheduler, until: Option<Pid>) {
+    let workers = scheduler.workers;
+    let stop = until.is_some_and(|pid| scheduler.is_finished(pid));
+    let pool = Pool {
+        scheduler: Mutex::new(scheduler),
+        queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+        busy: AtomicUsize::new(0),
+        idle: Condvar::new(),
+        until,
+        stop: AtomicBool::new(stop),
+    };
+    thread::scope(|scope| {
+        for worker in 0..workers {
+            let pool = &pool;
+            scope.spawn(move || pool.work(worker));
+        }
+    });
+    let mut scheduler = pool.lock();
+    for queue in &pool.queues {
+        scheduler.ready.extend(queue.lock().unwrap().drain(..));
+    }
+}
+
+impl<'a> Pool<'a> {
+    fn work(&self, worker: usize) {
+        while !self.stop.load(Ordering::SeqCst) {
+            self.busy.fetch_add(1, Ordering::SeqCst);
+            let pid = match self.find_work(worker) {
+                Some(pid) => pid,
+                None => {
+                    self.busy.fetch_sub(1, Ordering::SeqCst);
+                    if self.is_done() {
+                        self.stop.store(true, Ordering::SeqCst);
+                        self.idle.notify_all();
+                        return;
+                    }
+                    let scheduler = self.lock();
+                    let _ = self.idle.wait_timeout(scheduler, IDLE_WAIT).unwrap();
+                    continue;
+                }
+            };
+            if self.run_turn(pid) {
+                self.queues[worker].lock().unwrap().push_back(pid);
+                self.idle.notify_one();
+            }
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Locks the scheduler. A turn that panicked while holding the lock has already ended
+    /// its process, so the lock being poisoned doesn't stop the other workers.
+    fn lock(&self) -> MutexGuard<'_, &'a mut Scheduler> {
+        self.scheduler
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a process from the worker's own queue, then from the scheduler's, and otherwise
+    /// steals one from another worker
+    fn find_work(&self, worker: usize) -> Option<Pid> {
+        if let Some(pid) = self.queues[worker].lock().unwrap().pop_front() {
+            return Some(pid);
+        }
+        {
+            let mut scheduler = self.lock();
+            scheduler.wake_timed_out(Instant::now());
+            if let Some(pid) = scheduler.ready.pop_front() {
+                return Some(pid);
+            }
+        }
+        let workers = self.queues.len();
+        (1..workers)
+            .map(|offset| (worker + offset) % workers)
+            .find_map(|victim| self.queues[victim].lock().unwrap().pop_back())
+    }
+
+    /// Gives `pid` a turn, returning whether it is ready to run again
+    fn run_turn(&self, pid: Pid) -> bool {
+        let mut vm = match self.lock().begin_turn(pid) {
+            Some(vm) => vm,
+            None => return false,
+        };
+        // A panic ends only this process, not the worker and the whole run with it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| loop {
+            match vm.resume() {
+                RunOutcome::Trapped { trap } => {
+                    if !self.lock().handle_trap(pid, &mut vm, trap) {
+                        break None;
+                    }
+                }
+                outcome => break Some(outcome),
+            }
+        }));
+        let outcome = result.unwrap_or_else(|payload| {
+            Some(RunOutcome::Faulted {
+                fault: Fault::Panicked {
+                    message: panic_message(payload),
+                },
+            })
+        });
+        let mut scheduler = self.lock();
+        let ready = scheduler.end_turn(pid, vm, outcome);
+        if self.until == Some(pid) && scheduler.is_finished(pid) {
+            self.stop.store(true, Ordering::SeqCst);
+            self.idle.notify_all();
+        } else if !scheduler.ready.is_empty() {
+            self.idle.notify_all();
+        }
+        ready
+    }
+
+    /// Whether there is nothing left to run and no worker that might make more
+    fn is_done(&self) -> bool {
+        let mut scheduler = self.lock();
+        self.busy.load(Ordering::SeqCst) == 0
+            && scheduler.ready.is_empty()
+            && self
+                .queues
+                .iter()
+                .all(|queue| queue.lock().unwrap().is_empty())
+            && scheduler.next_deadline().is_none()
+    }
+}
+
+/// The message a panic was raised with, if it had one
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::{load, ProcessState, Scheduler};
+    use crate::vm::{Fault, RunOutcome};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    const COUNT_TO_1000: &str = r"
+        .code
+        load $1 #1000
+        loop: inc $0
+        neq $0 $1
+        jeq @loop
+        exit $0
+        ";
+
+    #[test]
+    fn test_workers_run_every_process() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 4;
+        scheduler.quantum = 50;
+        let pids: Vec<_> = (0..20)
+            .map(|_| scheduler.spawn(load(COUNT_TO_1000)).unwrap())
+            .collect();
+        scheduler.run();
+        for pid in pids {
+            assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 1000 }));
+        }
+    }
+
+    #[test]
+    fn test_fault_ends_only_its_process() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 4;
+        scheduler.quantum = 50;
+        let faulting = scheduler
+            .spawn(load(
+                r"
+                .code
+                load $0 #1
+                div $0 $1 $2
+                hlt
+                ",
+            ))
+            .unwrap();
+        let pids: Vec<_> = (0..8)
+            .map(|_| scheduler.spawn(load(COUNT_TO_1000)).unwrap())
+            .collect();
+        scheduler.run();
+        assert!(matches!(
+            scheduler.join(faulting),
+            Ok(RunOutcome::Faulted {
+                fault: Fault::DivideByZero { .. }
+            })
+        ));
+        for pid in pids {
+            assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 1000 }));
+        }
+    }
+
+    #[test]
+    fn test_workers_pass_messages() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 4;
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                self $5
+                load $1 @child
+                spawn $6 $1
+                spawn $6 $1
+                load $2 #1000
+                recv $3 $2
+                recv $4 $2
+                add $3 $4 $3
+                exit $3
+                child: load $4 #21
+                send $5 $4
+                ",
+            ))
+            .unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 42 }));
+    }
+
+    #[test]
+    fn test_linked_exit_stops_process_in_turn() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 2;
+        scheduler.quantum = u64::MAX;
+        let forever = scheduler
+            .spawn(load(
+                r"
+                .code
+                loop: inc $0
+                jmp @loop
+                ",
+            ))
+            .unwrap();
+        let crashing = scheduler
+            .spawn(load(
+                r"
+                .code
+                load $2 #5
+                recv $3 $2
+                div $0 $1 $2
+                ",
+            ))
+            .unwrap();
+        assert_eq!(scheduler.link(forever, crashing), Ok(()));
+        assert!(matches!(
+            scheduler.join(crashing),
+            Ok(RunOutcome::Faulted {
+                fault: Fault::DivideByZero { .. }
+            })
+        ));
+        assert_eq!(
+            scheduler.join(forever),
+            Ok(RunOutcome::LinkedExit { pid: crashing })
+        );
+    }
+
+    #[test]
+    fn test_wait_stops_workers() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 2;
+        let forever = scheduler
+            .spawn(load(
+                r"
+                .code
+                loop: inc $0
+                jmp @loop
+                ",
+            ))
+            .unwrap();
+        let pid = scheduler.spawn(load(COUNT_TO_1000)).unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Exited { code: 1000 }));
+        // The other process is left ready to run, in the scheduler's queue
+        assert_eq!(
+            scheduler.process(forever).unwrap().state,
+            ProcessState::Ready
+        );
+        assert!(scheduler.run_next());
+        assert_eq!(scheduler.kill(forever), Ok(()));
+    }
+
+    #[test]
+    fn test_cancel_from_another_thread() {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = 2;
+        scheduler.quantum = u64::MAX;
+        let pid = scheduler
+            .spawn(load(
+                r"
+                .code
+                loop: inc $0
+                jmp @loop
+                ",
+            ))
+            .unwrap();
+        let cancel = scheduler.cancel_flag(pid).unwrap();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            cancel.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Cancelled));
+        canceller.join().unwrap();
+    }
+}
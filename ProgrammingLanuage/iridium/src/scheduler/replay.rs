@@ -0,0 +1,934 @@
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+use super::{complete_spawn, Pid, ProcessState, Receive, Scheduler};
+use crate::codec::{write_bytes, write_u16, write_u32, write_u64, Reader, Truncated};
+use crate::fuel::CostTable;
+use crate::pie::checksum;
+use crate::vm::{RunOutcome, Trap, VM};
+
+/// Magic bytes at the start of every recording file, `-IRL`
+pub const RECORDING_PREFIX: [u8; 4] = [45, 73, 82, 76];
+/// The version of the recording layout written by this scheduler
+pub const RECORDING_VERSION: u16 = 2;
+
+/// What a trap came to, as far as the program could tell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrapResult {
+    // Nothing the program could tell apart between runs
+    Done,
+    // `send` delivered the message, or found no process to deliver it to
+    Sent {
+        delivered: bool,
+    },
+    // `recv` found a message straight away, or polled and found none
+    Received {
+        message: Option<i32>,
+    },
+    // `recv` had to wait, which ended the turn
+    Blocked,
+    // `spawn` started the child with this PID and share of the budget, or ran out of PIDs
+    Spawned {
+        child: Option<Pid>,
+        budget: Option<u64>,
+    },
+}
+
+/// A trap of a recorded turn
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrapRecord {
+    /// The process's instruction count when it trapped
+    pub instruction: u64,
+    pub trap: Trap,
+    pub result: TrapResult,
+}
+
+/// How a recorded turn ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum TurnEnd {
+    // The program executed `yield`
+    Yielded,
+    // The turn was cut short from outside, by running out of fuel for the turn or its timeslice
+    Preempted,
+    // The program waits in `recv`
+    Blocked,
+    // The process finished, with the outcome described
+    Finished { outcome: String },
+}
+
+impl fmt::Display for TurnEnd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TurnEnd::Yielded => f.write_str("a yield"),
+            TurnEnd::Preempted => f.write_str("the turn being cut short"),
+            TurnEnd::Blocked => f.write_str("a wait for a message"),
+            TurnEnd::Finished { ref outcome } => f.write_str(outcome),
+        }
+    }
+}
+
+/// A turn a process had while recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub pid: Pid,
+    /// What the `recv` the process was blocked in got when it was woken before the turn
+    pub woken: Option<Option<i32>>,
+    pub traps: Vec<TrapRecord>,
+    /// The process's instruction count once the turn was over
+    pub instructions: u64,
+    /// Fuel the turn used, which is charged to the process's budget
+    pub fuel: u64,
+    /// `pc` once the turn was over
+    pub pc: usize,
+    /// Checksum of the registers once the turn was over
+    pub registers: u32,
+    pub end: TurnEnd,
+}
+
+/// Something that happened while recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    // A process had a turn
+    Turn(Turn),
+    // The host killed the process
+    Killed { pid: Pid },
+    // The process ran past its deadline while waiting for a message
+    PastDeadline { pid: Pid },
+}
+
+/// Everything that made a run of the scheduler go the way it did, which the programs can't
+/// work out for themselves: which process ran when and for how many instructions, when a
+/// timeslice or deadline cut it short, and what each `send`, `recv` and `spawn` came to.
+/// Turns are in the order they started, which on a pool of workers is not the order they
+/// ended in; each process only depends on its own turns, so replaying them one after the
+/// other comes to the same.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Recording {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordingError {
+    // File does not start with `RECORDING_PREFIX`
+    InvalidPrefix,
+    // File was written for a different layout
+    UnsupportedVersion { version: u16 },
+    // File ends in the middle of a field
+    Truncated { offset: usize },
+    // Entry, trap or result at `offset` is of a kind this scheduler doesn't know
+    UnknownKind { offset: usize, kind: u8 },
+    // Outcome description is not valid UTF-8
+    InvalidOutcome { offset: usize },
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecordingError::InvalidPrefix => f.write_str("File is not an Iridium recording"),
+            RecordingError::UnsupportedVersion { version } => write!(
+                f,
+                "Recording version {} is not supported, expected version {}",
+                version, RECORDING_VERSION
+            ),
+            RecordingError::Truncated { offset } => {
+                write!(f, "Recording ends unexpectedly at offset {}", offset)
+            }
+            RecordingError::UnknownKind { offset, kind } => {
+                write!(
+                    f,
+                    "Recording has unknown kind {} at offset {}",
+                    kind, offset
+                )
+            }
+            RecordingError::InvalidOutcome { offset } => {
+                write!(f, "Recording has an invalid outcome at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for RecordingError {}
+
+impl From<Truncated> for RecordingError {
+    fn from(error: Truncated) -> RecordingError {
+        RecordingError::Truncated {
+            offset: error.offset,
+        }
+    }
+}
+
+/// Reasons a replay can't reproduce a recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    // Recording has a turn of a process the scheduler doesn't have
+    NoSuchProcess {
+        pid: Pid,
+    },
+    // Recording has a trap result that doesn't fit the trap
+    MismatchedResult {
+        pid: Pid,
+        instruction: u64,
+    },
+    // Process did something other than what was recorded, first at `instruction`
+    Diverged {
+        pid: Pid,
+        instruction: u64,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::NoSuchProcess { pid } => {
+                write!(f, "Recording has turns of process {}, which doesn't exist", pid)
+            }
+            ReplayError::MismatchedResult { pid, instruction } => write!(
+                f,
+                "Recording of process {} has the wrong kind of result for its trap at instruction {}",
+                pid, instruction
+            ),
+            ReplayError::Diverged {
+                pid,
+                instruction,
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "Process {} diverged from the recording at instruction {}: expected {}, found {}",
+                pid, instruction, expected, found
+            ),
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording::default()
+    }
+
+    /// Starts recording a turn of `pid`, returning its index
+    pub(super) fn begin_turn(&mut self, pid: Pid, woken: Option<Option<i32>>) -> usize {
+        self.entries.push(Entry::Turn(Turn {
+            pid,
+            woken,
+            traps: vec![],
+            instructions: 0,
+            fuel: 0,
+            pc: 0,
+            registers: 0,
+            end: TurnEnd::Preempted,
+        }));
+        self.entries.len() - 1
+    }
+
+    pub(super) fn record_trap(
+        &mut self,
+        turn: usize,
+        instruction: u64,
+        trap: Trap,
+        result: TrapResult,
+    ) {
+        if let Entry::Turn(ref mut turn) = self.entries[turn] {
+            turn.traps.push(TrapRecord {
+                instruction,
+                trap,
+                result,
+            });
+        }
+    }
+
+    pub(super) fn end_turn(&mut self, turn: usize, vm: &VM, fuel: u64, end: TurnEnd) {
+        if let Entry::Turn(ref mut turn) = self.entries[turn] {
+            turn.instructions = vm.instruction_count;
+            turn.fuel = fuel;
+            turn.pc = vm.pc;
+            turn.registers = registers_checksum(vm);
+            turn.end = end;
+        }
+    }
+
+    /// Whether `bytes` start like a recording
+    pub fn is_recording(bytes: &[u8]) -> bool {
+        bytes.starts_with(&RECORDING_PREFIX)
+    }
+
+    /// Serializes the recording. All integers are big-endian, addresses are 64 bit, and
+    /// descriptions are prefixed with their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = RECORDING_PREFIX.to_vec();
+        write_u16(&mut out, RECORDING_VERSION);
+        write_u32(&mut out, self.entries.len() as u32);
+        for entry in &self.entries {
+            match *entry {
+                Entry::Turn(ref turn) => {
+                    out.push(0);
+                    write_turn(&mut out, turn);
+                }
+                Entry::Killed { pid } => {
+                    out.push(1);
+                    write_u32(&mut out, pid);
+                }
+                Entry::PastDeadline { pid } => {
+                    out.push(2);
+                    write_u32(&mut out, pid);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Recording, RecordingError> {
+        if !Recording::is_recording(bytes) {
+            return Err(RecordingError::InvalidPrefix);
+        }
+        let mut reader = Reader::new(bytes, RECORDING_PREFIX.len());
+        let version = reader.read_u16()?;
+        if version != RECORDING_VERSION {
+            return Err(RecordingError::UnsupportedVersion { version });
+        }
+        let mut recording = Recording::new();
+        for _ in 0..reader.read_u32()? {
+            let entry = match read_kind(&mut reader, 2)? {
+                0 => Entry::Turn(read_turn(&mut reader)?),
+                1 => Entry::Killed {
+                    pid: reader.read_u32()?,
+                },
+                _ => Entry::PastDeadline {
+                    pid: reader.read_u32()?,
+                },
+            };
+            recording.entries.push(entry);
+        }
+        Ok(recording)
+    }
+}
+
+fn registers_checksum(vm: &VM) -> u32 {
+    let mut bytes = vec![];
+    for register in &vm.registers {
+        write_u32(&mut bytes, *register as u32);
+    }
+    checksum(&bytes)
+}
+
+/// Describes a trap the way replay errors show it
+fn describe_trap(trap: &Trap, instruction: u64) -> String {
+    let action = match *trap {
+        Trap::Send { pid, message } => format!("send {} to process {}", message, pid),
+        Trap::Receive { register, .. } => format!("receive a message into ${}", register),
+        Trap::SelfPid { .. } => "find its PID".to_string(),
+        Trap::Spawn { entry, .. } => format!("spawn a process at offset {:#06x}", entry),
+    };
+    format!("a trap to {} at instruction {}", action, instruction)
+}
+
+/// Describes how a turn ended, with `None` for one that was still going when it was stopped
+fn describe_end(end: Option<&TurnEnd>, instruction: u64) -> String {
+    match end {
+        Some(end) => format!("{} at instruction {}", end, instruction),
+        None => format!("the process still running at instruction {}", instruction),
+    }
+}
+
+/// The outcome a process finished with when its turn was cut short from outside
+fn stopped_outcome(description: &str) -> Option<RunOutcome> {
+    [
+        RunOutcome::OutOfFuel,
+        RunOutcome::DeadlineExceeded,
+        RunOutcome::Cancelled,
+    ]
+    .iter()
+    .find(|outcome| outcome.to_string() == description)
+    .cloned()
+}
+
+impl Scheduler {
+    /// Starts recording what happens from now on, replacing any recording already going
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::new());
+    }
+
+    /// Stops recording and returns what was recorded
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    /// Runs the turns of `recording` again, each for as many instructions as it had, with
+    /// every trap coming to what it came to then. The processes have to be set up the way they
+    /// were when recording started, by spawning the same programs in the same order.
+    ///
+    /// A VM runs the same between traps whatever happens around it, so a process that does
+    /// something other than what was recorded is caught at the first trap or turn end that
+    /// differs, and reported with the first instruction the two runs disagree on.
+    ///
+    /// Messages are not delivered while replaying, since what each `recv` got is recorded, so
+    /// messages that were still waiting in a mailbox when recording stopped are lost.
+    pub fn replay(&mut self, recording: &Recording) -> Result<(), ReplayError> {
+        self.replaying = true;
+        let replayed = recording
+            .entries
+            .iter()
+            .try_for_each(|entry| self.replay_entry(entry));
+        self.replaying = false;
+        // Turns were taken in the recorded order, rather than from the ready queue
+        self.ready = self
+            .processes
+            .iter()
+            .filter(|(_, process)| process.state == ProcessState::Ready)
+            .map(|(pid, _)| *pid)
+            .collect();
+        replayed
+    }
+
+    fn replay_entry(&mut self, entry: &Entry) -> Result<(), ReplayError> {
+        let (pid, outcome) = match *entry {
+            Entry::Turn(ref turn) => return self.replay_turn(turn),
+            Entry::Killed { pid } => (pid, RunOutcome::Killed),
+            Entry::PastDeadline { pid } => (pid, RunOutcome::DeadlineExceeded),
+        };
+        if !self.processes.contains_key(&pid) {
+            return Err(ReplayError::NoSuchProcess { pid });
+        }
+        self.finish(pid, outcome);
+        Ok(())
+    }
+
+    fn replay_turn(&mut self, turn: &Turn) -> Result<(), ReplayError> {
+        let pid = turn.pid;
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) => process,
+            None => return Err(ReplayError::NoSuchProcess { pid }),
+        };
+        // A linked process ended it while the turn was running, which took effect first
+        if process.state == ProcessState::Finished {
+            return Ok(());
+        }
+        if let Some(message) = turn.woken {
+            process.wake(message);
+        }
+        process.woken = None;
+        if process.state != ProcessState::Ready {
+            return Err(ReplayError::Diverged {
+                pid,
+                instruction: process.vm.instruction_count,
+                expected: "a turn".to_string(),
+                found: format!("the process {:?}", process.state),
+            });
+        }
+
+        let mut vm = mem::take(&mut process.vm);
+        // Fuel counts instructions, so that the turn stops where it stopped before
+        let costs = mem::replace(&mut vm.costs, CostTable::uniform(1));
+        vm.deadline = None;
+        let replayed = self.replay_instructions(pid, &mut vm, turn, &costs);
+        vm.costs = costs;
+        vm.fuel = None;
+        let process = self.processes.get_mut(&pid).unwrap();
+        process.vm = vm;
+        if let Some(budget) = process.budget.as_mut() {
+            *budget = budget.saturating_sub(turn.fuel);
+        }
+
+        match replayed? {
+            Some(outcome) => self.finish(pid, outcome),
+            None if turn.end == TurnEnd::Blocked => {
+                process.state = ProcessState::Blocked;
+                if let Some(deadline) = process.wakes_at() {
+                    self.timers.push(std::cmp::Reverse((deadline, pid)));
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Runs the instructions of a turn, returning the outcome to finish the process with if
+    /// the turn finished it
+    fn replay_instructions(
+        &mut self,
+        pid: Pid,
+        vm: &mut VM,
+        turn: &Turn,
+        costs: &CostTable,
+    ) -> Result<Option<RunOutcome>, ReplayError> {
+        let diverged = |instruction: u64, expected: String, found: String| {
+            Err(ReplayError::Diverged {
+                pid,
+                instruction,
+                expected,
+                found,
+            })
+        };
+        let mut traps = turn.traps.iter();
+        let (end, outcome) = loop {
+            vm.fuel = Some(turn.instructions.saturating_sub(vm.instruction_count));
+            let outcome = vm.resume();
+            let instruction = vm.instruction_count;
+            let trap = match outcome {
+                RunOutcome::Trapped { trap } => trap,
+                RunOutcome::OutOfFuel if instruction == turn.instructions => break (None, None),
+                RunOutcome::Yielded => break (Some(TurnEnd::Yielded), None),
+                outcome => {
+                    let end = TurnEnd::Finished {
+                        outcome: outcome.to_string(),
+                    };
+                    break (Some(end), Some(outcome));
+                }
+            };
+            let record = match traps.next() {
+                Some(record) if record.instruction == instruction && record.trap == trap => record,
+                Some(record) => {
+                    return diverged(
+                        record.instruction.min(instruction),
+                        describe_trap(&record.trap, record.instruction),
+                        describe_trap(&trap, instruction),
+                    )
+                }
+                None => {
+                    return diverged(
+                        instruction,
+                        describe_end(Some(&turn.end), turn.instructions),
+                        describe_trap(&trap, instruction),
+                    )
+                }
+            };
+            if !self.replay_trap(pid, vm, record, costs)? {
+                break (Some(TurnEnd::Blocked), None);
+            }
+        };
+
+        let instruction = vm.instruction_count;
+        if let Some(record) = traps.next() {
+            return diverged(
+                record.instruction.min(instruction),
+                describe_trap(&record.trap, record.instruction),
+                describe_end(end.as_ref(), instruction),
+            );
+        }
+        let outcome = match (end, &turn.end) {
+            // Stopped where the recorded turn was stopped from outside
+            (None, TurnEnd::Preempted) => None,
+            (None, TurnEnd::Finished { outcome }) if stopped_outcome(outcome).is_some() => {
+                stopped_outcome(outcome)
+            }
+            (Some(ref end), recorded) if end == recorded && instruction == turn.instructions => {
+                outcome
+            }
+            (end, recorded) => {
+                return diverged(
+                    instruction.min(turn.instructions),
+                    describe_end(Some(recorded), turn.instructions),
+                    describe_end(end.as_ref(), instruction),
+                );
+            }
+        };
+        if vm.pc != turn.pc || registers_checksum(vm) != turn.registers {
+            return diverged(
+                instruction,
+                format!(
+                    "pc {:#06x} and registers with checksum {:#010x}",
+                    turn.pc, turn.registers
+                ),
+                format!(
+                    "pc {:#06x} and registers with checksum {:#010x}",
+                    vm.pc,
+                    registers_checksum(vm)
+                ),
+            );
+        }
+        Ok(outcome)
+    }
+
+    /// Gives a trap the result it had when recording, returning whether the turn goes on
+    fn replay_trap(
+        &mut self,
+        pid: Pid,
+        vm: &mut VM,
+        record: &TrapRecord,
+        costs: &CostTable,
+    ) -> Result<bool, ReplayError> {
+        match (&record.trap, record.result) {
+            (Trap::Send { .. }, TrapResult::Sent { delivered }) => vm.set_equal_flag(delivered),
+            (Trap::Receive { register, .. }, TrapResult::Received { message }) => {
+                vm.complete_receive(*register, message)
+            }
+            (Trap::Receive { register, timeout }, TrapResult::Blocked) => {
+                let process = self.processes.get_mut(&pid).unwrap();
+                process.receive = Some(Receive::new(*register, *timeout));
+                return Ok(false);
+            }
+            (Trap::SelfPid { register }, TrapResult::Done) => {
+                vm.registers[*register as usize] = pid as i32;
+            }
+            (Trap::Spawn { register, entry }, TrapResult::Spawned { child, budget }) => {
+                if let Some(child) = child {
+                    if self.processes.contains_key(&child) {
+                        return Err(ReplayError::Diverged {
+                            pid,
+                            instruction: record.instruction,
+                            expected: format!("PID {} to be free", child),
+                            found: format!("a process {}", child),
+                        });
+                    }
+                    let parent = self.processes.get_mut(&pid).unwrap();
+                    if let (Some(left), Some(share)) = (parent.budget.as_mut(), budget) {
+                        *left = left.saturating_sub(share);
+                    }
+                    let mut process = self.child_process(pid, vm, *entry, budget);
+                    process.vm.costs = costs.clone();
+                    self.processes.insert(child, process);
+                    self.next_pid = if child >= self.max_pid { 0 } else { child + 1 };
+                }
+                complete_spawn(vm, *register, child);
+            }
+            _ => {
+                return Err(ReplayError::MismatchedResult {
+                    pid,
+                    instruction: record.instruction,
+                })
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn write_optional_u32(out: &mut Vec<u8>, value: Option<u32>) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            write_u32(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_optional_u64(out: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            write_u64(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_turn(out: &mut Vec<u8>, turn: &Turn) {
+    write_u32(out, turn.pid);
+    match turn.woken {
+        Some(message) => {
+            out.push(1);
+            write_optional_u32(out, message.map(|message| message as u32));
+        }
+        None => out.push(0),
+    }
+    write_u32(out, turn.traps.len() as u32);
+    for record in &turn.traps {
+        write_u64(out, record.instruction);
+        write_trap(out, &record.trap);
+        write_result(out, record.result);
+    }
+    write_u64(out, turn.instructions);
+    write_u64(out, turn.fuel);
+    write_u64(out, turn.pc as u64);
+    write_u32(out, turn.registers);
+    match turn.end {
+        TurnEnd::Yielded => out.push(0),
+        TurnEnd::Preempted => out.push(1),
+        TurnEnd::Blocked => out.push(2),
+        TurnEnd::Finished { ref outcome } => {
+            out.push(3);
+            write_bytes(out, outcome.as_bytes());
+        }
+    }
+}
+
+fn write_trap(out: &mut Vec<u8>, trap: &Trap) {
+    match *trap {
+        Trap::Send { pid, message } => {
+            out.push(0);
+            write_u32(out, pid as u32);
+            write_u32(out, message as u32);
+        }
+        Trap::Receive { register, timeout } => {
+            out.push(1);
+            out.push(register);
+            write_u32(out, timeout as u32);
+        }
+        Trap::SelfPid { register } => {
+            out.push(2);
+            out.push(register);
+        }
+        Trap::Spawn { register, entry } => {
+            out.push(3);
+            out.push(register);
+            write_u64(out, entry as u64);
+        }
+    }
+}
+
+fn write_result(out: &mut Vec<u8>, result: TrapResult) {
+    match result {
+        TrapResult::Done => out.push(0),
+        TrapResult::Sent { delivered } => {
+            out.push(1);
+            out.push(delivered as u8);
+        }
+        TrapResult::Received { message } => {
+            out.push(2);
+            write_optional_u32(out, message.map(|message| message as u32));
+        }
+        TrapResult::Blocked => out.push(3),
+        TrapResult::Spawned { child, budget } => {
+            out.push(4);
+            write_optional_u32(out, child);
+            write_optional_u64(out, budget);
+        }
+    }
+}
+
+/// Reads a kind byte, which has to be at most `max`
+fn read_kind(reader: &mut Reader<RecordingError>, max: u8) -> Result<u8, RecordingError> {
+    let offset = reader.position();
+    match reader.read_u8()? {
+        kind if kind <= max => Ok(kind),
+        kind => Err(RecordingError::UnknownKind { offset, kind }),
+    }
+}
+
+fn read_optional_u32(reader: &mut Reader<RecordingError>) -> Result<Option<u32>, RecordingError> {
+    match read_kind(reader, 1)? {
+        0 => Ok(None),
+        _ => Ok(Some(reader.read_u32()?)),
+    }
+}
+
+fn read_optional_u64(reader: &mut Reader<RecordingError>) -> Result<Option<u64>, RecordingError> {
+    match read_kind(reader, 1)? {
+        0 => Ok(None),
+        _ => Ok(Some(reader.read_u64()?)),
+    }
+}
+
+fn read_turn(reader: &mut Reader<RecordingError>) -> Result<Turn, RecordingError> {
+    let pid = reader.read_u32()?;
+    let woken = match read_kind(reader, 1)? {
+        0 => None,
+        _ => Some(read_optional_u32(reader)?.map(|message| message as i32)),
+    };
+    let mut traps = vec![];
+    for _ in 0..reader.read_u32()? {
+        traps.push(TrapRecord {
+            instruction: reader.read_u64()?,
+            trap: read_trap(reader)?,
+            result: read_result(reader)?,
+        });
+    }
+    let instructions = reader.read_u64()?;
+    let fuel = reader.read_u64()?;
+    let pc = reader.read_u64()? as usize;
+    let registers = reader.read_u32()?;
+    let end = match read_kind(reader, 3)? {
+        0 => TurnEnd::Yielded,
+        1 => TurnEnd::Preempted,
+        2 => TurnEnd::Blocked,
+        _ => TurnEnd::Finished {
+            outcome: reader.read_string(|offset| RecordingError::InvalidOutcome { offset })?,
+        },
+    };
+    Ok(Turn {
+        pid,
+        woken,
+        traps,
+        instructions,
+        fuel,
+        pc,
+        registers,
+        end,
+    })
+}
+
+fn read_trap(reader: &mut Reader<RecordingError>) -> Result<Trap, RecordingError> {
+    Ok(match read_kind(reader, 3)? {
+        0 => Trap::Send {
+            pid: reader.read_u32()? as i32,
+            message: reader.read_u32()? as i32,
+        },
+        1 => Trap::Receive {
+            register: reader.read_u8()?,
+            timeout: reader.read_u32()? as i32,
+        },
+        2 => Trap::SelfPid {
+            register: reader.read_u8()?,
+        },
+        _ => Trap::Spawn {
+            register: reader.read_u8()?,
+            entry: reader.read_u64()? as usize,
+        },
+    })
+}
+
+fn read_result(reader: &mut Reader<RecordingError>) -> Result<TrapResult, RecordingError> {
+    Ok(match read_kind(reader, 4)? {
+        0 => TrapResult::Done,
+        1 => TrapResult::Sent {
+            delivered: reader.read_u8()? != 0,
+        },
+        2 => TrapResult::Received {
+            message: read_optional_u32(reader)?.map(|message| message as i32),
+        },
+        3 => TrapResult::Blocked,
+        _ => TrapResult::Spawned {
+            child: read_optional_u32(reader)?,
+            budget: read_optional_u64(reader)?,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::load;
+    use std::time::Duration;
+
+    /// Spawns children that race to send their PID back, then waits for a message that may
+    /// or may not come in time, and exits with the sum of what it got
+    const RACE: &str = r"
+        .code
+        self $5
+        load $1 @child
+        spawn $6 $1
+        spawn $6 $1
+        spawn $6 $1
+        load $2 #1000
+        recv $3 $2
+        recv $4 $2
+        add $3 $4 $10
+        recv $3 $2
+        add $3 $10 $10
+        load $2 #1
+        recv $3 $2
+        jeq @got
+        exit $10
+        got: add $3 $10 $10
+        exit $10
+        child: self $7
+        load $8 #0
+        loop: inc $8
+        neq $8 $7
+        jeq @loop
+        send $5 $7
+        ";
+
+    fn record(workers: usize) -> (Recording, RunOutcome) {
+        let mut scheduler = Scheduler::new();
+        scheduler.workers = workers;
+        scheduler.quantum = 50;
+        scheduler.timeslice = Some(Duration::from_millis(1));
+        scheduler.start_recording();
+        let pid = scheduler.spawn(load(RACE)).unwrap();
+        let outcome = scheduler.join(pid).unwrap();
+        (scheduler.stop_recording().unwrap(), outcome)
+    }
+
+    #[test]
+    fn test_replay_reproduces_run() {
+        for workers in &[1, 4] {
+            let (recording, outcome) = record(*workers);
+            let mut scheduler = Scheduler::new();
+            let pid = scheduler.spawn(load(RACE)).unwrap();
+            assert_eq!(scheduler.replay(&recording), Ok(()));
+            assert_eq!(scheduler.join(pid), Ok(outcome));
+        }
+    }
+
+    #[test]
+    fn test_replay_keeps_shared_budgets() {
+        let program = r"
+            .code
+            load $1 @loop
+            spawn $2 $1
+            loop: inc $0
+            jmp @loop
+            ";
+        let run = |recording: Option<&Recording>| {
+            let mut scheduler = Scheduler::new();
+            scheduler.quantum = 50;
+            let mut vm = load(program);
+            vm.fuel = Some(1000);
+            let parent = scheduler.spawn(vm).unwrap();
+            match recording {
+                Some(recording) => scheduler.replay(recording).unwrap(),
+                None => {
+                    scheduler.start_recording();
+                    for _ in 0..3 {
+                        scheduler.run_next();
+                    }
+                }
+            }
+            let recording = scheduler.stop_recording();
+            // What is left of the budgets after the recorded turns decides the counts
+            scheduler.run();
+            let counts = [parent, 1].map(|pid| scheduler.process(pid).unwrap().vm.registers[0]);
+            (recording, counts)
+        };
+        let (recording, counts) = run(None);
+        assert_eq!(run(Some(&recording.unwrap())).1, counts);
+    }
+
+    #[test]
+    fn test_replay_finds_divergence() {
+        let (recording, _) = record(1);
+        let mut scheduler = Scheduler::new();
+        // The parent asks for one child less, which moves the code of the children up, so the
+        // first `spawn` already asks for a different entry
+        scheduler
+            .spawn(load(&RACE.replacen("spawn $6 $1\n", "", 1)))
+            .unwrap();
+        match scheduler.replay(&recording) {
+            Err(ReplayError::Diverged {
+                pid: 0,
+                instruction,
+                ..
+            }) => assert_eq!(instruction, 3),
+            other => panic!("Unexpected replay result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_missing_process() {
+        let (recording, _) = record(1);
+        assert_eq!(
+            Scheduler::new().replay(&recording),
+            Err(ReplayError::NoSuchProcess { pid: 0 })
+        );
+    }
+
+    #[test]
+    fn test_recording_keeps_every_kind_of_entry() {
+        let (mut recording, _) = record(4);
+        recording.entries.push(Entry::Killed { pid: 3 });
+        recording.entries.push(Entry::PastDeadline { pid: 4 });
+        let bytes = recording.to_bytes();
+        assert!(Recording::is_recording(&bytes));
+        assert_eq!(Recording::from_bytes(&bytes), Ok(recording));
+    }
+
+    #[test]
+    fn test_recording_rejects_unknown_entry_kind() {
+        // The kind of the first entry
+        let mut bad_kind = record(1).0.to_bytes();
+        bad_kind[10] = 7;
+        assert_eq!(
+            Recording::from_bytes(&bad_kind),
+            Err(RecordingError::UnknownKind {
+                offset: 10,
+                kind: 7
+            })
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use super::{Pid, Scheduler, SchedulerError};
+use crate::vm::{RunOutcome, VM};
+
+/// Index of a supervisor in the scheduler
+pub type SupervisorId = usize;
+
+/// Which children a supervisor restarts when one of them ends abnormally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // Restart only the child that ended
+    OneForOne,
+    // Kill the other children and restart all of them
+    OneForAll,
+}
+
+/// Restarts the processes it supervises from the start of their program when they end
+/// abnormally. If it has to restart them `max_restarts` times within `period` it gives up,
+/// kills the children it has left and stops supervising, which `has_failed` tells.
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    pub strategy: Strategy,
+    pub max_restarts: usize,
+    pub period: Duration,
+    /// Each running child, with the VM it was started from
+    pub(super) children: Vec<(Pid, VM)>,
+    /// When each restart within the last `period` happened, oldest first
+    restarts: VecDeque<Instant>,
+    failed: bool,
+}
+
+impl Supervisor {
+    pub fn new(strategy: Strategy, max_restarts: usize, period: Duration) -> Supervisor {
+        Supervisor {
+            strategy,
+            max_restarts,
+            period,
+            children: vec![],
+            restarts: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// The PIDs of the children, which change whenever they are restarted
+    pub fn children(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.children.iter().map(|(pid, _)| *pid)
+    }
+
+    /// Whether it gave up after restarting its children too often
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Counts a restart at `now`, returning false if that is one too many
+    fn allow_restart(&mut self, now: Instant) -> bool {
+        while let Some(restart) = self.restarts.front() {
+            if now.duration_since(*restart) < self.period {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+impl Scheduler {
+    pub fn add_supervisor(&mut self, supervisor: Supervisor) -> SupervisorId {
+        self.supervisors.push(supervisor);
+        self.supervisors.len() - 1
+    }
+
+    pub fn supervisor(&self, id: SupervisorId) -> Option<&Supervisor> {
+        self.supervisors.get(id)
+    }
+
+    /// Adds a process like `spawn` does, which the supervisor restarts if it ends abnormally
+    pub fn spawn_supervised(&mut self, id: SupervisorId, vm: VM) -> Result<Pid, SchedulerError> {
+        match self.supervisors.get(id) {
+            None => Err(SchedulerError::NoSuchSupervisor { id }),
+            Some(supervisor) if supervisor.failed => Err(SchedulerError::NoSuchSupervisor { id }),
+            Some(_) => self.start_process(vm, Some(id)),
+        }
+    }
+
+    /// Called once a child of supervisor `id` has finished
+    pub(super) fn child_finished(&mut self, id: SupervisorId, pid: Pid, abnormal: bool) {
+        let supervisor = &mut self.supervisors[id];
+        let index = match supervisor
+            .children
+            .iter()
+            .position(|(child, _)| *child == pid)
+        {
+            Some(index) => index,
+            // Already being restarted or killed along with the others
+            None => return,
+        };
+        if !abnormal {
+            supervisor.children.remove(index);
+            return;
+        }
+        if !supervisor.allow_restart(Instant::now()) {
+            supervisor.failed = true;
+            for (child, _) in mem::take(&mut supervisor.children) {
+                self.finish(child, RunOutcome::Killed);
+            }
+            return;
+        }
+        let restarting = match supervisor.strategy {
+            Strategy::OneForOne => vec![supervisor.children.remove(index)],
+            Strategy::OneForAll => mem::take(&mut supervisor.children),
+        };
+        for (child, _) in &restarting {
+            self.finish(*child, RunOutcome::Killed);
+        }
+        for (child, vm) in restarting {
+            self.reap(child);
+            // Out of PIDs, so the child stays down
+            let _ = self.start_process(vm, Some(id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{load, ProcessState};
+
+    const CRASH: &str = r"
+        .code
+        igl
+        ";
+
+    const WAIT_FOREVER: &str = r"
+        .code
+        load $0 #0
+        dec $0
+        recv $1 $0
+        hlt
+        ";
+
+    #[test]
+    fn test_one_for_one_gives_up() {
+        let mut scheduler = Scheduler::new();
+        let id = scheduler.add_supervisor(Supervisor::new(
+            Strategy::OneForOne,
+            3,
+            Duration::from_secs(60),
+        ));
+        let crashing = scheduler.spawn_supervised(id, load(CRASH)).unwrap();
+        let waiting = scheduler.spawn_supervised(id, load(WAIT_FOREVER)).unwrap();
+        scheduler.run();
+        let supervisor = scheduler.supervisor(id).unwrap();
+        assert!(supervisor.has_failed());
+        assert_eq!(supervisor.children().count(), 0);
+        // Restarted processes are taken out of the table
+        assert!(scheduler.process(crashing).is_none());
+        assert_eq!(scheduler.join(waiting), Ok(RunOutcome::Killed));
+        assert_eq!(
+            scheduler.spawn_supervised(id, load(CRASH)),
+            Err(SchedulerError::NoSuchSupervisor { id })
+        );
+    }
+
+    #[test]
+    fn test_restarts_child_that_divides_by_zero() {
+        let mut scheduler = Scheduler::new();
+        let id = scheduler.add_supervisor(Supervisor::new(
+            Strategy::OneForOne,
+            1,
+            Duration::from_secs(60),
+        ));
+        let pid = scheduler
+            .spawn_supervised(id, load(".code\nload $0 #1\ndiv $0 $1 $2\nhlt\n"))
+            .unwrap();
+        assert!(scheduler.run_next());
+        let children: Vec<_> = scheduler.supervisor(id).unwrap().children().collect();
+        assert_eq!(children.len(), 1);
+        assert_ne!(children[0], pid);
+        assert!(scheduler.process(pid).is_none());
+        assert_eq!(
+            scheduler.process(children[0]).unwrap().state,
+            ProcessState::Ready
+        );
+        assert!(!scheduler.supervisor(id).unwrap().has_failed());
+    }
+
+    #[test]
+    fn test_one_for_all_restarts_siblings() {
+        let mut scheduler = Scheduler::new();
+        let id = scheduler.add_supervisor(Supervisor::new(
+            Strategy::OneForAll,
+            1,
+            Duration::from_secs(60),
+        ));
+        let waiting = scheduler.spawn_supervised(id, load(WAIT_FOREVER)).unwrap();
+        let crashing = scheduler.spawn_supervised(id, load(CRASH)).unwrap();
+        while scheduler.run_next() {
+            if scheduler
+                .supervisor(id)
+                .unwrap()
+                .children()
+                .all(|pid| pid > crashing)
+            {
+                break;
+            }
+        }
+        let children: Vec<_> = scheduler.supervisor(id).unwrap().children().collect();
+        assert_eq!(children.len(), 2);
+        assert!(!children.contains(&waiting));
+        assert!(scheduler.process(waiting).is_none());
+        assert_eq!(
+            scheduler.process(children[0]).unwrap().state,
+            ProcessState::Ready
+        );
+    }
+
+    #[test]
+    fn test_normal_exit_is_not_restarted() {
+        let mut scheduler = Scheduler::new();
+        let id = scheduler.add_supervisor(Supervisor::new(
+            Strategy::OneForOne,
+            1,
+            Duration::from_secs(60),
+        ));
+        let pid = scheduler
+            .spawn_supervised(id, load(".code\nhlt\n"))
+            .unwrap();
+        assert_eq!(scheduler.join(pid), Ok(RunOutcome::Halted));
+        assert_eq!(scheduler.supervisor(id).unwrap().children().count(), 0);
+        assert_eq!(
+            scheduler.spawn_supervised(7, load(CRASH)),
+            Err(SchedulerError::NoSuchSupervisor { id: 7 })
+        );
+    }
+}
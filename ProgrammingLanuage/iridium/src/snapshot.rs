@@ -0,0 +1,355 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::codec::{write_bytes, write_u16, write_u32, write_u64, Reader, Truncated};
+use crate::debug_info::{DebugInfo, DebugInfoError};
+use crate::fuel::CostTable;
+use crate::instruction::Opcode;
+use crate::symbol_map::{SymbolMap, SymbolMapError};
+
+/// Magic bytes at the start of every snapshot file, `-IRS`
+pub const SNAPSHOT_PREFIX: [u8; 4] = [45, 73, 82, 83];
+/// The version of the snapshot layout written by this VM
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    // File does not start with `SNAPSHOT_PREFIX`
+    InvalidPrefix,
+    // File was written for a different layout
+    UnsupportedVersion { version: u16 },
+    // File ends in the middle of a field
+    Truncated { offset: usize },
+    // Code range read at `offset` lies outside the program
+    InvalidCodeRange { offset: usize },
+    // Debug info carried along with the program is broken
+    InvalidDebugInfo { error: DebugInfoError },
+    // Symbol map carried along with the program is broken
+    InvalidSymbolMap { error: SymbolMapError },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::InvalidPrefix => f.write_str("File is not an Iridium snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(
+                f,
+                "Snapshot version {} is not supported, expected version {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated { offset } => {
+                write!(f, "Snapshot ends unexpectedly at offset {}", offset)
+            }
+            SnapshotError::InvalidCodeRange { offset } => write!(
+                f,
+                "Snapshot has a code range outside its program at offset {}",
+                offset
+            ),
+            SnapshotError::InvalidDebugInfo { ref error } => {
+                write!(f, "Snapshot has invalid debug info: {}", error)
+            }
+            SnapshotError::InvalidSymbolMap { ref error } => {
+                write!(f, "Snapshot has an invalid symbol map: {}", error)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<Truncated> for SnapshotError {
+    fn from(error: Truncated) -> SnapshotError {
+        SnapshotError::Truncated {
+            offset: error.offset,
+        }
+    }
+}
+
+/// Everything a VM needs to go on running a program from where it was stopped. The VM has no
+/// call stack: subroutines return through addresses kept in registers, so the registers, `pc`
+/// and the flags are the whole of where the program is. The cancel flag and the deadline
+/// belong to whoever runs the VM and are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [i32; 32],
+    pub pc: usize,
+    pub remainder: u32,
+    pub equal_flag: bool,
+    /// The program image, followed by the code of any modules loaded next to it
+    pub program: Vec<u8>,
+    pub code: Range<usize>,
+    pub modules: Vec<Range<usize>>,
+    pub heap: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub data: Vec<u8>,
+    pub require_verification: bool,
+    pub debug_info: Option<DebugInfo>,
+    pub symbol_map: Option<SymbolMap>,
+    pub fuel: Option<u64>,
+    pub costs: CostTable,
+    pub instruction_count: u64,
+}
+
+impl Snapshot {
+    /// Whether `bytes` start like a snapshot
+    pub fn is_snapshot(bytes: &[u8]) -> bool {
+        bytes.starts_with(&SNAPSHOT_PREFIX)
+    }
+
+    /// Serializes the snapshot. All integers are big-endian, addresses are 64 bit, and byte
+    /// strings are prefixed with their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_PREFIX.to_vec();
+        write_u16(&mut out, SNAPSHOT_VERSION);
+        for register in &self.registers {
+            write_u32(&mut out, *register as u32);
+        }
+        write_u64(&mut out, self.pc as u64);
+        write_u32(&mut out, self.remainder);
+        out.push(self.equal_flag as u8);
+
+        write_bytes(&mut out, &self.program);
+        write_range(&mut out, &self.code);
+        write_u32(&mut out, self.modules.len() as u32);
+        for module in &self.modules {
+            write_range(&mut out, module);
+        }
+        write_bytes(&mut out, &self.heap);
+        write_bytes(&mut out, &self.ro_data);
+        write_bytes(&mut out, &self.data);
+
+        out.push(self.require_verification as u8);
+        let debug_info = self.debug_info.as_ref().map(DebugInfo::to_bytes);
+        write_optional_bytes(&mut out, debug_info.as_deref());
+        let symbol_map = self.symbol_map.as_ref().map(SymbolMap::to_text);
+        write_optional_bytes(&mut out, symbol_map.as_ref().map(String::as_bytes));
+
+        match self.fuel {
+            Some(fuel) => {
+                out.push(1);
+                write_u64(&mut out, fuel);
+            }
+            None => out.push(0),
+        }
+        write_u32(&mut out, Opcode::IGL as u32 + 1);
+        for byte in 0..=Opcode::IGL as u8 {
+            write_u64(&mut out, self.costs.cost(Opcode::from(byte)));
+        }
+        write_u64(&mut out, self.instruction_count);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if !Snapshot::is_snapshot(bytes) {
+            return Err(SnapshotError::InvalidPrefix);
+        }
+        let mut reader = Reader::new(bytes, SNAPSHOT_PREFIX.len());
+        let version = reader.read_u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = reader.read_u32()? as i32;
+        }
+        let pc = reader.read_u64()? as usize;
+        let remainder = reader.read_u32()?;
+        let equal_flag = reader.read_u8()? != 0;
+
+        let program = reader.read_bytes()?.to_vec();
+        let code = read_range(&mut reader, program.len())?;
+        let mut modules = vec![];
+        for _ in 0..reader.read_u32()? {
+            modules.push(read_range(&mut reader, program.len())?);
+        }
+        let heap = reader.read_bytes()?.to_vec();
+        let ro_data = reader.read_bytes()?.to_vec();
+        let data = reader.read_bytes()?.to_vec();
+
+        let require_verification = reader.read_u8()? != 0;
+        let debug_info = match read_optional_bytes(&mut reader)? {
+            Some(bytes) => match DebugInfo::from_bytes(bytes) {
+                Ok(debug_info) => Some(debug_info),
+                Err(error) => return Err(SnapshotError::InvalidDebugInfo { error }),
+            },
+            None => None,
+        };
+        let symbol_map = match read_optional_bytes(&mut reader)? {
+            Some(bytes) => match SymbolMap::from_text(&String::from_utf8_lossy(bytes)) {
+                Ok(symbol_map) => Some(symbol_map),
+                Err(error) => return Err(SnapshotError::InvalidSymbolMap { error }),
+            },
+            None => None,
+        };
+
+        let fuel = match reader.read_u8()? {
+            0 => None,
+            _ => Some(reader.read_u64()?),
+        };
+        let mut costs = CostTable::new();
+        for byte in 0..reader.read_u32()? {
+            let cost = reader.read_u64()?;
+            // Costs of opcodes this VM doesn't know are dropped
+            if byte <= Opcode::IGL as u32 {
+                costs.set_cost(Opcode::from(byte as u8), cost);
+            }
+        }
+        let instruction_count = reader.read_u64()?;
+
+        Ok(Snapshot {
+            registers,
+            pc,
+            remainder,
+            equal_flag,
+            program,
+            code,
+            modules,
+            heap,
+            ro_data,
+            data,
+            require_verification,
+            debug_info,
+            symbol_map,
+            fuel,
+            costs,
+            instruction_count,
+        })
+    }
+}
+
+fn write_optional_bytes(out: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            out.push(1);
+            write_bytes(out, bytes);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_range(out: &mut Vec<u8>, range: &Range<usize>) {
+    write_u64(out, range.start as u64);
+    write_u64(out, range.end as u64);
+}
+
+fn read_optional_bytes<'a>(
+    reader: &mut Reader<'a, SnapshotError>,
+) -> Result<Option<&'a [u8]>, SnapshotError> {
+    match reader.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(reader.read_bytes()?)),
+    }
+}
+
+/// Reads a range of code, which has to lie within a program of `program_len` bytes
+fn read_range(
+    reader: &mut Reader<SnapshotError>,
+    program_len: usize,
+) -> Result<Range<usize>, SnapshotError> {
+    let offset = reader.position();
+    let start = reader.read_u64()?;
+    let end = reader.read_u64()?;
+    if start > end || end > program_len as u64 {
+        return Err(SnapshotError::InvalidCodeRange { offset });
+    }
+    Ok(start as usize..end as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{RunOutcome, VM};
+    use byteorder::{BigEndian, ByteOrder};
+
+    fn stopped_vm() -> VM {
+        let mut vm = VM::new();
+        let program = Assembler::new()
+            .assemble_with_debug_info(
+                r"
+                .data
+                counter: .word #0
+                .code
+                load $1 @counter
+                loop: lw $0 $1
+                inc $0
+                sw $0 $1
+                load $2 #100
+                neq $0 $2
+                jeq @loop
+                exit $0
+                ",
+                "count.iasm",
+            )
+            .unwrap();
+        vm.load_program(program).unwrap();
+        vm.fuel = Some(50);
+        assert_eq!(vm.run(), RunOutcome::OutOfFuel);
+        vm
+    }
+
+    #[test]
+    fn test_snapshot_keeps_stopped_vm_state() {
+        let snapshot = stopped_vm().snapshot();
+        let bytes = snapshot.to_bytes();
+        assert!(Snapshot::is_snapshot(&bytes));
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_restored_vm_goes_on() {
+        let mut vm = stopped_vm();
+        let bytes = vm.snapshot().to_bytes();
+        let mut restored = VM::from_snapshot(Snapshot::from_bytes(&bytes).unwrap());
+        assert_eq!(restored.pc, vm.pc);
+        assert_eq!(restored.instruction_count, vm.instruction_count);
+        assert_eq!(
+            restored.describe_location(restored.pc),
+            vm.describe_location(vm.pc)
+        );
+
+        vm.fuel = None;
+        restored.fuel = None;
+        assert_eq!(vm.resume(), RunOutcome::Exited { code: 100 });
+        assert_eq!(restored.resume(), RunOutcome::Exited { code: 100 });
+        assert_eq!(restored.instruction_count, vm.instruction_count);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_other_and_truncated_files() {
+        assert_eq!(
+            Snapshot::from_bytes(&[0, 1, 2, 3]),
+            Err(SnapshotError::InvalidPrefix)
+        );
+
+        let bytes = stopped_vm().snapshot().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated {
+                offset: bytes.len() - 1
+            })
+        );
+
+        let mut bad_version = bytes;
+        bad_version[5] = 9;
+        assert_eq!(
+            Snapshot::from_bytes(&bad_version),
+            Err(SnapshotError::UnsupportedVersion { version: 9 })
+        );
+    }
+
+    #[test]
+    fn test_snapshot_code_range_must_lie_in_program() {
+        // The end of the code range, right after the program
+        let mut bad_range = stopped_vm().snapshot().to_bytes();
+        let program_len = BigEndian::read_u32(&bad_range[147..]) as usize;
+        let code_range = 151 + program_len;
+        BigEndian::write_u64(&mut bad_range[code_range + 8..], program_len as u64 + 1);
+        assert_eq!(
+            Snapshot::from_bytes(&bad_range),
+            Err(SnapshotError::InvalidCodeRange { offset: code_range })
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::linker::object::ObjectSection;
+
+/// First line of every symbol map
+const SYMBOL_MAP_HEADER: &str = "# Iridium symbol map";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolMapError {
+    // Line does not have the five fields of an entry
+    InvalidLine { line: usize },
+    // Kind is not `code` or `data`
+    UnknownKind { line: usize, kind: String },
+    // Section is not one the assembler knows
+    UnknownSection { line: usize, section: String },
+    // Offset or size is not a number
+    InvalidNumber { line: usize, number: String },
+}
+
+impl fmt::Display for SymbolMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolMapError::InvalidLine { line } => write!(
+                f,
+                "Line {} of the symbol map is not of the form `name kind section offset size`",
+                line
+            ),
+            SymbolMapError::UnknownKind { line, ref kind } => {
+                write!(
+                    f,
+                    "Line {} of the symbol map has unknown kind {}",
+                    line, kind
+                )
+            }
+            SymbolMapError::UnknownSection { line, ref section } => write!(
+                f,
+                "Line {} of the symbol map has unknown section {}",
+                line, section
+            ),
+            SymbolMapError::InvalidNumber { line, ref number } => write!(
+                f,
+                "Line {} of the symbol map has invalid number {}",
+                line, number
+            ),
+        }
+    }
+}
+
+impl Error for SymbolMapError {}
+
+/// What a symbol names
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    // An instruction, addressed like the VM's `pc`
+    Code,
+    // Bytes in the data address space
+    Data,
+}
+
+/// One symbol of a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolMapEntry {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub section: ObjectSection,
+    /// Offset into the program for code, or address in the data address space for data
+    pub offset: u32,
+    /// Number of bytes up to the next symbol of the section, or the end of the section
+    pub size: u32,
+}
+
+/// The names of a program's addresses, which can be written next to the program so tools can
+/// show names without having its source. The text format has one symbol per line:
+///
+/// ```text
+/// # Iridium symbol map
+/// loop code .code 0x0044 5
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SymbolMap {
+    pub entries: Vec<SymbolMapEntry>,
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap::default()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&SymbolMapEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// The closest code symbol at or before `offset`
+    pub fn code_symbol(&self, offset: usize) -> Option<&SymbolMapEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.kind == SymbolKind::Code && entry.offset as usize <= offset)
+            .max_by_key(|entry| entry.offset)
+    }
+
+    /// The data symbol whose bytes include `address`
+    pub fn data_symbol(&self, address: usize) -> Option<&SymbolMapEntry> {
+        self.entries.iter().find(|entry| {
+            entry.kind == SymbolKind::Data
+                && entry.offset as usize <= address
+                && address < (entry.offset + entry.size) as usize
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", SYMBOL_MAP_HEADER).unwrap();
+        writeln!(out, "# NAME KIND SECTION OFFSET SIZE").unwrap();
+        for entry in &self.entries {
+            writeln!(
+                out,
+                "{} {} {} {:#06x} {}",
+                entry.name,
+                kind_name(entry.kind),
+                section_name(entry.section),
+                entry.offset,
+                entry.size
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Reads a symbol map written by `to_text`. Blank lines and lines starting with `#` are skipped.
+    pub fn from_text(text: &str) -> Result<SymbolMap, SymbolMapError> {
+        let mut map = SymbolMap::new();
+        for (index, text) in text.lines().enumerate() {
+            let line = index + 1;
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            if fields.len() != 5 {
+                return Err(SymbolMapError::InvalidLine { line });
+            }
+            let kind = match fields[1] {
+                "code" => SymbolKind::Code,
+                "data" => SymbolKind::Data,
+                kind => {
+                    return Err(SymbolMapError::UnknownKind {
+                        line,
+                        kind: kind.to_string(),
+                    })
+                }
+            };
+            let section = match fields[2] {
+                ".code" => ObjectSection::Code,
+                ".rodata" => ObjectSection::ReadOnlyData,
+                ".data" => ObjectSection::Data,
+                ".bss" => ObjectSection::Bss,
+                section => {
+                    return Err(SymbolMapError::UnknownSection {
+                        line,
+                        section: section.to_string(),
+                    })
+                }
+            };
+            map.entries.push(SymbolMapEntry {
+                name: fields[0].to_string(),
+                kind,
+                section,
+                offset: parse_number(line, fields[3])?,
+                size: parse_number(line, fields[4])?,
+            });
+        }
+        Ok(map)
+    }
+}
+
+fn kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Code => "code",
+        SymbolKind::Data => "data",
+    }
+}
+
+fn section_name(section: ObjectSection) -> &'static str {
+    match section {
+        ObjectSection::Code => ".code",
+        ObjectSection::ReadOnlyData => ".rodata",
+        ObjectSection::Data => ".data",
+        ObjectSection::Bss => ".bss",
+    }
+}
+
+/// Parses a decimal number, or a hexadecimal one starting with `0x`
+fn parse_number(line: usize, number: &str) -> Result<u32, SymbolMapError> {
+    let parsed = match number.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse(),
+    };
+    parsed.map_err(|_| SymbolMapError::InvalidNumber {
+        line,
+        number: number.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol_map() -> SymbolMap {
+        SymbolMap {
+            entries: vec![
+                SymbolMapEntry {
+                    name: "main".to_string(),
+                    kind: SymbolKind::Code,
+                    section: ObjectSection::Code,
+                    offset: 64,
+                    size: 4,
+                },
+                SymbolMapEntry {
+                    name: "loop".to_string(),
+                    kind: SymbolKind::Code,
+                    section: ObjectSection::Code,
+                    offset: 68,
+                    size: 5,
+                },
+                SymbolMapEntry {
+                    name: "counter".to_string(),
+                    kind: SymbolKind::Data,
+                    section: ObjectSection::Data,
+                    offset: 3,
+                    size: 4,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_symbol_map_text_parses_back() {
+        let map = symbol_map();
+        let text = map.to_text();
+        assert!(text.contains("loop code .code 0x0044 5\n"));
+        assert_eq!(SymbolMap::from_text(&text), Ok(map));
+    }
+
+    #[test]
+    fn test_symbol_map_lookups() {
+        let map = symbol_map();
+        assert_eq!(map.code_symbol(70).unwrap().name, "loop");
+        assert_eq!(map.code_symbol(64).unwrap().name, "main");
+        assert_eq!(map.code_symbol(10), None);
+        assert_eq!(map.data_symbol(6).unwrap().name, "counter");
+        assert_eq!(map.data_symbol(7), None);
+        assert_eq!(map.symbol("main").unwrap().offset, 64);
+    }
+
+    #[test]
+    fn test_symbol_map_errors_name_the_line() {
+        assert_eq!(
+            SymbolMap::from_text("# comment\nmain code"),
+            Err(SymbolMapError::InvalidLine { line: 2 })
+        );
+        assert_eq!(
+            SymbolMap::from_text("main func .code 0x40 4"),
+            Err(SymbolMapError::UnknownKind {
+                line: 1,
+                kind: "func".to_string()
+            })
+        );
+        assert_eq!(
+            SymbolMap::from_text("main code .text 0x40 4"),
+            Err(SymbolMapError::UnknownSection {
+                line: 1,
+                section: ".text".to_string()
+            })
+        );
+        assert_eq!(
+            SymbolMap::from_text("main code .code 0xzz 4"),
+            Err(SymbolMapError::InvalidNumber {
+                line: 1,
+                number: "0xzz".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::register_parsers::REGISTER_COUNT;
+use crate::disassembler::{decode_instruction, DisassemblerError, Disassembly, Operand};
+use crate::instruction::Opcode;
+use crate::pie::{HeaderError, PieHeader, PIE_HEADER_LENGTH};
+
+/// Problems found in a program without running it
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifierError {
+    // Program header is missing or wrong
+    InvalidHeader { error: HeaderError },
+    // Code ends in the middle of an instruction
+    TruncatedInstruction { offset: usize },
+    // Byte is not an opcode the VM knows
+    IllegalOpcode { offset: usize, opcode: u8 },
+    // Register operand is not one of the VM's registers
+    RegisterOutOfRange { offset: usize, register: u8 },
+    // Jump leaves the code
+    JumpOutOfCode { offset: usize, target: usize },
+    // Jump lands in the middle of an instruction
+    JumpIntoInstruction { offset: usize, target: usize },
+    // Execution starts in the middle of an instruction
+    EntryPointInInstruction { entry_point: usize },
+    // `prts` address is outside of the data address space
+    StringOutOfBounds { offset: usize, address: usize },
+    // `prts` of read-only data that isn't null-terminated
+    UnterminatedString { offset: usize, address: usize },
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifierError::InvalidHeader { ref error } => {
+                write!(f, "Program header is invalid: {}", error)
+            }
+            VerifierError::TruncatedInstruction { offset } => write!(
+                f,
+                "{:#06x}: code ends in the middle of this instruction",
+                offset
+            ),
+            VerifierError::IllegalOpcode { offset, opcode } => {
+                write!(f, "{:#06x}: illegal opcode {:#04x}", offset, opcode)
+            }
+            VerifierError::RegisterOutOfRange { offset, register } => write!(
+                f,
+                "{:#06x}: register ${} does not exist, there are {} registers",
+                offset, register, REGISTER_COUNT
+            ),
+            VerifierError::JumpOutOfCode { offset, target } => write!(
+                f,
+                "{:#06x}: jump to {:#06x}, which is outside of the code",
+                offset, target
+            ),
+            VerifierError::JumpIntoInstruction { offset, target } => write!(
+                f,
+                "{:#06x}: jump to {:#06x}, which is in the middle of an instruction",
+                offset, target
+            ),
+            VerifierError::EntryPointInInstruction { entry_point } => write!(
+                f,
+                "entry point {:#06x} is in the middle of an instruction",
+                entry_point
+            ),
+            VerifierError::StringOutOfBounds { offset, address } => write!(
+                f,
+                "{:#06x}: prts of address {}, which is outside of the data",
+                offset, address
+            ),
+            VerifierError::UnterminatedString { offset, address } => write!(
+                f,
+                "{:#06x}: prts of address {}, which is not null-terminated",
+                offset, address
+            ),
+        }
+    }
+}
+
+impl Error for VerifierError {}
+
+/// Checks a program with a PIE header before it is run, returning every problem found. An
+/// empty list means the program is safe to run as far as can be told without running it:
+/// jumps through registers that don't hold a constant can't be checked.
+pub fn verify(program: &[u8]) -> Vec<VerifierError> {
+    let header = match PieHeader::parse(program) {
+        Ok(header) => header,
+        Err(error) => return vec![VerifierError::InvalidHeader { error }],
+    };
+    let code = &program[..header.code_end()];
+    let mut errors = vec![];
+
+    // Code before the entry point can still be jumped to, so the whole section is decoded
+    let mut disassembly = Disassembly::default();
+    let mut offset = PIE_HEADER_LENGTH;
+    while offset < code.len() {
+        let instruction = match decode_instruction(code, offset) {
+            Ok(instruction) => instruction,
+            Err(DisassemblerError::TruncatedInstruction { offset }) => {
+                errors.push(VerifierError::TruncatedInstruction { offset });
+                break;
+            }
+            Err(DisassemblerError::InvalidHeader { error }) => {
+                errors.push(VerifierError::InvalidHeader { error });
+                break;
+            }
+        };
+        offset += instruction.byte_len();
+
+        if instruction.opcode == Opcode::IGL {
+            errors.push(VerifierError::IllegalOpcode {
+                offset: instruction.offset,
+                opcode: instruction.raw_opcode,
+            });
+        }
+        for operand in &instruction.operands {
+            if let Operand::Register(register) = *operand {
+                if register as usize >= REGISTER_COUNT {
+                    errors.push(VerifierError::RegisterOutOfRange {
+                        offset: instruction.offset,
+                        register,
+                    });
+                }
+            }
+        }
+        disassembly.instructions.push(instruction);
+    }
+
+    // The header already checked the entry point is within the code, and like a jump it may be
+    // the very end of it
+    let entry_point = header.entry_point as usize;
+    if entry_point < code.len() && !disassembly.is_instruction_start(entry_point) {
+        errors.push(VerifierError::EntryPointInInstruction { entry_point });
+    }
+
+    // Jumping to the very end of the code is allowed, it stops the program
+    for jump in disassembly.jump_targets() {
+        if jump.target < PIE_HEADER_LENGTH || jump.target > code.len() {
+            errors.push(VerifierError::JumpOutOfCode {
+                offset: jump.instruction,
+                target: jump.target,
+            });
+        } else if jump.target < code.len() && !disassembly.is_instruction_start(jump.target) {
+            errors.push(VerifierError::JumpIntoInstruction {
+                offset: jump.instruction,
+                target: jump.target,
+            });
+        }
+    }
+
+    // `prts` reads from the data address space: read-only data, then data, then .bss
+    let ro_data = header.ro_data(program);
+    let data_len = (header.data_length + header.bss_size) as usize;
+    for instruction in &disassembly.instructions {
+        let address = match (instruction.opcode, instruction.operands.first()) {
+            (Opcode::PRTS, Some(Operand::Integer(address))) => *address as usize,
+            _ => continue,
+        };
+        if address >= ro_data.len() + data_len {
+            errors.push(VerifierError::StringOutOfBounds {
+                offset: instruction.offset,
+                address,
+            });
+        } else if address < ro_data.len() && !ro_data[address..].contains(&0) {
+            // Strings in writable data may be terminated at runtime, read-only ones can't be
+            errors.push(VerifierError::UnterminatedString {
+                offset: instruction.offset,
+                address,
+            });
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::pie::PIE_FLAG_POSITION_INDEPENDENT;
+    use crate::vm::prepend_header;
+
+    #[test]
+    fn test_verify_assembled_program() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                r"
+                .rodata
+                hello: .asciiz 'Hello'
+                .code
+                load $0 #3
+                loop: dec $0
+                prts @hello
+                load $1 #0
+                neq $0 $1
+                jeq @loop
+                hlt
+                ",
+            )
+            .unwrap();
+        assert_eq!(verify(&program), vec![]);
+    }
+
+    #[test]
+    fn test_verify_requires_header() {
+        assert_eq!(
+            verify(&[0, 1]),
+            vec![VerifierError::InvalidHeader {
+                error: HeaderError::TooShort { length: 2 }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_verify_reports_every_problem() {
+        let program = prepend_header(vec![
+            200, // igl
+            17, 40, // inc $40
+            1, 0, 0, 66, // load $0 #66
+            6, 0, // jmp $0, into the middle of `inc`
+            23, 0, 100, // br to past the end
+            1,   // truncated load
+        ]);
+        assert_eq!(
+            verify(&program),
+            vec![
+                VerifierError::IllegalOpcode {
+                    offset: 64,
+                    opcode: 200
+                },
+                VerifierError::RegisterOutOfRange {
+                    offset: 65,
+                    register: 40
+                },
+                VerifierError::TruncatedInstruction { offset: 76 },
+                VerifierError::JumpIntoInstruction {
+                    offset: 71,
+                    target: 66
+                },
+                VerifierError::JumpOutOfCode {
+                    offset: 73,
+                    target: 173
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_code_before_entry_point() {
+        let mut program = prepend_header(vec![
+            17, 1, // inc $1
+            0, // hlt
+            1, 0, 0, 64, // load $0 #64, where execution starts
+            6, 0, // jmp $0, back to the `inc` before the entry point
+        ]);
+        let mut header = PieHeader::from_bytes(&program).unwrap();
+        header.entry_point = 67;
+        program[..PIE_HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+        assert_eq!(verify(&program), vec![]);
+
+        header.entry_point = 68;
+        program[..PIE_HEADER_LENGTH].copy_from_slice(&header.to_bytes());
+        assert_eq!(
+            verify(&program),
+            vec![VerifierError::EntryPointInInstruction { entry_point: 68 }]
+        );
+    }
+
+    #[test]
+    fn test_verify_strings() {
+        // prts #1; prts #9
+        let program = PieHeader::build_image(
+            &[19, 0, 1, 19, 0, 9],
+            b"abc",
+            &[],
+            4,
+            PIE_FLAG_POSITION_INDEPENDENT,
+        );
+        assert_eq!(
+            verify(&program),
+            vec![
+                VerifierError::UnterminatedString {
+                    offset: 64,
+                    address: 1
+                },
+                VerifierError::StringOutOfBounds {
+                    offset: 67,
+                    address: 9
+                },
+            ]
+        );
+    }
+}
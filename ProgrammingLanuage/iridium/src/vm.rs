@@ -1,6 +1,191 @@
 use super::instruction::*;
-use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+use crate::debug_info::DebugInfo;
+use crate::fuel::CostTable;
+use crate::pie::{HeaderError, PieHeader, PIE_HEADER_LENGTH};
+use crate::snapshot::Snapshot;
+use crate::symbol_map::SymbolMap;
+use crate::verifier::{verify, VerifierError};
+use std::error::Error;
+use std::fmt;
 use std::num::ParseIntError;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Number of instructions `resume` runs between looking at the clock for the deadline
+const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+/// Faults raised when accessing the data address space
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryError {
+    // Address is past the end of the mapped data
+    OutOfBounds { address: usize },
+    // Address lies within the read-only data
+    ReadOnly { address: usize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::OutOfBounds { address } => {
+                write!(f, "Memory access out of bounds at address {}", address)
+            }
+            MemoryError::ReadOnly { address } => {
+                write!(f, "Attempted to write to read-only memory at address {}", address)
+            }
+        }
+    }
+}
+
+impl Error for MemoryError {}
+
+/// Reasons a program can't go on running
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    // Program header is missing or wrong
+    InvalidHeader { error: HeaderError },
+    // Verification is required and the verifier found problems
+    FailedVerification { errors: Vec<VerifierError> },
+    // Byte at `offset` is not an opcode the VM knows
+    IllegalOpcode { offset: usize, opcode: u8 },
+    // Instruction at `offset` accessed data it may not
+    Memory { offset: usize, error: MemoryError },
+    // Instruction at `offset` divided by zero
+    DivideByZero { offset: usize },
+    // Result of the instruction at `offset` doesn't fit in a register
+    ArithmeticOverflow { offset: usize },
+    // Instruction at `offset` names a register the VM doesn't have
+    InvalidRegister { offset: usize, register: u8 },
+    // Instruction at `offset` runs past the end of the program, or jumps outside of its code
+    PcOutOfBounds { offset: usize },
+    // Instruction at `offset` asked for a negative number of bytes
+    NegativeAllocation { offset: usize, size: i32 },
+    // The VM itself panicked while running the program
+    Panicked { message: String },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::InvalidHeader { ref error } => write!(f, "Header was incorrect: {}", error),
+            Fault::FailedVerification { ref errors } => write!(
+                f,
+                "Program failed verification with {} problems",
+                errors.len()
+            ),
+            Fault::IllegalOpcode { opcode, .. } => {
+                write!(f, "Illegal opcode {:#04x} encountered", opcode)
+            }
+            Fault::Memory { ref error, .. } => write!(f, "{}", error),
+            Fault::DivideByZero { .. } => f.write_str("Division by zero"),
+            Fault::ArithmeticOverflow { .. } => f.write_str("Arithmetic overflow"),
+            Fault::InvalidRegister { register, .. } => {
+                write!(f, "Register ${} does not exist", register)
+            }
+            Fault::PcOutOfBounds { .. } => f.write_str("Program counter left the program"),
+            Fault::NegativeAllocation { size, .. } => {
+                write!(f, "Attempted to allocate {} bytes", size)
+            }
+            Fault::Panicked { ref message } => write!(f, "The VM panicked: {}", message),
+        }
+    }
+}
+
+impl Error for Fault {}
+
+/// Requests a program makes of the scheduler running it. The VM stops with
+/// `RunOutcome::Trapped` so the scheduler can carry one out, after which `resume` goes on
+/// with the next instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    // `send`: deliver the word `message` to the process `pid`. Messages are single words,
+    // since processes don't share a heap a buffer has to be sent one word at a time.
+    Send { pid: i32, message: i32 },
+    // `recv`: wait for a message to put in `register`, for at most `timeout` milliseconds
+    // unless it is negative
+    Receive { register: u8, timeout: i32 },
+    // `self`: put the PID of the running process in `register`
+    SelfPid { register: u8 },
+    // `spawn`: start a process running this program from `entry`, putting its PID in `register`
+    Spawn { register: u8, entry: usize },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trap::Send { pid, .. } => write!(f, "send a message to process {}", pid),
+            Trap::Receive { .. } => f.write_str("receive a message"),
+            Trap::SelfPid { .. } => f.write_str("find its PID"),
+            Trap::Spawn { entry, .. } => write!(f, "spawn a process at offset {:#06x}", entry),
+        }
+    }
+}
+
+/// How a call to `run` ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    // `hlt` was executed, or the program ran off the end of its code
+    Halted,
+    // `exit` was executed with the status in its register
+    Exited { code: i32 },
+    // The program couldn't go on
+    Faulted { fault: Fault },
+    // Fuel ran out before the instruction at `pc`; add fuel and `resume` to go on
+    OutOfFuel,
+    // `yield` was executed; `resume` goes on after it
+    Yielded,
+    // Host code stopped the program for good; the VM itself never returns this
+    Killed,
+    // A linked process ended abnormally, which ended this one too; the VM never returns this
+    LinkedExit { pid: u32 },
+    // The cancel flag was raised before the instruction at `pc`; lower it and `resume` to go on
+    Cancelled,
+    // The deadline passed before the instruction at `pc`
+    DeadlineExceeded,
+    // The program asked for something only a scheduler can do
+    Trapped { trap: Trap },
+}
+
+impl RunOutcome {
+    /// The status a process running the program should exit with: 0 once it halted, the
+    /// program's own status once it exited and 1 when it couldn't finish
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            RunOutcome::Halted => 0,
+            RunOutcome::Exited { code } => code,
+            RunOutcome::Faulted { .. }
+            | RunOutcome::OutOfFuel
+            | RunOutcome::Yielded
+            | RunOutcome::Killed
+            | RunOutcome::LinkedExit { .. }
+            | RunOutcome::Cancelled
+            | RunOutcome::DeadlineExceeded
+            | RunOutcome::Trapped { .. } => 1,
+        }
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunOutcome::Halted => f.write_str("Program halted"),
+            RunOutcome::Exited { code } => write!(f, "Program exited with status {}", code),
+            RunOutcome::Faulted { ref fault } => write!(f, "Program faulted: {}", fault),
+            RunOutcome::OutOfFuel => f.write_str("Program ran out of fuel"),
+            RunOutcome::Yielded => f.write_str("Program yielded"),
+            RunOutcome::Killed => f.write_str("Program was killed"),
+            RunOutcome::LinkedExit { pid } => {
+                write!(f, "Program ended along with linked process {}", pid)
+            }
+            RunOutcome::Cancelled => f.write_str("Program was cancelled"),
+            RunOutcome::DeadlineExceeded => f.write_str("Program ran past its deadline"),
+            RunOutcome::Trapped { ref trap } => {
+                write!(f, "Program needs a scheduler to {}", trap)
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct VM {
@@ -10,21 +195,77 @@ pub struct VM {
     remainder: u32,
     equal_flag: bool,
     heap: Vec<u8>,
+    /// Read-only data, mapped at address 0 of the data address space
     pub ro_data: Vec<u8>,
+    /// Writable data (initialized data followed by the zeroed .bss), mapped right after `ro_data`
+    pub data: Vec<u8>,
+    /// Where the code of the program sits in `program`, set when it is loaded or run
+    code: Range<usize>,
+    /// Where the code of each module loaded with `load_module` sits in `program`
+    modules: Vec<Range<usize>>,
+    /// Refuse to run programs the verifier finds problems with
+    pub require_verification: bool,
+    /// Source locations of the loaded program, if it was assembled with debug info
+    pub debug_info: Option<DebugInfo>,
+    /// Names of the loaded program's addresses, if its symbol map was loaded
+    pub symbol_map: Option<SymbolMap>,
+    /// Fuel left for running instructions, or `None` to run without a budget
+    pub fuel: Option<u64>,
+    /// What each instruction costs when running with a budget
+    pub costs: CostTable,
+    /// Number of instructions executed since the program was started
+    pub instruction_count: u64,
+    /// Raised from any thread to stop the program before its next instruction
+    pub cancel: Arc<AtomicBool>,
+    /// When `resume` gives up on the program, or `None` to let it run for as long as it needs.
+    /// The clock is only read every few hundred instructions.
+    pub deadline: Option<Instant>,
 }
 
-pub fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-    let mut prepension = vec![];
-    for byte in PIE_HEADER_PREFIX.into_iter() {
-        prepension.push(byte.clone());
-    }
-    while prepension.len() < PIE_HEADER_LENGTH {
-        prepension.push(0);
+/// Reasons a module can't be loaded next to the program already in the VM
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModuleError {
+    // Module header is missing or wrong
+    InvalidHeader { error: HeaderError },
+    // Module was not built to run at an arbitrary address
+    NotPositionIndependent,
+    // Module has data sections, whose addresses would clash with the program's
+    HasData,
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModuleError::InvalidHeader { ref error } => {
+                write!(f, "Module header is invalid: {}", error)
+            }
+            ModuleError::NotPositionIndependent => f.write_str(
+                "Module is not position independent and can only run at its own address",
+            ),
+            ModuleError::HasData => f.write_str(
+                "Module has data sections, only code can be loaded next to another program",
+            ),
+        }
     }
+}
+
+fn trap(trap: Trap) -> Option<RunOutcome> {
+    Some(RunOutcome::Trapped { trap })
+}
+
+/// Puts a valid header in front of the code `b`
+pub fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
+    let mut prepension = PieHeader::new(&b, b.len() as u32).to_bytes();
     prepension.append(&mut b);
     prepension
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
         VM {
@@ -35,54 +276,371 @@ impl VM {
             equal_flag: false,
             heap: vec![],
             ro_data: vec![],
+            data: vec![],
+            code: 0..0,
+            modules: vec![],
+            require_verification: false,
+            debug_info: None,
+            symbol_map: None,
+            fuel: None,
+            costs: CostTable::new(),
+            instruction_count: 0,
+            cancel: Arc::new(AtomicBool::new(false)),
+            deadline: None,
+        }
+    }
+
+    /// Maps the data sections of an assembled program into the data address space:
+    /// read-only data first, then initialized data, then `bss_size` zeroed bytes
+    pub fn map_data_sections(&mut self, ro: Vec<u8>, mut data: Vec<u8>, bss_size: usize) {
+        let data_len = data.len();
+        data.resize(data_len + bss_size, 0);
+        self.ro_data = ro;
+        self.data = data;
+    }
+
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryError> {
+        if address < self.ro_data.len() {
+            return Ok(self.ro_data[address]);
+        }
+        match self.data.get(address - self.ro_data.len()) {
+            Some(byte) => Ok(*byte),
+            None => Err(MemoryError::OutOfBounds { address }),
         }
     }
 
-    pub fn verify_header(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
-            return false;
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryError> {
+        if address < self.ro_data.len() {
+            return Err(MemoryError::ReadOnly { address });
         }
-        true
+        match self.data.get_mut(address - self.ro_data.len()) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(MemoryError::OutOfBounds { address }),
+        }
+    }
+
+    /// Reads a big-endian word from the data address space
+    pub fn read_word(&self, address: usize) -> Result<i32, MemoryError> {
+        let mut word = 0u32;
+        for i in 0..4 {
+            word = (word << 8) | self.read_byte(address + i)? as u32;
+        }
+        Ok(word as i32)
+    }
+
+    /// Writes a big-endian word to the data address space. The whole word is checked
+    /// before anything is written, so a faulting store leaves memory untouched.
+    pub fn write_word(&mut self, address: usize, value: i32) -> Result<(), MemoryError> {
+        for i in 0..4 {
+            if address + i < self.ro_data.len() {
+                return Err(MemoryError::ReadOnly { address: address + i });
+            }
+            if address + i >= self.ro_data.len() + self.data.len() {
+                return Err(MemoryError::OutOfBounds { address: address + i });
+            }
+        }
+        for (i, byte) in (value as u32).to_be_bytes().iter().enumerate() {
+            self.write_byte(address + i, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a null-terminated string from the data address space
+    fn read_string(&self, address: usize) -> Result<Vec<u8>, MemoryError> {
+        let mut bytes = vec![];
+        let mut current = address;
+        loop {
+            match self.read_byte(current)? {
+                0 => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+            current += 1;
+        }
+    }
+
+    /// Loads a complete program image, mapping the data sections it carries into the data
+    /// address space. The previous program and data are replaced.
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<PieHeader, HeaderError> {
+        let header = PieHeader::parse(&program)?;
+        self.map_data_sections(
+            header.ro_data(&program).to_vec(),
+            header.data(&program).to_vec(),
+            header.bss_size as usize,
+        );
+        // Broken debug info only makes faults harder to find, the program can still run
+        self.debug_info = match header.debug_info(&program).map(DebugInfo::from_bytes) {
+            Some(Ok(debug_info)) => Some(debug_info),
+            Some(Err(e)) => {
+                println!("Ignoring debug info: {}", e);
+                None
+            }
+            None => None,
+        };
+        self.program = program;
+        self.pc = header.entry_point as usize;
+        self.code = PIE_HEADER_LENGTH..header.code_end();
+        self.modules.clear();
+        Ok(header)
+    }
+
+    /// Appends the code of a position-independent program after everything already in
+    /// `program`, returning the address of its entry point. Jumping there runs the module.
+    pub fn load_module(&mut self, module: &[u8]) -> Result<usize, ModuleError> {
+        let header = match PieHeader::parse(module) {
+            Ok(header) => header,
+            Err(error) => return Err(ModuleError::InvalidHeader { error }),
+        };
+        if !header.is_position_independent() {
+            return Err(ModuleError::NotPositionIndependent);
+        }
+        // Data addresses are absolute, so a module can't bring its own data along
+        if header.ro_length != 0 || header.data_length != 0 || header.bss_size != 0 {
+            return Err(ModuleError::HasData);
+        }
+        let base = self.program.len();
+        self.program
+            .extend_from_slice(&module[PIE_HEADER_LENGTH..header.code_end()]);
+        self.modules.push(base..self.program.len());
+        Ok(base + header.entry_point as usize - PIE_HEADER_LENGTH)
+    }
+
+    /// Describes where `offset` is in the source if the program has debug info. Otherwise the
+    /// offset itself is given, along with the symbol it belongs to if a symbol map was loaded.
+    pub fn describe_location(&self, offset: usize) -> String {
+        if let Some(description) = self
+            .debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.describe(offset))
+        {
+            return description;
+        }
+        match self
+            .symbol_map
+            .as_ref()
+            .and_then(|map| map.code_symbol(offset))
+        {
+            Some(symbol) => format!("offset {:#06x} in `{}`", offset, symbol.name),
+            None => format!("offset {:#06x}", offset),
+        }
+    }
+
+    /// Captures the state of the VM, so that it can be saved and later go on running
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            pc: self.pc,
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+            program: self.program.clone(),
+            code: self.code.clone(),
+            modules: self.modules.clone(),
+            heap: self.heap.clone(),
+            ro_data: self.ro_data.clone(),
+            data: self.data.clone(),
+            require_verification: self.require_verification,
+            debug_info: self.debug_info.clone(),
+            symbol_map: self.symbol_map.clone(),
+            fuel: self.fuel,
+            costs: self.costs.clone(),
+            instruction_count: self.instruction_count,
+        }
+    }
+
+    /// A VM in the state captured by `snapshot`. `resume` goes on where it was stopped.
+    pub fn from_snapshot(snapshot: Snapshot) -> VM {
+        VM {
+            registers: snapshot.registers,
+            pc: snapshot.pc,
+            remainder: snapshot.remainder,
+            equal_flag: snapshot.equal_flag,
+            program: snapshot.program,
+            code: snapshot.code,
+            modules: snapshot.modules,
+            heap: snapshot.heap,
+            ro_data: snapshot.ro_data,
+            data: snapshot.data,
+            require_verification: snapshot.require_verification,
+            debug_info: snapshot.debug_info,
+            symbol_map: snapshot.symbol_map,
+            fuel: snapshot.fuel,
+            costs: snapshot.costs,
+            instruction_count: snapshot.instruction_count,
+            ..VM::new()
+        }
+    }
+
+    /// Checks every field of the program's header, reporting the first one that is wrong
+    pub fn verify_header(&self) -> Result<PieHeader, HeaderError> {
+        PieHeader::parse(&self.program)
     }
 
     pub fn init_registers(&mut self, vec: [i32; 32]) {
         self.registers = vec;
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    /// Reads the next byte of the instruction starting at `start`
+    fn next_8_bits(&mut self, start: usize) -> Result<u8, Fault> {
+        let result = match self.program.get(self.pc) {
+            Some(byte) => *byte,
+            None => return Err(Fault::PcOutOfBounds { offset: start }),
+        };
         self.pc += 1;
-        return result;
+        Ok(result)
+    }
+
+    fn next_16_bits(&mut self, start: usize) -> Result<u16, Fault> {
+        Ok(((self.next_8_bits(start)? as u16) << 8) | self.next_8_bits(start)? as u16)
+    }
+
+    /// Reads a register operand, checking the register exists
+    fn next_register(&mut self, start: usize) -> Result<usize, Fault> {
+        let register = self.next_8_bits(start)?;
+        if register as usize >= self.registers.len() {
+            return Err(Fault::InvalidRegister {
+                offset: start,
+                register,
+            });
+        }
+        Ok(register as usize)
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        return result;
+    /// Reads a register operand and returns the value in it
+    fn next_register_value(&mut self, start: usize) -> Result<i32, Fault> {
+        let register = self.next_register(start)?;
+        Ok(self.registers[register])
     }
 
+    #[allow(clippy::needless_return)]
     fn decode_opcode(&mut self) -> Opcode {
         let opcode = Opcode::from(self.program[self.pc]);
         self.pc += 1;
         return opcode;
     }
 
-    pub fn run(&mut self) -> u32 {
-        if !self.verify_header() {
-            println!("Header was incorrect");
-            1
-        } else {
-            self.pc = 64;
-            let mut is_done = false;
-            while !is_done {
-                is_done = self.execute_instruction();
+    /// Runs the program from its entry point until it stops, and tells how it stopped
+    pub fn run(&mut self) -> RunOutcome {
+        match self.start() {
+            Some(outcome) => outcome,
+            None => self.resume(),
+        }
+    }
+
+    /// Checks the program and points `pc` at its entry point, so that `resume` runs it from
+    /// the start. Returns the fault if the program can't run at all.
+    pub fn start(&mut self) -> Option<RunOutcome> {
+        let header = match self.verify_header() {
+            Ok(header) => header,
+            Err(error) => {
+                println!("Header was incorrect: {}", error);
+                return Some(RunOutcome::Faulted {
+                    fault: Fault::InvalidHeader { error },
+                });
+            }
+        };
+        if self.require_verification {
+            let errors = verify(&self.program);
+            if !errors.is_empty() {
+                for error in &errors {
+                    println!("Program failed verification: {}", error);
+                }
+                return Some(RunOutcome::Faulted {
+                    fault: Fault::FailedVerification { errors },
+                });
             }
-            0
+        }
+        self.pc = header.entry_point as usize;
+        self.code = PIE_HEADER_LENGTH..header.code_end();
+        self.instruction_count = 0;
+        None
+    }
+
+    /// Goes on running from `pc` until the program stops, e.g. after it ran out of fuel or yielded
+    pub fn resume(&mut self) -> RunOutcome {
+        let mut executed: u64 = 0;
+        loop {
+            // The data sections follow the code, running into them is the same as running off the end
+            if !self.is_code() {
+                return RunOutcome::Halted;
+            }
+            if self.cancel.load(Ordering::Relaxed) {
+                return RunOutcome::Cancelled;
+            }
+            if executed.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.is_past_deadline() {
+                return RunOutcome::DeadlineExceeded;
+            }
+            executed += 1;
+            if let Some(outcome) = self.execute_instruction() {
+                return outcome;
+            }
+        }
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether `pc` points into the program's code or the code of a loaded module
+    fn is_code(&self) -> bool {
+        self.code.contains(&self.pc) || self.modules.iter().any(|module| module.contains(&self.pc))
+    }
+
+    /// Checks that the instruction at `start` may jump to `target`: an instruction in the code
+    /// or a loaded module, or the very end of either, which stops the program. Bytes run without
+    /// a header, as the REPL does, are all code.
+    fn jump_target(&self, start: usize, target: usize) -> Result<usize, Fault> {
+        let reaches = |code: &Range<usize>| code.start <= target && target <= code.end;
+        let allowed = if self.code.is_empty() {
+            target <= self.program.len()
+        } else {
+            reaches(&self.code) || self.modules.iter().any(reaches)
+        };
+        if !allowed {
+            return Err(Fault::PcOutOfBounds { offset: start });
+        }
+        Ok(target)
+    }
+
+    /// Gives the program `amount` more fuel. A program running without a budget is unaffected.
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = self.fuel {
+            self.fuel = Some(fuel.saturating_add(amount));
         }
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Reads a pc-relative operand and turns it into an address, counting from the start of
+    /// the instruction at `start`
+    fn next_relative_address(&mut self, start: usize) -> Result<usize, Fault> {
+        let offset = self.next_16_bits(start)? as i16;
+        Ok((start as isize + offset as isize) as usize)
+    }
+
+    /// Executes a single instruction, returning how the program ended if it did
+    pub fn run_once(&mut self) -> Option<RunOutcome> {
+        self.execute_instruction()
+    }
+
+    /// Finishes a `recv` once the scheduler knows whether a message arrived in time: the
+    /// message goes in `register` and the equal flag tells whether there was one
+    pub fn complete_receive(&mut self, register: u8, message: Option<i32>) {
+        if let Some(message) = message {
+            self.registers[register as usize] = message;
+        }
+        self.equal_flag = message.is_some();
+    }
+
+    /// Sets the flag `jeq` and `beq` test, for schedulers reporting whether a request worked
+    pub fn set_equal_flag(&mut self, equal_flag: bool) {
+        self.equal_flag = equal_flag;
+    }
+
+    /// Reports a fault of the instruction at `offset`, which stops the program
+    fn fault(&self, offset: usize, fault: Fault) -> Option<RunOutcome> {
+        println!("Fault at {}: {}", self.describe_location(offset), fault);
+        Some(RunOutcome::Faulted { fault })
     }
 
     pub fn add_hexes(&mut self, i: &str) {
@@ -97,6 +655,7 @@ impl VM {
         }
     }
 
+    #[allow(clippy::needless_borrow)]
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
         let split = i.split(" ").collect::<Vec<&str>>();
 
@@ -119,136 +678,198 @@ impl VM {
         self.program.append(&mut b);
     }
 
-    fn execute_instruction(&mut self) -> bool {
+    /// Executes the instruction at `pc`, returning how the program ended if it did
+    fn execute_instruction(&mut self) -> Option<RunOutcome> {
         if self.pc >= self.program.len() {
-            return true;
+            return Some(RunOutcome::Halted);
+        }
+        if let Some(fuel) = self.fuel {
+            // Nothing is charged for an instruction that doesn't run, so `resume` can retry it
+            let cost = self.costs.cost(Opcode::from(self.program[self.pc]));
+            if fuel < cost {
+                return Some(RunOutcome::OutOfFuel);
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        self.instruction_count += 1;
+        let start = self.pc;
+        match self.execute_opcode(start) {
+            Ok(outcome) => outcome,
+            Err(fault) => self.fault(start, fault),
         }
+    }
+
+    /// Decodes and carries out the instruction starting at `start`
+    #[allow(clippy::needless_bool_assign, clippy::print_with_newline)]
+    fn execute_opcode(&mut self, start: usize) -> Result<Option<RunOutcome>, Fault> {
+        let overflow = Fault::ArithmeticOverflow { offset: start };
         match self.decode_opcode() {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u32;
+                let register = self.next_register(start)?;
+                let number = self.next_16_bits(start)? as u32;
                 self.registers[register] = number as i32;
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
+                let result = register1.checked_add(register2).ok_or(overflow)?;
+                self.registers[self.next_register(start)?] = result;
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
+                let result = register1.checked_sub(register2).ok_or(overflow)?;
+                self.registers[self.next_register(start)?] = result;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
+                let result = register1.checked_mul(register2).ok_or(overflow)?;
+                self.registers[self.next_register(start)?] = result;
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
+                if register2 == 0 {
+                    return Err(Fault::DivideByZero { offset: start });
+                }
+                let result = register1.checked_div(register2).ok_or(overflow.clone())?;
+                self.registers[self.next_register(start)?] = result;
+                self.remainder = register1.checked_rem(register2).ok_or(overflow)? as u32;
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let target = self.next_register_value(start)?;
+                self.pc = self.jump_target(start, target as usize)?;
             }
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc += value as usize;
+                let value = self.next_register_value(start)?;
+                let target = self
+                    .pc
+                    .checked_add(value as usize)
+                    .ok_or(Fault::PcOutOfBounds { offset: start })?;
+                self.pc = self.jump_target(start, target)?;
             }
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize];
-                self.pc -= value as usize;
+                let value = self.next_register_value(start)?;
+                let target = self
+                    .pc
+                    .checked_sub(value as usize)
+                    .ok_or(Fault::PcOutOfBounds { offset: start })?;
+                self.pc = self.jump_target(start, target)?;
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 == register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 == register2 {
                     self.equal_flag = false;
                 } else {
                     self.equal_flag = true;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 > register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 < register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 >= register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.next_register_value(start)?;
+                let register2 = self.next_register_value(start)?;
                 if register1 <= register2 {
                     self.equal_flag = true;
                 } else {
                     self.equal_flag = false;
                 }
-                self.next_8_bits();
+                self.next_8_bits(start)?;
             }
             Opcode::JEQ => {
-                let value = self.registers[self.next_8_bits() as usize];
+                let value = self.next_register_value(start)?;
+                if self.equal_flag {
+                    self.pc = self.jump_target(start, value as usize)?;
+                }
+            }
+            Opcode::LEA => {
+                let register = self.next_register(start)?;
+                self.registers[register] = self.next_relative_address(start)? as i32;
+            }
+            Opcode::BR => {
+                let target = self.next_relative_address(start)?;
+                self.pc = self.jump_target(start, target)?;
+            }
+            Opcode::BEQ => {
+                let target = self.next_relative_address(start)?;
                 if self.equal_flag {
-                    self.pc = value as usize;
+                    self.pc = self.jump_target(start, target)?;
                 }
             }
             Opcode::ALOC => {
-                let bytes = self.registers[self.next_8_bits() as usize];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+                let bytes = self.next_register_value(start)?;
+                if bytes < 0 {
+                    return Err(Fault::NegativeAllocation {
+                        offset: start,
+                        size: bytes,
+                    });
+                }
+                let new_end = self.heap.len() + bytes as usize;
+                self.heap.resize(new_end, 0);
             }
             Opcode::INC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                let register = self.next_register(start)?;
+                self.registers[register] =
+                    self.registers[register].checked_add(1).ok_or(overflow)?;
             }
             Opcode::DEC => {
-                let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+                let register = self.next_register(start)?;
+                self.registers[register] =
+                    self.registers[register].checked_sub(1).ok_or(overflow)?;
             }
             Opcode::PRTS => {
-                let starting_offset = self.next_16_bits() as usize;
-                let mut ending_offset = starting_offset;
-                let slice = self.ro_data.as_slice();
-                while slice[ending_offset] != 0 {
-                    ending_offset += 1;
-                }
-                let result = std::str::from_utf8(&slice[starting_offset..ending_offset]);
+                let starting_offset = self.next_16_bits(start)? as usize;
+                let bytes = match self.read_string(starting_offset) {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        return Err(Fault::Memory {
+                            offset: start,
+                            error,
+                        });
+                    }
+                };
+                let result = std::str::from_utf8(&bytes);
                 match result {
                     Ok(s) => {
                         print!("{}\n", s);
@@ -256,22 +877,76 @@ impl VM {
                     Err(e) => println!("Error decoding string for prts instruction: {:#?}", e),
                 };
             }
+            Opcode::LW => {
+                let register = self.next_register(start)?;
+                let address = self.next_register_value(start)?;
+                match self.read_word(address as usize) {
+                    Ok(value) => self.registers[register] = value,
+                    Err(error) => {
+                        return Err(Fault::Memory {
+                            offset: start,
+                            error,
+                        });
+                    }
+                }
+            }
+            Opcode::SW => {
+                let value = self.next_register_value(start)?;
+                let address = self.next_register_value(start)?;
+                if let Err(error) = self.write_word(address as usize, value) {
+                    return Err(Fault::Memory {
+                        offset: start,
+                        error,
+                    });
+                }
+            }
+            Opcode::EXIT => {
+                let code = self.next_register_value(start)?;
+                return Ok(Some(RunOutcome::Exited { code }));
+            }
+            Opcode::YIELD => {
+                return Ok(Some(RunOutcome::Yielded));
+            }
+            Opcode::SEND => {
+                let pid = self.next_register_value(start)?;
+                let message = self.next_register_value(start)?;
+                return Ok(trap(Trap::Send { pid, message }));
+            }
+            Opcode::RECV => {
+                let register = self.next_register(start)? as u8;
+                let timeout = self.next_register_value(start)?;
+                return Ok(trap(Trap::Receive { register, timeout }));
+            }
+            Opcode::SELF => {
+                let register = self.next_register(start)? as u8;
+                return Ok(trap(Trap::SelfPid { register }));
+            }
+            Opcode::SPAWN => {
+                let register = self.next_register(start)? as u8;
+                let entry = self.next_register_value(start)? as usize;
+                self.jump_target(start, entry)?;
+                return Ok(trap(Trap::Spawn { register, entry }));
+            }
             Opcode::HLT => {
                 println!("HLT encountered");
-                return true;
+                return Ok(Some(RunOutcome::Halted));
             }
             Opcode::IGL => {
-                println!("IGL encountered");
-                return true;
+                return Err(Fault::IllegalOpcode {
+                    offset: start,
+                    opcode: self.program[start],
+                });
             }
         }
-        false
+        Ok(None)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use crate::pie::PIE_FLAG_POSITION_INDEPENDENT;
 
     #[test]
     fn test_create_vm() {
@@ -299,6 +974,156 @@ mod tests {
         assert_eq!(test_vm.pc, 1 + PIE_HEADER_LENGTH);
     }
 
+    #[test]
+    fn test_run_rejects_bad_header() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![1, 0, 0, 5]);
+        // Changing the code after the header was written breaks the checksum
+        test_vm.program[PIE_HEADER_LENGTH + 3] = 6;
+        let error = test_vm.verify_header().unwrap_err();
+        assert_eq!(
+            test_vm.run(),
+            RunOutcome::Faulted {
+                fault: Fault::InvalidHeader { error }
+            }
+        );
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_run_starts_at_entry_point() {
+        let mut test_vm = VM::new();
+        let code = vec![1, 0, 0, 5, 1, 1, 0, 7];
+        let mut header = PieHeader::new(&code, code.len() as u32);
+        header.entry_point = PIE_HEADER_LENGTH as u32 + 4;
+        test_vm.program = header.to_bytes();
+        test_vm.add_bytes(code);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 7);
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut test_vm = VM::new();
+        // prts 0; hlt
+        let image = PieHeader::build_image(&[19, 0, 0, 0, 0], b"Hi\0", &[0, 0, 0, 9], 4, 0);
+        let header = test_vm.load_program(image).unwrap();
+        assert_eq!(test_vm.pc, header.entry_point as usize);
+        assert_eq!(test_vm.ro_data, b"Hi\0");
+        assert_eq!(test_vm.read_word(3), Ok(9));
+        assert_eq!(test_vm.read_word(7), Ok(0));
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_load_program_debug_info() {
+        let mut test_vm = VM::new();
+        test_vm.load_program(prepend_header(vec![200])).unwrap();
+        assert_eq!(test_vm.describe_location(64), "offset 0x0040");
+        test_vm.symbol_map = Some(SymbolMap::from_text("main code .code 0x40 1").unwrap());
+        assert_eq!(test_vm.describe_location(64), "offset 0x0040 in `main`");
+
+        let program = Assembler::new()
+            .assemble_with_debug_info(".code\nstart: hlt\n", "foo.iasm")
+            .unwrap();
+        test_vm.load_program(program).unwrap();
+        assert!(test_vm.debug_info.is_some());
+        assert_eq!(test_vm.describe_location(64), "foo.iasm:2:1 in `start`");
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_run_stops_at_code_end() {
+        let mut test_vm = VM::new();
+        // The data word would decode as `load $1 #7` if it were executed
+        let image = PieHeader::build_image(&[1, 0, 0, 5], &[], &[1, 1, 0, 7], 0, 0);
+        test_vm.load_program(image).unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_lea_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 0, 22, 0, 0xff, 0xfc];
+        test_vm.pc = 4;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 0);
+        test_vm.program = vec![22, 1, 0, 6];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[1], 6);
+    }
+
+    #[test]
+    fn test_br_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 23, 0xff, 0xfe];
+        test_vm.pc = 2;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_beq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![24, 0, 3, 24, 0, 3];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 3);
+        test_vm.equal_flag = true;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 6);
+    }
+
+    #[test]
+    fn test_load_module() {
+        let mut test_vm = VM::new();
+        // jmp $5; hlt
+        let main = PieHeader::build_image(&[6, 5, 0], &[], &[], 0, 0);
+        // load $0 #7; br to the hlt of the main program, 5 bytes back
+        let module = PieHeader::build_image(
+            &[1, 0, 0, 7, 23, 0xff, 0xfb],
+            &[],
+            &[],
+            0,
+            PIE_FLAG_POSITION_INDEPENDENT,
+        );
+        test_vm.load_program(main).unwrap();
+        let entry = test_vm.load_module(&module).unwrap();
+        assert_eq!(entry, PIE_HEADER_LENGTH + 3);
+        test_vm.registers[5] = entry as i32;
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[0], 7);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 3);
+
+        let absolute = PieHeader::build_image(&[0], &[], &[], 0, 0);
+        assert_eq!(
+            test_vm.load_module(&absolute),
+            Err(ModuleError::NotPositionIndependent)
+        );
+        let with_data = PieHeader::build_image(&[0], b"x\0", &[], 0, PIE_FLAG_POSITION_INDEPENDENT);
+        assert_eq!(test_vm.load_module(&with_data), Err(ModuleError::HasData));
+    }
+
+    #[test]
+    fn test_run_requires_verification() {
+        let mut test_vm = VM::new();
+        // load $40 #1
+        test_vm.program = prepend_header(vec![1, 40, 0, 1]);
+        test_vm.require_verification = true;
+        let outcome = test_vm.run();
+        assert_eq!(outcome.exit_code(), 1);
+        assert!(matches!(
+            outcome,
+            RunOutcome::Faulted {
+                fault: Fault::FailedVerification { .. }
+            }
+        ));
+        assert_eq!(test_vm.pc, 0);
+    }
+
     #[test]
     fn test_init_registers() {
         let mut test_vm = VM::new();
@@ -309,6 +1134,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn test_sub_opcode() {
         let mut test_vm = VM::new();
         let mut array = [0; 32];
@@ -324,6 +1150,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn test_mul_opcode() {
         let mut test_vm = VM::new();
         let mut array = [0; 32];
@@ -339,6 +1166,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn test_div_opcode() {
         let mut test_vm = VM::new();
         let mut array = [0; 32];
@@ -387,6 +1215,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_eq_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![9, 0, 0, 0];
@@ -397,6 +1226,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_neq_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![10, 0, 0, 0];
@@ -407,6 +1237,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_gt_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![11, 0, 0, 0];
@@ -417,6 +1248,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_lt_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![12, 0, 0, 0];
@@ -427,6 +1259,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_gtq_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![13, 0, 0, 0];
@@ -437,6 +1270,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_ltq_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![14, 0, 0, 0];
@@ -447,6 +1281,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_jeq_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![9, 0, 0, 0, 15, 0];
@@ -475,6 +1310,185 @@ mod tests {
         assert_eq!(test_vm.registers[0], 1023);
     }
 
+    #[test]
+    fn test_prts_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.map_data_sections(vec![72, 105, 0], vec![33, 0], 0);
+        test_vm.program = vec![19, 0, 3];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 3);
+    }
+
+    #[test]
+    fn test_lw_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.map_data_sections(vec![0, 0, 1, 0], vec![0, 0, 0, 42], 0);
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![20, 0, 1, 20, 2, 3];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 42);
+        // Read-only data can be read as well
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[2], 256);
+    }
+
+    #[test]
+    fn test_sw_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.map_data_sections(vec![1, 2, 3, 4], vec![], 8);
+        assert_eq!(test_vm.data.len(), 8);
+        test_vm.registers[0] = 258;
+        test_vm.registers[1] = 8;
+        test_vm.program = vec![21, 0, 1];
+        test_vm.run_once();
+        assert_eq!(test_vm.data, vec![0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(test_vm.read_word(8), Ok(258));
+    }
+
+    #[test]
+    fn test_sw_read_only_fault() {
+        let mut test_vm = VM::new();
+        test_vm.map_data_sections(vec![1, 2, 3, 4], vec![0; 4], 0);
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![21, 0, 1];
+        test_vm.program = prepend_header(test_vm.program);
+        assert_eq!(
+            test_vm.run(),
+            RunOutcome::Faulted {
+                fault: Fault::Memory {
+                    offset: PIE_HEADER_LENGTH,
+                    error: MemoryError::ReadOnly { address: 2 }
+                }
+            }
+        );
+        assert_eq!(test_vm.ro_data, vec![1, 2, 3, 4]);
+        assert_eq!(test_vm.data, vec![0; 4]);
+        assert_eq!(
+            test_vm.write_word(0, 1),
+            Err(MemoryError::ReadOnly { address: 0 })
+        );
+        assert_eq!(
+            test_vm.write_word(6, 1),
+            Err(MemoryError::OutOfBounds { address: 8 })
+        );
+    }
+
+    #[test]
+    fn test_run_out_of_fuel() {
+        let mut test_vm = VM::new();
+        // load $1 #68, inc $0, jmp $1: the jump lands on the `inc` forever
+        test_vm.program = prepend_header(vec![1, 1, 0, 68, 17, 0, 6, 1]);
+        test_vm.costs = CostTable::uniform(1);
+        test_vm.fuel = Some(5);
+        assert_eq!(test_vm.run(), RunOutcome::OutOfFuel);
+        assert_eq!(test_vm.fuel, Some(0));
+        assert_eq!(test_vm.instruction_count, 5);
+        assert_eq!(test_vm.registers[0], 2);
+        // The `inc` that had no fuel left hasn't run
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+
+        test_vm.add_fuel(2);
+        assert_eq!(test_vm.resume(), RunOutcome::OutOfFuel);
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+    }
+
+    #[test]
+    fn test_cancel_flag_stops_program() {
+        let mut test_vm = VM::new();
+        // inc $0, jmp $1 with $1 pointing at the `inc`, forever
+        test_vm.program = prepend_header(vec![17, 0, 6, 1]);
+        test_vm.registers[1] = PIE_HEADER_LENGTH as i32;
+        test_vm.cancel.store(true, Ordering::SeqCst);
+        assert_eq!(test_vm.run(), RunOutcome::Cancelled);
+        assert_eq!(test_vm.instruction_count, 0);
+
+        test_vm.cancel.store(false, Ordering::SeqCst);
+        let cancel = test_vm.cancel.clone();
+        let runner = std::thread::spawn(move || test_vm.resume());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cancel.store(true, Ordering::SeqCst);
+        assert_eq!(runner.join().unwrap(), RunOutcome::Cancelled);
+    }
+
+    #[test]
+    fn test_deadline_stops_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![17, 0, 6, 1]);
+        test_vm.registers[1] = PIE_HEADER_LENGTH as i32;
+        test_vm.deadline = Some(Instant::now() + std::time::Duration::from_millis(10));
+        assert_eq!(test_vm.run(), RunOutcome::DeadlineExceeded);
+        assert!(test_vm.registers[0] > 0);
+        assert!(test_vm
+            .instruction_count
+            .is_multiple_of(DEADLINE_CHECK_INTERVAL));
+    }
+
+    #[test]
+    fn test_fuel_is_charged_per_opcode() {
+        let mut test_vm = VM::new();
+        // load $0 #2, mul $0 $0 $1
+        test_vm.program = prepend_header(vec![1, 0, 0, 2, 4, 0, 0, 1]);
+        test_vm.costs.set_cost(Opcode::MUL, 4);
+        test_vm.fuel = Some(4);
+        assert_eq!(test_vm.run(), RunOutcome::OutOfFuel);
+        assert_eq!(test_vm.fuel, Some(3));
+        test_vm.add_fuel(1);
+        assert_eq!(test_vm.resume(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[1], 4);
+        assert_eq!(test_vm.fuel, Some(0));
+    }
+
+    #[test]
+    fn test_exit_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[2] = 3;
+        test_vm.program = prepend_header(vec![25, 2, 1, 0, 0, 5]);
+        let outcome = test_vm.run();
+        assert_eq!(outcome, RunOutcome::Exited { code: 3 });
+        assert_eq!(outcome.exit_code(), 3);
+        // The load after `exit` never runs
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_recv_traps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 50;
+        test_vm.program = prepend_header(vec![28, 2, 1, 15, 3]);
+        assert_eq!(
+            test_vm.run(),
+            RunOutcome::Trapped {
+                trap: Trap::Receive {
+                    register: 2,
+                    timeout: 50
+                }
+            }
+        );
+        test_vm.complete_receive(2, Some(9));
+        assert_eq!(test_vm.registers[2], 9);
+        assert!(test_vm.equal_flag);
+        test_vm.complete_receive(2, None);
+        assert_eq!(test_vm.registers[2], 9);
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_igl_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![200]);
+        assert_eq!(
+            test_vm.run(),
+            RunOutcome::Faulted {
+                fault: Fault::IllegalOpcode {
+                    offset: PIE_HEADER_LENGTH,
+                    opcode: 200
+                }
+            }
+        );
+    }
+
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = VM::new();
@@ -483,4 +1497,118 @@ mod tests {
         test_vm.run_once();
         assert_eq!(test_vm.heap.len(), 1024);
     }
+
+    /// Runs `code` after a header and returns the fault it stopped with
+    fn run_faulting(mut test_vm: VM, code: Vec<u8>) -> Fault {
+        test_vm.program = prepend_header(code);
+        match test_vm.run() {
+            RunOutcome::Faulted { fault } => fault,
+            outcome => panic!("expected a fault, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_div_by_zero_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        assert_eq!(
+            run_faulting(test_vm, vec![5, 0, 1, 2]),
+            Fault::DivideByZero {
+                offset: PIE_HEADER_LENGTH
+            }
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_overflow_faults() {
+        let overflow = Fault::ArithmeticOverflow {
+            offset: PIE_HEADER_LENGTH,
+        };
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        assert_eq!(run_faulting(test_vm.clone(), vec![2, 0, 1, 2]), overflow);
+        assert_eq!(run_faulting(test_vm, vec![17, 0]), overflow);
+
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        assert_eq!(run_faulting(test_vm, vec![5, 0, 1, 2]), overflow);
+    }
+
+    #[test]
+    fn test_invalid_register_faults() {
+        assert_eq!(
+            run_faulting(VM::new(), vec![17, 40]),
+            Fault::InvalidRegister {
+                offset: PIE_HEADER_LENGTH,
+                register: 40
+            }
+        );
+    }
+
+    #[test]
+    fn test_truncated_instruction_faults() {
+        // `load $0` without its number
+        assert_eq!(
+            run_faulting(VM::new(), vec![1, 0]),
+            Fault::PcOutOfBounds {
+                offset: PIE_HEADER_LENGTH
+            }
+        );
+    }
+
+    #[test]
+    fn test_jmpb_before_start_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1000;
+        assert_eq!(
+            run_faulting(test_vm, vec![8, 0]),
+            Fault::PcOutOfBounds {
+                offset: PIE_HEADER_LENGTH
+            }
+        );
+    }
+
+    #[test]
+    fn test_jump_outside_code_faults() {
+        let out_of_bounds = Fault::PcOutOfBounds {
+            offset: PIE_HEADER_LENGTH + 4,
+        };
+        // `load $1 #0` first, then a jump into the header or past the end of the program
+        for target in [10, 5000] {
+            // jmp $0, jeq $0 and spawn $1 $0
+            for jump in [vec![6, 0], vec![15, 0], vec![30, 1, 0]] {
+                let mut test_vm = VM::new();
+                test_vm.registers[0] = target;
+                test_vm.equal_flag = true;
+                let code = [vec![1, 1, 0, 0], jump].concat();
+                assert_eq!(run_faulting(test_vm, code), out_of_bounds);
+            }
+        }
+        // `br` back into the header
+        assert_eq!(
+            run_faulting(VM::new(), vec![1, 1, 0, 0, 23, 0xff, 0x00]),
+            out_of_bounds
+        );
+
+        // Jumping to the very end of the code halts
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = PIE_HEADER_LENGTH as i32 + 2;
+        test_vm.program = prepend_header(vec![6, 0]);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_aloc_negative_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        assert_eq!(
+            run_faulting(test_vm, vec![16, 0]),
+            Fault::NegativeAllocation {
+                offset: PIE_HEADER_LENGTH,
+                size: -1
+            }
+        );
+    }
 }